@@ -47,6 +47,7 @@ pub fn main() -> Result<()> {
     }
     // this channel will contain parsed events ready to be inserted
     let (event_tx, event_rx) = mpsc::sync_channel(100_000);
+    let indexed_tags = settings.options.indexed_tag_names().to_vec();
     // Thread for reading events
     let _stdin_reader_handler = thread::spawn(move || {
         let stdin = io::stdin();
@@ -56,7 +57,7 @@ pub fn main() -> Result<()> {
                 let eres: Result<Event, serde_json::Error> = serde_json::from_str(&line);
                 if let Ok(mut e) = eres {
                     if let Ok(()) = e.validate() {
-                        e.build_index();
+                        e.build_index(&indexed_tags);
                         //debug!("Event: {:?}", e);
                         event_tx.send(Some(e)).ok();
                     } else {
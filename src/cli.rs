@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(about = "A nostr relay written in Rust", author = env!("CARGO_PKG_AUTHORS"), version = env!("CARGO_PKG_VERSION"))]
@@ -17,4 +17,33 @@ pub struct CLIArgs {
         required = false
     )]
     pub config: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Manage the names served at /.well-known/nostr.json (NIP-05)
+    Names {
+        #[command(subcommand)]
+        action: NamesAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum NamesAction {
+    /// List registered names
+    List,
+    /// Register a name for a pubkey, replacing any existing entry
+    Add {
+        #[arg(help = "Local part of the identifier (a-z0-9-_.)")]
+        name: String,
+        #[arg(help = "Pubkey, in hex")]
+        pubkey: String,
+    },
+    /// Remove a registered name
+    Remove {
+        #[arg(help = "Local part of the identifier")]
+        name: String,
+    },
 }
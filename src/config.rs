@@ -13,6 +13,35 @@ pub struct Info {
     pub pubkey: Option<String>,
     pub contact: Option<String>,
     pub favicon: Option<String>,
+    pub icon: Option<String>,                 // URL of a relay icon (NIP-11)
+    pub banner: Option<String>,               // URL of a relay banner (NIP-11)
+    pub relay_countries: Option<Vec<String>>, // ISO 3166-1 alpha-2 country codes of relevant legal jurisdictions
+    pub language_tags: Option<Vec<String>>, // IETF language tags of major languages spoken on the relay
+    pub tags: Option<Vec<String>>,          // community preference tags (e.g. "sfw-only")
+    pub posting_policy: Option<String>,     // URL of a document describing the posting policy
+    pub fees: Option<Fees>,                 // fees charged by the relay, if any
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[allow(unused)]
+pub struct Fees {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub admission: Vec<Fee>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscription: Vec<Fee>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub publication: Vec<Fee>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Fee {
+    pub amount: u64,
+    pub unit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>, // subscription period, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<Vec<u64>>, // event kinds a publication fee applies to
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +54,7 @@ pub struct Database {
     pub max_conn: u32,
     pub connection: String,
     pub connection_write: Option<String>,
+    pub notify_events: bool, // share new events with other relays using this database (postgres only)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,18 +77,50 @@ pub struct Network {
 pub struct Options {
     pub reject_future_seconds: Option<usize>, // if defined, reject any events with a timestamp more than X seconds in the future
     pub cleanup_contact_list: bool,           // delete old kind 3 events automatically
+    pub search_kinds: Option<Vec<u64>>, // if defined, enable NIP-50 search over the content of these event kinds
+    pub indexed_tags: Option<Vec<String>>, // multi-character tag names to index, and allow in filters
+}
+
+impl Options {
+    /// Event kinds indexed for full-text search; empty if search is disabled.
+    #[must_use]
+    pub fn searchable_kinds(&self) -> &[u64] {
+        self.search_kinds.as_deref().unwrap_or_default()
+    }
+
+    /// Multi-character tag names indexed in addition to single-letter tags.
+    #[must_use]
+    pub fn indexed_tag_names(&self) -> &[String] {
+        self.indexed_tags.as_deref().unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Retention {
-    // TODO: implement
     pub max_events: Option<usize>,                // max events
     pub max_bytes: Option<usize>,                 // max size
     pub persist_days: Option<usize>,              // oldest message
     pub whitelist_addresses: Option<Vec<String>>, // whitelisted addresses (never delete)
 }
 
+impl Retention {
+    /// Is any retention limit configured?
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.max_events.is_some() || self.max_bytes.is_some() || self.persist_days.is_some()
+    }
+
+    /// Whitelisted addresses as binary pubkeys, suitable for queries.
+    #[must_use]
+    pub fn whitelist_blobs(&self) -> Vec<Vec<u8>> {
+        self.whitelist_addresses
+            .as_ref()
+            .map(|wl| wl.iter().filter_map(|x| hex::decode(x).ok()).collect())
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Limits {
@@ -71,15 +133,68 @@ pub struct Limits {
     pub max_ws_frame_bytes: Option<usize>,
     pub broadcast_buffer: usize, // events to buffer for subscribers (prevents slow readers from consuming memory)
     pub event_persist_buffer: usize, // events to buffer for database commits (block senders if database writes are too slow)
+    pub event_write_batch: usize,    // most events to persist in a single database transaction
+    pub event_write_batch_ms: u64,   // how long to wait for more events to add to a transaction
+    pub verify_workers: Option<usize>, // threads verifying event signatures (defaults to one per CPU)
+    pub verify_queue: usize, // events waiting for signature verification (block senders when full)
+    pub verified_id_cache: usize, // recently verified events to skip re-checking signatures for
     pub rate_limit_whitelist: Vec<String>, // List of ip's which bypass event publishing limits
     pub event_kind_blacklist: Option<Vec<u64>>,
+    pub min_pow_difficulty: Option<u32>, // Minimum proof-of-work (NIP-13) difficulty, in leading zero bits of the event id
+    pub kind_pow_difficulty: Option<Vec<KindPowDifficulty>>, // Minimum proof-of-work difficulty for specific kinds, overriding the default
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct KindPowDifficulty {
+    pub kinds: Vec<u64>,
+    pub difficulty: u32,
+}
+
+impl Limits {
+    /// Minimum proof-of-work difficulty required for an event kind
+    #[must_use]
+    pub fn min_pow_difficulty_for(&self, kind: u64) -> Option<u32> {
+        self.kind_pow_difficulty
+            .as_ref()
+            .and_then(|kp| kp.iter().find(|k| k.kinds.contains(&kind)))
+            .map(|k| k.difficulty)
+            .or(self.min_pow_difficulty)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Authorization {
     pub pubkey_whitelist: Option<Vec<String>>, // If present, only allow these pubkeys to publish events
-    pub nip42_auth: bool,                      // if true enables NIP-42 authentication
+    pub pubkey_whitelist_delegation: bool, // if true, also allow events delegated (NIP-26) by whitelisted pubkeys
+    pub nip42_auth: bool,                  // if true enables NIP-42 authentication
+    pub nip42_dms: bool, // if true, only send DMs (kinds 4 and 1059) to authenticated participants
+    pub nip42_publish: PublishAuthMode, // require NIP-42 authentication before accepting events
+    pub admin_pubkeys: Option<Vec<String>>, // pubkeys allowed to use the management API (NIP-86), in addition to info.pubkey
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PublishAuthMode {
+    Disabled,      // events are accepted from any client
+    Authenticated, // clients must be authenticated as any pubkey
+    Author,        // clients must be authenticated as the event author (or its delegator)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Groups {
+    pub enabled: bool,                    // if true, host relay-based groups (NIP-29)
+    pub relay_secret_key: Option<String>, // hex secret key used to sign group metadata events
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct LocalNames {
+    pub enabled: bool, // if true, serve registered names at /.well-known/nostr.json (NIP-05)
+    #[serde(default)]
+    pub relays: Vec<String>, // relays advertised for every registered name
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -163,6 +278,8 @@ pub struct Settings {
     pub verified_users: VerifiedUsers,
     pub retention: Retention,
     pub options: Options,
+    pub groups: Groups,
+    pub local_names: LocalNames,
 }
 
 impl Settings {
@@ -180,6 +297,16 @@ impl Settings {
         }
     }
 
+    /// Pubkeys allowed to manage the relay (NIP-86)
+    #[must_use]
+    pub fn admin_pubkeys(&self) -> Vec<String> {
+        let mut admins = self.authorization.admin_pubkeys.clone().unwrap_or_default();
+        if let Some(pubkey) = &self.info.pubkey {
+            admins.push(pubkey.to_owned());
+        }
+        admins
+    }
+
     fn new_from_default(
         default: &Settings,
         config_file_name: &Option<String>,
@@ -225,6 +352,13 @@ impl Default for Settings {
                 pubkey: None,
                 contact: None,
                 favicon: None,
+                icon: None,
+                banner: None,
+                relay_countries: None,
+                language_tags: None,
+                tags: None,
+                posting_policy: None,
+                fees: None,
             },
             diagnostics: Diagnostics { tracing: false },
             database: Database {
@@ -235,6 +369,7 @@ impl Default for Settings {
                 max_conn: 128,
                 connection: "".to_owned(),
                 connection_write: None,
+                notify_events: false,
             },
             grpc: Grpc {
                 event_admission_server: None,
@@ -255,12 +390,23 @@ impl Default for Settings {
                 max_ws_frame_bytes: Some(2 << 17),   // 128K
                 broadcast_buffer: 16384,
                 event_persist_buffer: 4096,
+                event_write_batch: 100,
+                event_write_batch_ms: 5,
+                verify_workers: None,
+                verify_queue: 1024,
+                verified_id_cache: 10000,
                 event_kind_blacklist: None,
+                min_pow_difficulty: None,
+                kind_pow_difficulty: None,
                 rate_limit_whitelist: vec!["127.0.0.1".to_string()],
             },
             authorization: Authorization {
-                pubkey_whitelist: None, // Allow any address to publish
-                nip42_auth: false,      // Disable NIP-42 authentication
+                pubkey_whitelist: None,                   // Allow any address to publish
+                pubkey_whitelist_delegation: true,        // Allow whitelisted delegators
+                nip42_auth: false,                        // Disable NIP-42 authentication
+                nip42_dms: false,                         // Send DMs to anyone
+                nip42_publish: PublishAuthMode::Disabled, // Accept events from unauthenticated clients
+                admin_pubkeys: None,                      // Only info.pubkey may manage the relay
             },
             verified_users: VerifiedUsers {
                 mode: VerifiedUsersMode::Disabled,
@@ -281,7 +427,34 @@ impl Default for Settings {
             options: Options {
                 reject_future_seconds: None, // Reject events in the future if defined
                 cleanup_contact_list: true,
+                search_kinds: None, // NIP-50 search is disabled
+                indexed_tags: None, // Only single-letter tags are indexed
+            },
+            groups: Groups {
+                enabled: false,         // Groups are disabled
+                relay_secret_key: None, // No relay signing key
+            },
+            local_names: LocalNames {
+                enabled: false, // Don't act as a NIP-05 provider
+                relays: vec![], // No relay hints
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_names_without_relays() {
+        let path = std::env::temp_dir().join(format!("local-names-{}.toml", std::process::id()));
+        std::fs::write(&path, "[local_names]\nenabled = true\n").unwrap();
+        let settings =
+            Settings::new_from_default(&Settings::default(), &Some(path.display().to_string()));
+        std::fs::remove_file(&path).ok();
+        let settings = settings.unwrap();
+        assert!(settings.local_names.enabled);
+        assert!(settings.local_names.relays.is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 //! Client connection state
 use crate::close::Close;
-use crate::config::Settings;
+use crate::config::{PublishAuthMode, Settings};
 use crate::conn::Nip42AuthState::{AuthPubkey, Challenge, NoAuth};
 use crate::error::Error;
 use crate::error::Result;
@@ -17,7 +17,10 @@ use tracing::{debug, trace};
 use uuid::Uuid;
 
 /// A subscription identifier has a maximum length
-const MAX_SUBSCRIPTION_ID_LEN: usize = 256;
+pub const MAX_SUBSCRIPTION_ID_LEN: usize = 256;
+
+/// Per-connection maximum concurrent subscriptions
+pub const MAX_SUBSCRIPTIONS: usize = 32;
 
 /// NIP-42 authentication state
 pub enum Nip42AuthState {
@@ -60,7 +63,7 @@ impl ClientConn {
             client_ip_addr: client_ip_addr.clone(),
             client_id,
             subscriptions: HashMap::new(),
-            max_subs: 32,
+            max_subs: MAX_SUBSCRIPTIONS,
             pub_limiter: if config.limits.rate_limit_whitelist.contains(&client_ip_addr) {
                 None
             } else {
@@ -182,14 +185,14 @@ impl ClientConn {
         match event.validate() {
             Ok(_) => {
                 if event.kind != 22242 {
-                    return Err(Error::AuthFailure);
+                    return Err(Error::AuthWrongKind);
                 }
 
                 let curr_time = unix_time();
                 let past_cutoff = curr_time - 600; // 10 minutes
                 let future_cutoff = curr_time + 600; // 10 minutes
                 if event.created_at < past_cutoff || event.created_at > future_cutoff {
-                    return Err(Error::AuthFailure);
+                    return Err(Error::AuthStaleEvent);
                 }
 
                 let mut challenge: Option<&String> = None;
@@ -207,22 +210,22 @@ impl ClientConn {
                 match (challenge, &self.auth) {
                     (Some(received_challenge), Challenge(sent_challenge)) => {
                         if received_challenge != sent_challenge {
-                            return Err(Error::AuthFailure);
+                            return Err(Error::AuthChallengeMismatch);
                         }
                     }
                     (_, _) => {
-                        return Err(Error::AuthFailure);
+                        return Err(Error::AuthChallengeMismatch);
                     }
                 }
 
                 match (relay.and_then(|url| host_str(url)), host_str(relay_url)) {
                     (Some(received_relay), Some(our_relay)) => {
                         if received_relay != our_relay {
-                            return Err(Error::AuthFailure);
+                            return Err(Error::AuthRelayMismatch);
                         }
                     }
                     (_, _) => {
-                        return Err(Error::AuthFailure);
+                        return Err(Error::AuthRelayMismatch);
                     }
                 }
 
@@ -238,6 +241,24 @@ impl ClientConn {
         }
     }
 
+    /// Reason this connection may not publish an event, if
+    /// publishing requires authentication.
+    #[must_use]
+    pub fn publish_auth_error(&self, event: &Event, mode: PublishAuthMode) -> Option<&'static str> {
+        match (mode, self.auth_pubkey()) {
+            (PublishAuthMode::Disabled, _) => None,
+            (_, None) => Some("authentication is required to publish events"),
+            (PublishAuthMode::Authenticated, Some(_)) => None,
+            (PublishAuthMode::Author, Some(pubkey)) => {
+                if &event.pubkey == pubkey || event.delegated_by.as_ref() == Some(pubkey) {
+                    None
+                } else {
+                    Some("authenticated pubkey does not match event author")
+                }
+            }
+        }
+    }
+
     /// False if over limit
     pub fn check_pub_rate_limit(&mut self) -> bool {
         if let Some(limiter) = &self.pub_limiter {
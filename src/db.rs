@@ -2,9 +2,11 @@
 //use crate::config::SETTINGS;
 use crate::config::Settings;
 use crate::error::{Error, Result};
-use crate::event::Event;
+use crate::event::{BroadcastEvent, Event};
 use crate::nauthz;
-use crate::notice::Notice;
+use crate::nip29::{self, GroupState};
+use crate::nip86::Moderation;
+use crate::notice::{EventResultStatus, Notice};
 use crate::repo::postgres::{PostgresPool, PostgresRepo};
 use crate::repo::sqlite::SqliteRepo;
 use crate::repo::NostrRepo;
@@ -18,6 +20,7 @@ use sqlx::ConnectOptions;
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
 use tracing::log::LevelFilter;
 use tracing::{debug, info, trace, warn};
 
@@ -79,7 +82,7 @@ async fn build_postgres_pool(config: &Settings, metrics: NostrMetrics) -> Postgr
         None => pool.clone(),
     };
 
-    let repo = PostgresRepo::new(pool, write_pool, metrics);
+    let repo = PostgresRepo::new(pool, write_pool, config, metrics);
 
     // Panic on migration failure
     let version = repo.migrate_up().await.unwrap();
@@ -97,12 +100,15 @@ async fn build_sqlite_pool(settings: &Settings, metrics: NostrMetrics) -> Sqlite
 }
 
 /// Spawn a database writer that persists events to the SQLite store.
+#[allow(clippy::too_many_arguments)]
 pub async fn db_writer(
     repo: Arc<dyn NostrRepo>,
     settings: Settings,
     mut event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
-    bcast_tx: tokio::sync::broadcast::Sender<Event>,
+    bcast_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
     metadata_tx: tokio::sync::broadcast::Sender<Event>,
+    moderation: tokio::sync::watch::Receiver<Moderation>,
+    groups: tokio::sync::watch::Sender<GroupState>,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
 ) -> Result<()> {
     // are we performing NIP-05 checking?
@@ -112,6 +118,11 @@ pub async fn db_writer(
 
     // Make a copy of the whitelist
     let whitelist = &settings.authorization.pubkey_whitelist.clone();
+    // are delegators on the whitelist allowed to publish through delegates?
+    let whitelist_delegation = settings.authorization.pubkey_whitelist_delegation;
+
+    // relay key for publishing group state, if groups are enabled
+    let mut group_signer = nip29::RelaySigner::from_settings(&settings);
 
     // get rate limit settings
     let rps_setting = settings.limits.messages_per_sec;
@@ -137,40 +148,74 @@ pub async fn db_writer(
     //        event_admitter_connect(&s);
     //    });
 
+    // accepted events are written together, in a single transaction,
+    // once the batch is full or has waited long enough.
+    let batch_size = settings.limits.event_write_batch.max(1);
+    let batch_wait = Duration::from_millis(settings.limits.event_write_batch_ms);
+    let mut batch: Vec<PendingWrite> = vec![];
+    let mut batch_deadline: Option<tokio::time::Instant> = None;
+
     loop {
         if shutdown.try_recv().is_ok() {
             info!("shutting down database writer");
+            write_batch(&repo, batch, &bcast_tx, &groups, &mut group_signer).await;
             break;
         }
-        // call blocking read on channel
-        let next_event = event_rx.recv().await;
+        let batch_due = batch_deadline.is_some_and(|d| d <= tokio::time::Instant::now());
+        if batch.len() >= batch_size || batch_due {
+            batch_deadline = None;
+            let batch = std::mem::take(&mut batch);
+            let written = write_batch(&repo, batch, &bcast_tx, &groups, &mut group_signer).await;
+            // use rate limit, if defined, for events actually written.
+            if let Some(ref lim) = lim_opt {
+                for _ in 0..written {
+                    if let Err(n) = lim.check() {
+                        let wait_for = n.wait_time_from(clock.now());
+                        // check if we have recently logged rate
+                        // limits, but print out a message only once
+                        // per second.
+                        if most_recent_rate_limit.elapsed().as_secs() > 10 {
+                            warn!(
+                                "rate limit reached for event creation (sleep for {:?}) (suppressing future messages for 10 seconds)",
+                                wait_for
+                            );
+                            // reset last rate limit message
+                            most_recent_rate_limit = Instant::now();
+                        }
+                        // block event writes, allowing them to queue up
+                        thread::sleep(wait_for);
+                    }
+                }
+            }
+        }
+        // call blocking read on channel, until the batch is due
+        let next_event = match batch_deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, event_rx.recv()).await {
+                Ok(next_event) => next_event,
+                Err(_) => continue,
+            },
+            None => event_rx.recv().await,
+        };
         // if the channel has closed, we will never get work
         if next_event.is_none() {
+            write_batch(&repo, batch, &bcast_tx, &groups, &mut group_signer).await;
             break;
         }
-        // track if an event write occurred; this is used to
-        // update the rate limiter
-        let mut event_write = false;
         let subm_event = next_event.unwrap();
         let event = subm_event.event;
         let notice_tx = subm_event.notice_tx;
-        // check if this event is authorized.
-        if let Some(allowed_addrs) = whitelist {
-            // TODO: incorporate delegated pubkeys
-            // if the event address is not in allowed_addrs.
-            if !allowed_addrs.contains(&event.pubkey) {
-                debug!(
-                    "rejecting event: {}, unauthorized author",
-                    event.get_event_id_prefix()
-                );
-                notice_tx
-                    .try_send(Notice::blocked(
-                        event.id,
-                        "pubkey is not allowed to publish to this relay",
-                    ))
-                    .ok();
-                continue;
-            }
+        // check if this event is authorized, by the whitelist and
+        // the bans and allowlists from the management API (NIP-86)
+        let author_rejection = publish_rejection(
+            whitelist.as_deref(),
+            whitelist_delegation,
+            &moderation.borrow(),
+            &event,
+        );
+        if let Some(msg) = author_rejection {
+            debug!("rejecting event: {}, {}", event.get_event_id_prefix(), msg);
+            notice_tx.try_send(Notice::blocked(event.id, msg)).ok();
+            continue;
         }
 
         // Check that event kind isn't blacklisted
@@ -189,6 +234,63 @@ pub async fn db_writer(
             }
         }
 
+        // Protected events (NIP-70) are only accepted from their
+        // authenticated author
+        if event.is_protected() {
+            let auth_pubkey = subm_event.auth_pubkey.as_ref().map(hex::encode);
+            let rejection = match auth_pubkey {
+                _ if !settings.authorization.nip42_auth => Some(Notice::restricted(
+                    event.id.clone(),
+                    "protected events are not accepted by this relay",
+                )),
+                None => Some(Notice::auth_required(
+                    event.id.clone(),
+                    "protected events may only be published by their authenticated author",
+                )),
+                Some(ref pubkey) if *pubkey != event.pubkey => Some(Notice::restricted(
+                    event.id.clone(),
+                    "protected events may only be published by their author",
+                )),
+                Some(_) => None,
+            };
+            if let Some(notice) = rejection {
+                debug!(
+                    "rejecting event: {}, protected event not published by author",
+                    event.get_event_id_prefix()
+                );
+                notice_tx.try_send(notice).ok();
+                continue;
+            }
+        }
+
+        // Group events (NIP-29) are only accepted from members,
+        // authenticated as the event author
+        let mut group_change = None;
+        if group_signer.is_some() {
+            let change = groups.borrow().apply(&event);
+            match change {
+                Err(msg) => {
+                    debug!("rejecting event: {}, {}", event.get_event_id_prefix(), msg);
+                    notice_tx.try_send(Notice::restricted(event.id, msg)).ok();
+                    continue;
+                }
+                Ok(Some(change)) => {
+                    let auth_pubkey = subm_event.auth_pubkey.as_ref().map(hex::encode);
+                    if auth_pubkey.as_ref() != Some(&event.pubkey) {
+                        notice_tx
+                            .try_send(Notice::auth_required(
+                                event.id,
+                                "group events may only be published by their authenticated author",
+                            ))
+                            .ok();
+                        continue;
+                    }
+                    group_change = Some(change);
+                }
+                Ok(None) => {}
+            }
+        }
+
         // send any metadata events to the NIP-05 verifier
         if nip05_active && event.is_kind_metadata() {
             // we are sending this prior to even deciding if we
@@ -295,78 +397,272 @@ pub async fn db_writer(
             }
         }
 
-        // TODO: cache recent list of authors to remove a DB call.
-        let start = Instant::now();
-        if event.is_ephemeral() {
-            bcast_tx.send(event.clone()).ok();
-            debug!(
-                "published ephemeral event: {:?} from: {:?} in: {:?}",
-                event.get_event_id_prefix(),
-                event.get_author_prefix(),
-                start.elapsed()
-            );
-            event_write = true;
+        // group changes are written right away, since they may
+        // affect whether the next events are accepted.
+        let due = if group_change.is_some() {
+            tokio::time::Instant::now()
         } else {
-            match repo.write_event(&event).await {
-                Ok(updated) => {
-                    if updated == 0 {
-                        trace!("ignoring duplicate or deleted event");
-                        notice_tx.try_send(Notice::duplicate(event.id)).ok();
-                    } else {
-                        info!(
-                            "persisted event: {:?} (kind: {}) from: {:?} in: {:?} (IP: {:?})",
-                            event.get_event_id_prefix(),
-                            event.kind,
-                            event.get_author_prefix(),
-                            start.elapsed(),
-                            subm_event.source_ip,
-                        );
-                        event_write = true;
-                        // send this out to all clients
-                        bcast_tx.send(event.clone()).ok();
-                        notice_tx.try_send(Notice::saved(event.id)).ok();
-                    }
-                }
-                Err(err) => {
-                    warn!("event insert failed: {:?}", err);
-                    let msg = "relay experienced an error trying to publish the latest event";
-                    notice_tx.try_send(Notice::error(event.id, msg)).ok();
-                }
+            tokio::time::Instant::now() + batch_wait
+        };
+        batch_deadline = Some(batch_deadline.map_or(due, |d| d.min(due)));
+        batch.push(PendingWrite {
+            event,
+            notice_tx,
+            source_ip: subm_event.source_ip,
+            group_change,
+        });
+    }
+    info!("database connection closed");
+    Ok(())
+}
+
+/// Reason an event may not be published, under the configured
+/// pubkey `whitelist` and the management API moderation.  Authors on
+/// either the whitelist or the API allowlist are accepted.
+fn publish_rejection(
+    whitelist: Option<&[String]>,
+    delegation: bool,
+    moderation: &Moderation,
+    event: &Event,
+) -> Option<&'static str> {
+    let whitelisted = whitelist.is_some_and(|w| is_whitelisted(w, delegation, event));
+    if whitelist.is_some()
+        && !whitelisted
+        && !moderation.allowed_pubkeys.contains_key(&event.pubkey)
+    {
+        return Some("pubkey is not allowed to publish to this relay");
+    }
+    moderation.event_rejection(event, whitelisted)
+}
+
+/// Check if an event is authored by a whitelisted pubkey, or validly
+/// delegated (NIP-26) by one if `delegation` is allowed.
+fn is_whitelisted(whitelist: &[String], delegation: bool, event: &Event) -> bool {
+    whitelist.contains(&event.pubkey)
+        || (delegation
+            && event
+                .delegated_by
+                .as_ref()
+                .is_some_and(|d| whitelist.contains(d)))
+}
+
+/// An accepted event, waiting for its batch to be written.
+struct PendingWrite {
+    event: Event,
+    notice_tx: tokio::sync::mpsc::Sender<Notice>,
+    source_ip: String,
+    group_change: Option<nip29::GroupChange>,
+}
+
+/// Persist a batch of events in one transaction, then report the
+/// result for each event, and publish the ones that were written.
+/// Returns the number of events published.
+async fn write_batch(
+    repo: &Arc<dyn NostrRepo>,
+    batch: Vec<PendingWrite>,
+    bcast_tx: &tokio::sync::broadcast::Sender<BroadcastEvent>,
+    groups: &tokio::sync::watch::Sender<GroupState>,
+    group_signer: &mut Option<nip29::RelaySigner>,
+) -> usize {
+    let start = Instant::now();
+    let mut published = 0;
+    // ephemeral events are published, but never stored.
+    let (ephemeral, batch): (Vec<_>, Vec<_>) =
+        batch.into_iter().partition(|p| p.event.is_ephemeral());
+    for p in ephemeral {
+        debug!(
+            "published ephemeral event: {:?} from: {:?} in: {:?}",
+            p.event.get_event_id_prefix(),
+            p.event.get_author_prefix(),
+            start.elapsed()
+        );
+        bcast_tx.send(BroadcastEvent::from(p.event)).ok();
+        published += 1;
+    }
+    if batch.is_empty() {
+        return published;
+    }
+    let events: Vec<Event> = batch.iter().map(|p| p.event.clone()).collect();
+    let results = match repo.write_events(&events).await {
+        Ok(results) => results,
+        Err(err) => {
+            warn!("batch insert of {} events failed: {:?}", events.len(), err);
+            let msg = "relay experienced an error trying to publish the latest event";
+            for p in batch {
+                p.notice_tx.try_send(Notice::error(p.event.id, msg)).ok();
             }
+            return published;
         }
-
-        // use rate limit, if defined, and if an event was actually written.
-        if event_write {
-            if let Some(ref lim) = lim_opt {
-                if let Err(n) = lim.check() {
-                    let wait_for = n.wait_time_from(clock.now());
-                    // check if we have recently logged rate
-                    // limits, but print out a message only once
-                    // per second.
-                    if most_recent_rate_limit.elapsed().as_secs() > 10 {
-                        warn!(
-                            "rate limit reached for event creation (sleep for {:?}) (suppressing future messages for 10 seconds)",
-                            wait_for
-                        );
-                        // reset last rate limit message
-                        most_recent_rate_limit = Instant::now();
+    };
+    for (p, result) in batch.into_iter().zip(results) {
+        let event = p.event;
+        match result {
+            Ok(0) => {
+                trace!("ignoring duplicate or deleted event");
+                p.notice_tx.try_send(Notice::duplicate(event.id)).ok();
+            }
+            Ok(_) => {
+                info!(
+                    "persisted event: {:?} (kind: {}) from: {:?} in: {:?} (IP: {:?})",
+                    event.get_event_id_prefix(),
+                    event.kind,
+                    event.get_author_prefix(),
+                    start.elapsed(),
+                    p.source_ip,
+                );
+                published += 1;
+                // send this out to all clients
+                bcast_tx.send(BroadcastEvent::from(event.clone())).ok();
+                p.notice_tx.try_send(Notice::saved(event.id)).ok();
+                // update and publish group state
+                if let (Some(change), Some(signer)) = (p.group_change, group_signer.as_mut()) {
+                    if let Err(e) =
+                        nip29::apply_change(repo, change, signer, groups, bcast_tx).await
+                    {
+                        warn!("group update failed: {:?}", e);
                     }
-                    // block event writes, allowing them to queue up
-                    thread::sleep(wait_for);
-                    continue;
                 }
             }
+            Err(Error::AuthorVanished) => {
+                info!(
+                    "refused event from vanished author: {:?}",
+                    event.get_author_prefix()
+                );
+                let msg = "author has requested to vanish";
+                p.notice_tx.try_send(Notice::blocked(event.id, msg)).ok();
+            }
+            Err(err) => {
+                warn!("event insert failed: {:?}", err);
+                let msg = "relay experienced an error trying to publish the latest event";
+                p.notice_tx.try_send(Notice::error(event.id, msg)).ok();
+            }
         }
     }
-    info!("database connection closed");
-    Ok(())
+    published
 }
 
 /// Serialized event associated with a specific subscription request.
-#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(Debug)]
 pub struct QueryResult {
     /// Subscription identifier
     pub sub_id: String,
     /// Serialized event
     pub event: String,
+    /// Reason the relay ended the query early, if it did
+    pub closed: Option<QueryClosed>,
+}
+
+impl QueryResult {
+    /// A query ended by the relay before it could complete.
+    #[must_use]
+    pub fn closed(
+        sub_id: String,
+        status: EventResultStatus,
+        msg: &str,
+        abandon_query_rx: oneshot::Receiver<()>,
+    ) -> QueryResult {
+        QueryResult {
+            sub_id,
+            event: String::new(),
+            closed: Some(QueryClosed {
+                status,
+                msg: msg.to_owned(),
+                abandon_query_rx,
+            }),
+        }
+    }
+}
+
+/// Why the relay ended a query early.
+#[derive(Debug)]
+pub struct QueryClosed {
+    pub status: EventResultStatus,
+    pub msg: String,
+    /// Cancellation channel of the ended query, which identifies it
+    abandon_query_rx: oneshot::Receiver<()>,
+}
+
+impl QueryClosed {
+    /// Check if the ended query is the one cancelled by
+    /// `abandon_query_tx`, rather than an earlier query for a
+    /// subscription with the same identifier.  Only call this once.
+    pub fn ends_query(&mut self, abandon_query_tx: &oneshot::Sender<()>) -> bool {
+        if abandon_query_tx.is_closed() {
+            return false;
+        }
+        // closing our receiver only closes the sender of the same query
+        self.abandon_query_rx.close();
+        abandon_query_tx.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_query_identified_by_its_channel() {
+        let closed = |rx| {
+            QueryResult::closed("s".to_owned(), EventResultStatus::Error, "x", rx)
+                .closed
+                .unwrap()
+        };
+        let (old_tx, old_rx) = oneshot::channel::<()>();
+        let (new_tx, _new_rx) = oneshot::channel::<()>();
+        assert!(closed(old_rx).ends_query(&old_tx));
+        // a newer query with the same subscription id is not ended
+        let (_, old_rx) = oneshot::channel::<()>();
+        assert!(!closed(old_rx).ends_query(&new_tx));
+        assert!(!new_tx.is_closed());
+    }
+
+    #[test]
+    fn whitelist_and_allowlist_both_accepted() {
+        let whitelist = vec!["w".to_owned()];
+        let mut moderation = Moderation::default();
+        moderation.allowed_pubkeys.insert("a".to_owned(), None);
+        let rejection = |moderation: &Moderation, pubkey: &str| {
+            let mut event = Event::simple_event();
+            event.pubkey = pubkey.to_owned();
+            publish_rejection(Some(&whitelist), false, moderation, &event)
+        };
+        assert_eq!(rejection(&moderation, "w"), None);
+        assert_eq!(rejection(&moderation, "a"), None);
+        assert_eq!(
+            rejection(&moderation, "x"),
+            Some("pubkey is not allowed to publish to this relay")
+        );
+        // bans still apply to whitelisted authors
+        moderation.banned_pubkeys.insert("w".to_owned(), None);
+        assert_eq!(
+            rejection(&moderation, "w"),
+            Some("pubkey is banned from this relay")
+        );
+    }
+
+    #[test]
+    fn whitelisted_delegators() {
+        let whitelist = vec!["w".to_owned()];
+        let moderation = Moderation::default();
+        let mut event = Event::simple_event();
+        event.pubkey = "d".to_owned();
+        // delegated by a whitelisted pubkey
+        event.delegated_by = Some("w".to_owned());
+        assert!(!is_whitelisted(&whitelist, false, &event));
+        assert!(is_whitelisted(&whitelist, true, &event));
+        assert_eq!(
+            publish_rejection(Some(&whitelist), true, &moderation, &event),
+            None
+        );
+        assert!(publish_rejection(Some(&whitelist), false, &moderation, &event).is_some());
+        // delegated by someone else
+        event.delegated_by = Some("x".to_owned());
+        assert!(!is_whitelisted(&whitelist, true, &event));
+        assert!(publish_rejection(Some(&whitelist), true, &moderation, &event).is_some());
+        // not delegated
+        event.delegated_by = None;
+        assert!(!is_whitelisted(&whitelist, true, &event));
+        event.pubkey = "w".to_owned();
+        assert!(is_whitelisted(&whitelist, true, &event));
+    }
 }
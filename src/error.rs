@@ -7,6 +7,8 @@ use tungstenite::error::Error as WsError;
 pub type Result<T, E = Error> = result::Result<T, E>;
 
 /// Custom error type for Nostr
+///
+/// Large errors from other crates are boxed, to keep results small.
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Protocol parse error")]
@@ -37,7 +39,7 @@ pub enum Error {
     #[error("JSON parsing failed")]
     JsonParseFailed(serde_json::Error),
     #[error("WebSocket proto error")]
-    WebsocketError(WsError),
+    WebsocketError(Box<WsError>),
     #[error("Command unknown")]
     CommandUnknownError,
     #[error("SQL error")]
@@ -67,9 +69,19 @@ pub enum Error {
     #[error("Authz error")]
     AuthzError,
     #[error("Tonic GRPC error")]
-    TonicError(tonic::Status),
+    TonicError(Box<tonic::Status>),
     #[error("Invalid AUTH message")]
     AuthFailure,
+    #[error("AUTH event has the wrong kind")]
+    AuthWrongKind,
+    #[error("AUTH event created_at is too far from the current time")]
+    AuthStaleEvent,
+    #[error("AUTH challenge does not match")]
+    AuthChallengeMismatch,
+    #[error("AUTH relay URL does not match")]
+    AuthRelayMismatch,
+    #[error("Event author has requested to vanish")]
+    AuthorVanished,
     #[error("I/O Error")]
     IoError(std::io::Error),
     #[error("Unknown/Undocumented")]
@@ -130,7 +142,7 @@ impl From<serde_json::Error> for Error {
 impl From<WsError> for Error {
     /// Wrap Websocket error
     fn from(r: WsError) -> Self {
-        Error::WebsocketError(r)
+        Error::WebsocketError(Box::new(r))
     }
 }
 
@@ -144,7 +156,7 @@ impl From<config::ConfigError> for Error {
 impl From<tonic::Status> for Error {
     /// Wrap Config error
     fn from(r: tonic::Status) -> Self {
-        Error::TonicError(r)
+        Error::TonicError(Box::new(r))
     }
 }
 
@@ -18,6 +18,7 @@ use serde_json::Number;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::str::FromStr;
+use std::sync::Arc;
 use tracing::{debug, info};
 
 lazy_static! {
@@ -25,6 +26,16 @@ lazy_static! {
     pub static ref SECP: Secp256k1<VerifyOnly> = Secp256k1::verification_only();
 }
 
+/// Event kinds that may be restricted to their participants (encrypted
+/// direct messages and gift wraps).
+pub const PRIVATE_KINDS: [u64; 2] = [4, 1059];
+
+/// Kind of requests to vanish (NIP-62)
+pub const VANISH_KIND: u64 = 62;
+
+/// Relay tag value addressing a vanish request to every relay
+pub const ALL_RELAYS: &str = "ALL_RELAYS";
+
 /// Event command in network format.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct EventCmd {
@@ -37,6 +48,17 @@ impl EventCmd {
     pub fn event_id(&self) -> &str {
         &self.event.id
     }
+
+    /// Command name (`EVENT` or `AUTH`)
+    #[must_use]
+    pub fn cmd(&self) -> &str {
+        &self.cmd
+    }
+
+    #[must_use]
+    pub fn into_event(self) -> Event {
+        self.event
+    }
 }
 
 /// Parsed nostr event.
@@ -55,7 +77,27 @@ pub struct Event {
     pub sig: String,
     // Optimization for tag search, built on demand.
     #[serde(skip)]
-    pub tagidx: Option<HashMap<char, HashSet<String>>>,
+    pub tagidx: Option<HashMap<String, HashSet<String>>>,
+}
+
+/// An event published to subscribers, serialized once for all of
+/// them.
+#[derive(Debug, Clone)]
+pub struct BroadcastEvent {
+    pub event: Arc<Event>,
+    /// JSON representation of the event
+    pub json: Arc<str>,
+}
+
+impl From<Event> for BroadcastEvent {
+    fn from(event: Event) -> Self {
+        // events only contain strings and numbers, which always serialize.
+        let json = serde_json::to_string(&event).expect("event could not be serialized");
+        BroadcastEvent {
+            event: Arc::new(event),
+            json: json.into(),
+        }
+    }
 }
 
 /// Simple tag type for array of array of strings.
@@ -90,22 +132,37 @@ pub fn single_char_tagname(tagname: &str) -> Option<char> {
     }
 }
 
+/// Determine if a tag name is indexed, because it is a single char
+/// or one of the configured extra names.
+#[must_use]
+pub fn is_indexed_tagname(tagname: &str, extra: &[String]) -> bool {
+    single_char_tagname(tagname).is_some() || extra.iter().any(|n| n == tagname)
+}
+
 pub enum EventWrapper {
     WrappedEvent(Event),
     WrappedAuth(Event),
 }
 
+impl EventWrapper {
+    /// Wrap an `EVENT` whose signature has been validated, indexing
+    /// single-letter tags and the configured `indexed_tags`.
+    #[must_use]
+    pub fn validated(mut e: Event, indexed_tags: &[String]) -> EventWrapper {
+        e.build_index(indexed_tags);
+        e.update_delegation();
+        WrappedEvent(e)
+    }
+}
+
 /// Convert network event to parsed/validated event.
 impl From<EventCmd> for Result<EventWrapper> {
     fn from(ec: EventCmd) -> Result<EventWrapper> {
         // ensure command is correct
         if ec.cmd == "EVENT" {
-            ec.event.validate().map(|_| {
-                let mut e = ec.event;
-                e.build_index();
-                e.update_delegation();
-                WrappedEvent(e)
-            })
+            ec.event
+                .validate()
+                .map(|_| EventWrapper::validated(ec.event, &[]))
         } else if ec.cmd == "AUTH" {
             // we don't want to validate the event here, because NIP-42 can be disabled
             // it will be validated later during the authentication process
@@ -153,6 +210,26 @@ impl Event {
         }
     }
 
+    /// Is this a protected event (NIP-70), carrying a `["-"]` tag?
+    #[must_use]
+    pub fn is_protected(&self) -> bool {
+        self.tags.iter().any(|t| t.len() == 1 && t[0] == "-")
+    }
+
+    /// Is this a request to vanish (NIP-62) from the relay at the
+    /// given URL, either by name or by addressing all relays?
+    #[must_use]
+    pub fn is_vanish_request(&self, relay_url: Option<&str>) -> bool {
+        if self.kind != VANISH_KIND {
+            return false;
+        }
+        let relay_url = relay_url.map(|u| u.trim_end_matches('/'));
+        self.tag_values_by_name("relay").iter().any(|r| {
+            r == ALL_RELAYS
+                || relay_url.is_some_and(|u| r.trim_end_matches('/').eq_ignore_ascii_case(u))
+        })
+    }
+
     /// Determine the time at which this event should expire
     pub fn expiration(&self) -> Option<u64> {
         let default = "".to_string();
@@ -206,6 +283,66 @@ impl Event {
         }
     }
 
+    /// Proof-of-work difficulty (NIP-13), as leading zero bits of the id
+    #[must_use]
+    pub fn pow_difficulty(&self) -> u32 {
+        let mut zeros = 0;
+        for c in self.id.chars() {
+            match c.to_digit(16) {
+                Some(0) => zeros += 4,
+                Some(d) => return zeros + (d.leading_zeros() - 28),
+                None => break,
+            }
+        }
+        zeros
+    }
+
+    /// Target difficulty committed to by a `nonce` tag, if any
+    #[must_use]
+    pub fn pow_target(&self) -> Option<u32> {
+        self.tags
+            .iter()
+            .find(|t| t.len() > 2 && t[0] == "nonce")
+            .and_then(|t| t[2].parse().ok())
+    }
+
+    /// Effective proof-of-work difficulty; a committed target lower
+    /// than the actual difficulty limits it, so lucky ids do not count.
+    #[must_use]
+    pub fn effective_pow_difficulty(&self) -> u32 {
+        let difficulty = self.pow_difficulty();
+        self.pow_target()
+            .map_or(difficulty, |target| difficulty.min(target))
+    }
+
+    /// Address (`<kind>:<pubkey>:<d-tag>`) of a parameterized replaceable event
+    #[must_use]
+    pub fn address(&self) -> Option<String> {
+        self.distinct_param()
+            .map(|d| format!("{}:{}:{}", self.kind, self.pubkey, d))
+    }
+
+    /// Parameterized replaceable events that a deletion refers to by
+    /// address (`a` tags), as kind and `d` tag pairs.  Addresses of
+    /// other authors are ignored.
+    #[must_use]
+    pub fn deleted_addresses(&self) -> Vec<(u64, String)> {
+        if self.kind != 5 {
+            return vec![];
+        }
+        self.tag_values_by_name("a")
+            .iter()
+            .filter_map(|a| {
+                let mut parts = a.splitn(3, ':');
+                let kind: u64 = parts.next()?.parse().ok()?;
+                let pubkey = parts.next()?;
+                let d_tag = parts.next()?;
+                ((30000..40000).contains(&kind) && pubkey == self.pubkey)
+                    .then(|| (kind, d_tag.to_owned()))
+            })
+            .collect()
+    }
+
     /// Pull a NIP-05 Name out of the event, if one exists
     #[must_use]
     pub fn get_nip05_addr(&self) -> Option<nip05::Nip05Name> {
@@ -267,28 +404,26 @@ impl Event {
     pub fn update_delegation(&mut self) {
         self.delegated_by = self.delegated_author();
     }
-    /// Build an event tag index
-    pub fn build_index(&mut self) {
+    /// Build an event tag index, of the tag names that filters may
+    /// search: single letters and the configured `indexed_tags`.
+    pub fn build_index(&mut self, indexed_tags: &[String]) {
         // if there are no tags; just leave the index as None
         if self.tags.is_empty() {
             return;
         }
         // otherwise, build an index
-        let mut idx: HashMap<char, HashSet<String>> = HashMap::new();
+        let mut idx: HashMap<String, HashSet<String>> = HashMap::new();
         // iterate over tags that have at least 2 elements
         for t in self.tags.iter().filter(|x| x.len() > 1) {
             let tagname = t.get(0).unwrap();
-            let tagnamechar_opt = single_char_tagname(tagname);
-            if tagnamechar_opt.is_none() {
+            if !is_indexed_tagname(tagname, indexed_tags) {
                 continue;
             }
-            let tagnamechar = tagnamechar_opt.unwrap();
             let tagval = t.get(1).unwrap();
-            // ensure a vector exists for this tag
-            idx.entry(tagnamechar).or_insert_with(HashSet::new);
-            // get the tag vec and insert entry
-            let idx_tag_vec = idx.get_mut(&tagnamechar).expect("could not get tag vector");
-            idx_tag_vec.insert(tagval.clone());
+            // ensure a vector exists for this tag, and insert entry
+            idx.entry(tagname.clone())
+                .or_default()
+                .insert(tagval.clone());
         }
         // save the tag structure
         self.tagidx = Some(idx);
@@ -304,6 +439,16 @@ impl Event {
         self.pubkey.chars().take(8).collect()
     }
 
+    /// Check if a pubkey is the author, or a `p`-tagged recipient.
+    #[must_use]
+    pub fn is_participant(&self, pubkey: &str) -> bool {
+        self.pubkey == pubkey
+            || self
+                .tags
+                .iter()
+                .any(|t| t.len() > 1 && t[0] == "p" && t[1] == pubkey)
+    }
+
     /// Retrieve tag initial values across all tags matching the name
     #[must_use]
     pub fn tag_values_by_name(&self, tag_name: &str) -> Vec<String> {
@@ -332,9 +477,8 @@ impl Event {
         true
     }
 
-    /// Check if this event has a valid signature.
-    pub fn validate(&self) -> Result<()> {
-        // TODO: return a Result with a reason for invalid events
+    /// Compute the digest of the event, ensuring it matches the id.
+    fn validated_digest(&self) -> Result<sha256::Hash> {
         // validation is performed by:
         // * parsing JSON string into event fields
         // * create an array:
@@ -354,8 +498,24 @@ impl Event {
             debug!("event id does not match digest");
             return Err(EventInvalidId);
         }
+        Ok(digest)
+    }
+
+    /// Check if the event id matches its contents, without checking
+    /// the signature.
+    pub fn validate_id(&self) -> Result<()> {
+        self.validated_digest().map(|_| ())
+    }
+
+    /// Check if this event has a valid signature.
+    pub fn validate(&self) -> Result<()> {
+        // TODO: return a Result with a reason for invalid events
+        let digest = self.validated_digest()?;
         // * validate the message digest (sig) using the pubkey & computed sha256 message hash.
-        let sig = schnorr::Signature::from_str(&self.sig).unwrap();
+        let Ok(sig) = schnorr::Signature::from_str(&self.sig) else {
+            debug!("client sent malformed signature");
+            return Err(EventInvalidSignature);
+        };
         if let Ok(msg) = secp256k1::Message::from_slice(digest.as_ref()) {
             if let Ok(pubkey) = XOnlyPublicKey::from_str(&self.pubkey) {
                 SECP.verify_schnorr(&sig, &msg, &pubkey)
@@ -409,10 +569,10 @@ impl Event {
 
     /// Determine if the given tag and value set intersect with tags in this event.
     #[must_use]
-    pub fn generic_tag_val_intersect(&self, tagname: char, check: &HashSet<String>) -> bool {
+    pub fn generic_tag_val_intersect(&self, tagname: &str, check: &HashSet<String>) -> bool {
         match &self.tagidx {
             // check if this is indexable tagname
-            Some(idx) => match idx.get(&tagname) {
+            Some(idx) => match idx.get(tagname) {
                 Some(valset) => {
                     let common = valset.intersection(check);
                     common.count() > 0
@@ -422,6 +582,15 @@ impl Event {
             None => false,
         }
     }
+
+    /// Determine if every value in the given set is present for a tag in this event.
+    #[must_use]
+    pub fn generic_tag_val_superset(&self, tagname: &str, check: &HashSet<String>) -> bool {
+        self.tagidx
+            .as_ref()
+            .and_then(|idx| idx.get(tagname))
+            .is_some_and(|valset| check.is_subset(valset))
+    }
 }
 
 #[cfg(test)]
@@ -448,17 +617,31 @@ mod tests {
     fn empty_event_tag_match() {
         let event = Event::simple_event();
         assert!(!event
-            .generic_tag_val_intersect('e', &HashSet::from(["foo".to_owned(), "bar".to_owned()])));
+            .generic_tag_val_intersect("e", &HashSet::from(["foo".to_owned(), "bar".to_owned()])));
+    }
+
+    #[test]
+    fn only_indexed_tag_names() {
+        let mut event = Event::simple_event();
+        event.tags = vec![
+            vec!["e".to_owned(), "foo".to_owned()],
+            vec!["client".to_owned(), "app".to_owned()],
+            vec!["title".to_owned(), "hi".to_owned()],
+        ];
+        event.build_index(&["title".to_owned()]);
+        let idx = event.tagidx.as_ref().unwrap();
+        assert!(idx.contains_key("e") && idx.contains_key("title"));
+        assert!(!idx.contains_key("client"));
     }
 
     #[test]
     fn single_event_tag_match() {
         let mut event = Event::simple_event();
         event.tags = vec![vec!["e".to_owned(), "foo".to_owned()]];
-        event.build_index();
+        event.build_index(&[]);
         assert_eq!(
             event.generic_tag_val_intersect(
-                'e',
+                "e",
                 &HashSet::from(["foo".to_owned(), "bar".to_owned()])
             ),
             true
@@ -776,4 +959,118 @@ mod tests {
         ];
         assert_eq!(event.expiration(), Some(10));
     }
+
+    #[test]
+    fn participants() {
+        let mut event = Event::simple_event();
+        event.kind = 4;
+        event.pubkey = "abc".to_owned();
+        event.tags = vec![
+            vec!["e".to_string(), "def".to_string()],
+            vec!["p".to_string(), "123".to_string()],
+        ];
+        assert!(event.is_participant("abc"));
+        assert!(event.is_participant("123"));
+        assert!(!event.is_participant("def"));
+    }
+
+    #[test]
+    fn param_replaceable_address() {
+        let mut event = Event::simple_event();
+        event.kind = 30023;
+        event.pubkey = "abc".to_owned();
+        event.tags = vec![vec!["d".to_string(), "post:1".to_string()]];
+        assert_eq!(event.address(), Some("30023:abc:post:1".to_owned()));
+        event.kind = 1;
+        assert_eq!(event.address(), None);
+    }
+
+    #[test]
+    fn deleted_addresses() {
+        let mut event = Event::simple_event();
+        event.kind = 5;
+        event.pubkey = "abc".to_owned();
+        event.tags = vec![
+            vec!["a".to_string(), "30023:abc:post:1".to_string()],
+            // other authors cannot be deleted
+            vec!["a".to_string(), "30023:def:post:1".to_string()],
+            // not parameterized replaceable
+            vec!["a".to_string(), "1:abc:post:1".to_string()],
+            vec!["a".to_string(), "30023:abc".to_string()],
+            vec!["a".to_string(), "30000:abc:".to_string()],
+        ];
+        assert_eq!(
+            event.deleted_addresses(),
+            vec![(30023, "post:1".to_owned()), (30000, "".to_owned())]
+        );
+    }
+
+    #[test]
+    fn pow_difficulty() {
+        let mut event = Event::simple_event();
+        event.id = "000006d8c378af1779d2feebc7603a125d99eca0ccf1085959b307f64e5dd358".to_owned();
+        assert_eq!(event.pow_difficulty(), 21);
+        event.id = "f000000000000000000000000000000000000000000000000000000000000000".to_owned();
+        assert_eq!(event.pow_difficulty(), 0);
+        event.id = "0".repeat(64);
+        assert_eq!(event.pow_difficulty(), 256);
+    }
+
+    #[test]
+    fn pow_committed_target() {
+        let mut event = Event::simple_event();
+        event.id = "000006d8c378af1779d2feebc7603a125d99eca0ccf1085959b307f64e5dd358".to_owned();
+        event.tags = vec![vec![
+            "nonce".to_string(),
+            "776797".to_string(),
+            "20".to_string(),
+        ]];
+        assert_eq!(event.pow_target(), Some(20));
+        // the lucky extra bit does not count
+        assert_eq!(event.effective_pow_difficulty(), 20);
+        event.tags = vec![vec![
+            "nonce".to_string(),
+            "776797".to_string(),
+            "24".to_string(),
+        ]];
+        assert_eq!(event.effective_pow_difficulty(), 21);
+        // without a commitment, the actual difficulty is used
+        event.tags = vec![vec!["nonce".to_string(), "776797".to_string()]];
+        assert_eq!(event.effective_pow_difficulty(), 21);
+    }
+
+    #[test]
+    fn protected_event() {
+        let mut event = Event::simple_event();
+        assert!(!event.is_protected());
+        event.tags = vec![vec!["-".to_string()]];
+        assert!(event.is_protected());
+        // a dash tag with a value is not a protection marker
+        event.tags = vec![vec!["-".to_string(), "x".to_string()]];
+        assert!(!event.is_protected());
+    }
+
+    #[test]
+    fn vanish_request() {
+        let relay_url = Some("wss://relay.example.com/");
+        let mut event = Event::simple_event();
+        event.kind = VANISH_KIND;
+        assert!(!event.is_vanish_request(relay_url));
+        event.tags = vec![vec![
+            "relay".to_string(),
+            "wss://relay.example.com".to_string(),
+        ]];
+        assert!(event.is_vanish_request(relay_url));
+        assert!(!event.is_vanish_request(None));
+        event.tags = vec![vec![
+            "relay".to_string(),
+            "wss://other.example.com".to_string(),
+        ]];
+        assert!(!event.is_vanish_request(relay_url));
+        event.tags = vec![vec!["relay".to_string(), ALL_RELAYS.to_string()]];
+        assert!(event.is_vanish_request(None));
+        // only kind 62 events are vanish requests
+        event.kind = 1;
+        assert!(!event.is_vanish_request(relay_url));
+    }
 }
@@ -0,0 +1,365 @@
+//! Realtime delivery of new events to matching subscriptions
+//!
+//! Every subscription is registered in a shared index, under the most
+//! selective attribute of each of its filters (event id, author, tag
+//! value or kind).  A new event is only checked against the
+//! subscriptions found under its own attributes, and each connection
+//! receives the events it is interested in on its own channel.
+use crate::event::{BroadcastEvent, Event};
+use crate::subscription::{ReqFilter, Subscription};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, trace, warn};
+
+/// Length of a full (non-prefix) event id or public key
+const FULL_HEX_LEN: usize = 64;
+
+/// A new event, and the subscriptions of a connection it matched.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub event: BroadcastEvent,
+    pub sub_ids: Vec<String>,
+}
+
+/// Attribute of an event that subscriptions are indexed by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum IndexKey {
+    Id(String),
+    Author(String),
+    Tag(String, String),
+    Kind(u64),
+    /// Filters with none of the above must see every event
+    Any,
+}
+
+/// A subscription, identified by connection and subscription id.
+type SubKey = (u64, String);
+
+struct Listener {
+    tx: mpsc::Sender<Delivery>,
+    /// Subscriptions, with the keys they were indexed under
+    subs: HashMap<String, (Subscription, Vec<IndexKey>)>,
+}
+
+#[derive(Default)]
+struct Inner {
+    listeners: HashMap<u64, Listener>,
+    index: HashMap<IndexKey, HashSet<SubKey>>,
+}
+
+/// Index of the subscriptions of all connections.
+#[derive(Default)]
+pub struct SubscriptionIndex {
+    next_id: AtomicU64,
+    inner: RwLock<Inner>,
+}
+
+/// A connection's registration with the index.  Subscriptions are
+/// removed from the index when this is dropped.
+pub struct Registration {
+    id: u64,
+    index: Arc<SubscriptionIndex>,
+}
+
+/// Keys a filter is indexed under; any event the filter matches has
+/// at least one of them.  No keys means the filter can never match.
+fn filter_keys(f: &ReqFilter) -> Vec<IndexKey> {
+    if f.force_no_match {
+        return vec![];
+    }
+    let full = |vs: &Vec<String>| vs.iter().all(|v| v.len() == FULL_HEX_LEN);
+    if let Some(ids) = f.ids.as_ref().filter(|v| full(v)) {
+        return ids.iter().map(|id| IndexKey::Id(id.clone())).collect();
+    }
+    if let Some(authors) = f.authors.as_ref().filter(|v| full(v)) {
+        return authors
+            .iter()
+            .map(|a| IndexKey::Author(a.clone()))
+            .collect();
+    }
+    // every value of an '&' filter is present, so one is enough.
+    if let Some((name, val)) = f.all_tags.as_ref().and_then(|m| {
+        m.iter()
+            .find_map(|(k, vs)| vs.iter().next().map(|v| (k, v)))
+    }) {
+        return vec![IndexKey::Tag(name.clone(), val.clone())];
+    }
+    if let Some((name, vals)) = f.tags.as_ref().and_then(|m| m.iter().next()) {
+        return vals
+            .iter()
+            .map(|v| IndexKey::Tag(name.clone(), v.clone()))
+            .collect();
+    }
+    if let Some(kinds) = &f.kinds {
+        return kinds.iter().map(|k| IndexKey::Kind(*k)).collect();
+    }
+    vec![IndexKey::Any]
+}
+
+/// Keys to look up subscriptions that may be interested in an event.
+fn event_keys(event: &Event) -> Vec<IndexKey> {
+    let mut keys = vec![
+        IndexKey::Id(event.id.clone()),
+        IndexKey::Author(event.pubkey.clone()),
+        IndexKey::Kind(event.kind),
+        IndexKey::Any,
+    ];
+    if let Some(delegator) = &event.delegated_by {
+        keys.push(IndexKey::Author(delegator.clone()));
+    }
+    for tag in &event.tags {
+        if let [name, val, ..] = tag.as_slice() {
+            keys.push(IndexKey::Tag(name.clone(), val.clone()));
+        }
+    }
+    keys
+}
+
+impl Inner {
+    fn remove(&mut self, conn: u64, sub_id: &str) {
+        let Some(listener) = self.listeners.get_mut(&conn) else {
+            return;
+        };
+        let Some((_, keys)) = listener.subs.remove(sub_id) else {
+            return;
+        };
+        let sub_key = (conn, sub_id.to_owned());
+        for key in keys {
+            if let Some(subs) = self.index.get_mut(&key) {
+                subs.remove(&sub_key);
+                if subs.is_empty() {
+                    self.index.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+impl SubscriptionIndex {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a connection, returning the channel it receives
+    /// matching events on.
+    #[must_use]
+    pub fn register(self: &Arc<Self>, buffer: usize) -> (Registration, mpsc::Receiver<Delivery>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(buffer.max(1));
+        self.inner.write().unwrap().listeners.insert(
+            id,
+            Listener {
+                tx,
+                subs: HashMap::new(),
+            },
+        );
+        let reg = Registration {
+            id,
+            index: self.clone(),
+        };
+        (reg, rx)
+    }
+
+    /// Number of registered connections
+    #[must_use]
+    pub fn connection_count(&self) -> usize {
+        self.inner.read().unwrap().listeners.len()
+    }
+
+    /// Number of subscriptions across all connections
+    #[must_use]
+    pub fn subscription_count(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        inner.listeners.values().map(|l| l.subs.len()).sum()
+    }
+
+    /// Deliver an event to every connection with a matching
+    /// subscription.  Connections that are not keeping up miss it.
+    pub fn publish(&self, bcast: &BroadcastEvent) {
+        let event = &bcast.event;
+        let inner = self.inner.read().unwrap();
+        let candidates: HashSet<&SubKey> = event_keys(event)
+            .iter()
+            .filter_map(|k| inner.index.get(k))
+            .flatten()
+            .collect();
+        if candidates.is_empty() {
+            return;
+        }
+        let mut matches: HashMap<u64, Vec<String>> = HashMap::new();
+        for (conn, sub_id) in candidates {
+            let interested = inner
+                .listeners
+                .get(conn)
+                .and_then(|l| l.subs.get(sub_id))
+                .is_some_and(|(sub, _)| sub.interested_in_event(event));
+            if interested {
+                matches.entry(*conn).or_default().push(sub_id.clone());
+            }
+        }
+        if matches.is_empty() {
+            return;
+        }
+        for (conn, sub_ids) in matches {
+            let Some(listener) = inner.listeners.get(&conn) else {
+                continue;
+            };
+            let delivery = Delivery {
+                event: bcast.clone(),
+                sub_ids,
+            };
+            if listener.tx.try_send(delivery).is_err() {
+                debug!(
+                    "dropped realtime event for slow connection: {:?}",
+                    event.get_event_id_prefix()
+                );
+            }
+        }
+    }
+}
+
+impl Registration {
+    /// Start delivering events for a subscription, replacing any
+    /// with the same id.
+    pub fn subscribe(&self, sub: &Subscription) {
+        let mut inner = self.index.inner.write().unwrap();
+        inner.remove(self.id, &sub.id);
+        let keys: HashSet<IndexKey> = sub.filters.iter().flat_map(filter_keys).collect();
+        let keys: Vec<IndexKey> = keys.into_iter().collect();
+        for key in &keys {
+            inner
+                .index
+                .entry(key.clone())
+                .or_default()
+                .insert((self.id, sub.id.clone()));
+        }
+        if let Some(listener) = inner.listeners.get_mut(&self.id) {
+            listener.subs.insert(sub.id.clone(), (sub.clone(), keys));
+        }
+    }
+
+    /// Stop delivering events for a subscription.
+    pub fn unsubscribe(&self, sub_id: &str) {
+        self.index.inner.write().unwrap().remove(self.id, sub_id);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut inner = self.index.inner.write().unwrap();
+        let sub_ids: Vec<String> = inner
+            .listeners
+            .get(&self.id)
+            .map(|l| l.subs.keys().cloned().collect())
+            .unwrap_or_default();
+        for sub_id in sub_ids {
+            inner.remove(self.id, &sub_id);
+        }
+        inner.listeners.remove(&self.id);
+    }
+}
+
+/// Deliver every event published on the broadcast channel to the
+/// connections with matching subscriptions.
+pub async fn dispatch(
+    index: Arc<SubscriptionIndex>,
+    mut bcast_rx: broadcast::Receiver<BroadcastEvent>,
+) {
+    loop {
+        match bcast_rx.recv().await {
+            Ok(event) => {
+                trace!("dispatching event: {:?}", event.event.get_event_id_prefix());
+                index.publish(&event);
+            }
+            Err(broadcast::error::RecvError::Lagged(c)) => {
+                warn!("realtime dispatch lagged, {} events were not delivered", c);
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                info!("broadcast channel closed, stopping realtime dispatch");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sub(json: &str) -> Subscription {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn event(pubkey: &str, kind: u64, tags: Vec<Vec<&str>>) -> Event {
+        let mut e = Event::simple_event();
+        e.id = "e".repeat(64);
+        e.pubkey = pubkey.to_owned();
+        e.kind = kind;
+        e.tags = tags
+            .into_iter()
+            .map(|t| t.into_iter().map(str::to_owned).collect())
+            .collect();
+        e.build_index(&[]);
+        e
+    }
+
+    fn bcast(pubkey: &str, kind: u64, tags: Vec<Vec<&str>>) -> BroadcastEvent {
+        BroadcastEvent::from(event(pubkey, kind, tags))
+    }
+
+    #[test]
+    fn delivers_to_matching_subscriptions() {
+        let index = Arc::new(SubscriptionIndex::new());
+        let alice = "a".repeat(64);
+        let (reg1, mut rx1) = index.register(10);
+        let (reg2, mut rx2) = index.register(10);
+        reg1.subscribe(&sub(&format!(
+            r#"["REQ","authors",{{"authors":["{alice}"]}}]"#
+        )));
+        reg1.subscribe(&sub(r##"["REQ","tags",{"#t":["nostr"],"kinds":[1]}]"##));
+        reg2.subscribe(&sub(r#"["REQ","kinds",{"kinds":[7]}]"#));
+        index.publish(&bcast(&alice, 1, vec![vec!["t", "nostr"]]));
+        let mut delivered = rx1.try_recv().unwrap().sub_ids;
+        delivered.sort();
+        assert_eq!(delivered, vec!["authors", "tags"]);
+        assert!(rx2.try_recv().is_err());
+        index.publish(&bcast(&alice, 7, vec![]));
+        assert_eq!(rx1.try_recv().unwrap().sub_ids, vec!["authors"]);
+        assert_eq!(rx2.try_recv().unwrap().sub_ids, vec!["kinds"]);
+    }
+
+    #[test]
+    fn prefixes_and_empty_filters_are_checked() {
+        let index = Arc::new(SubscriptionIndex::new());
+        let (reg, mut rx) = index.register(10);
+        // prefixes can not be looked up, so are matched by kind.
+        reg.subscribe(&sub(r#"["REQ","prefix",{"authors":["aaaa"],"kinds":[1]}]"#));
+        reg.subscribe(&sub(r#"["REQ","all",{"limit":10}]"#));
+        index.publish(&bcast(&"a".repeat(64), 1, vec![]));
+        let mut delivered = rx.try_recv().unwrap().sub_ids;
+        delivered.sort();
+        assert_eq!(delivered, vec!["all", "prefix"]);
+        index.publish(&bcast(&"b".repeat(64), 1, vec![]));
+        assert_eq!(rx.try_recv().unwrap().sub_ids, vec!["all"]);
+    }
+
+    #[test]
+    fn removes_closed_subscriptions() {
+        let index = Arc::new(SubscriptionIndex::new());
+        let (reg, mut rx) = index.register(10);
+        reg.subscribe(&sub(r#"["REQ","a",{"kinds":[1]}]"#));
+        reg.subscribe(&sub(r#"["REQ","b",{"kinds":[1]}]"#));
+        // replacing a subscription drops its previous filters
+        reg.subscribe(&sub(r#"["REQ","b",{"kinds":[2]}]"#));
+        reg.unsubscribe("a");
+        index.publish(&bcast(&"a".repeat(64), 1, vec![]));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(index.subscription_count(), 1);
+        drop(reg);
+        assert_eq!(index.connection_count(), 0);
+        assert!(index.inner.read().unwrap().index.is_empty());
+    }
+}
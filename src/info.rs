@@ -1,6 +1,7 @@
 //! Relay metadata using NIP-11
 /// Relay Info
-use crate::config::Settings;
+use crate::config::{Fees, PublishAuthMode, Settings};
+use crate::conn::{MAX_SUBSCRIPTIONS, MAX_SUBSCRIPTION_ID_LEN};
 use serde::{Deserialize, Serialize};
 
 pub const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
@@ -24,18 +25,123 @@ pub struct RelayInfo {
     pub software: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limitation: Option<Limitation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Vec<RetentionInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay_countries: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub posting_policy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees: Option<Fees>,
+}
+
+/// Server limitations that clients should respect
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Limitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_content_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subscriptions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subid_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at_upper_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_pow_difficulty: Option<u32>,
+    pub auth_required: bool,
+    pub payment_required: bool,
+    pub restricted_writes: bool,
+}
+
+/// How long events are stored for
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct RetentionInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<Vec<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
 }
 
 /// Convert an Info configuration into public Relay Info
 impl From<Settings> for RelayInfo {
     fn from(c: Settings) -> Self {
-        let mut supported_nips = vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40, 42];
+        let mut supported_nips = vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40, 42, 45, 62, 70, 77];
 
         if c.authorization.nip42_auth {
             supported_nips.push(42);
             supported_nips.sort();
         }
 
+        if c.limits.min_pow_difficulty.is_some() || c.limits.kind_pow_difficulty.is_some() {
+            supported_nips.push(13);
+            supported_nips.sort();
+        }
+
+        if c.groups.enabled {
+            supported_nips.push(29);
+            supported_nips.sort();
+        }
+
+        if !c.admin_pubkeys().is_empty() {
+            supported_nips.push(86);
+            supported_nips.sort();
+        }
+
+        if !c.options.searchable_kinds().is_empty() {
+            supported_nips.push(50);
+            supported_nips.sort();
+        }
+
+        let payment_required = c
+            .info
+            .fees
+            .as_ref()
+            .is_some_and(|f| !f.admission.is_empty() || !f.subscription.is_empty());
+        let auth_required = c.authorization.nip42_publish != PublishAuthMode::Disabled;
+        let limitation = Limitation {
+            max_message_length: c.limits.max_ws_message_bytes,
+            // a limit of zero means events of any size are accepted
+            max_content_length: c.limits.max_event_bytes.filter(|&b| b > 0),
+            max_subscriptions: Some(MAX_SUBSCRIPTIONS),
+            max_subid_length: Some(MAX_SUBSCRIPTION_ID_LEN),
+            created_at_upper_limit: c.options.reject_future_seconds,
+            min_pow_difficulty: c.limits.min_pow_difficulty,
+            auth_required,
+            payment_required,
+            restricted_writes: auth_required
+                || c.authorization.pubkey_whitelist.is_some()
+                || c.verified_users.is_enabled()
+                || c.limits.min_pow_difficulty.is_some()
+                || c.limits.kind_pow_difficulty.is_some()
+                || payment_required,
+        };
+
+        let retention = if c.retention.is_active() {
+            Some(vec![RetentionInfo {
+                kinds: None,
+                time: c.retention.persist_days.map(|d| d as u64 * 86400),
+                count: c.retention.max_events,
+            }])
+        } else {
+            None
+        };
+
         let i = c.info;
 
         RelayInfo {
@@ -47,6 +153,89 @@ impl From<Settings> for RelayInfo {
             supported_nips: Some(supported_nips),
             software: Some("https://github.com/v0l/nostr-rs-relay".to_owned()),
             version: CARGO_PKG_VERSION.map(std::borrow::ToOwned::to_owned),
+            limitation: Some(limitation),
+            retention,
+            relay_countries: i.relay_countries,
+            language_tags: i.language_tags,
+            tags: i.tags,
+            posting_policy: i.posting_policy,
+            icon: i.icon,
+            banner: i.banner,
+            fees: i.fees,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Fee;
+
+    #[test]
+    fn limitation_from_settings() {
+        let mut settings = Settings::default();
+        settings.limits.max_ws_message_bytes = Some(131_072);
+        settings.authorization.pubkey_whitelist = Some(vec![]);
+        let info = RelayInfo::from(settings);
+        let limitation = info.limitation.unwrap();
+        assert_eq!(limitation.max_message_length, Some(131_072));
+        assert!(limitation.restricted_writes);
+        assert!(!limitation.payment_required);
+        assert!(info.retention.is_none());
+        assert_eq!(limitation.min_pow_difficulty, None);
+    }
+
+    #[test]
+    fn limitation_document() {
+        let mut settings = Settings::default();
+        settings.limits.max_event_bytes = Some(65_536);
+        let info = RelayInfo::from(settings.clone());
+        let json = serde_json::to_value(info.limitation.unwrap()).unwrap();
+        assert_eq!(json["max_content_length"], 65_536);
+        assert_eq!(json["auth_required"], false);
+        assert_eq!(json["restricted_writes"], false);
+        // requiring authentication to publish is reported
+        settings.authorization.nip42_auth = true;
+        settings.authorization.nip42_publish = PublishAuthMode::Authenticated;
+        settings.limits.max_event_bytes = Some(0);
+        let info = RelayInfo::from(settings);
+        let json = serde_json::to_value(info.limitation.unwrap()).unwrap();
+        assert!(json.get("max_content_length").is_none());
+        assert_eq!(json["auth_required"], true);
+        assert_eq!(json["restricted_writes"], true);
+    }
+
+    #[test]
+    fn pow_limitation() {
+        let mut settings = Settings::default();
+        settings.limits.min_pow_difficulty = Some(16);
+        let info = RelayInfo::from(settings);
+        assert!(info.supported_nips.unwrap().contains(&13));
+        assert_eq!(info.limitation.unwrap().min_pow_difficulty, Some(16));
+    }
+
+    #[test]
+    fn retention_and_fees() {
+        let mut settings = Settings::default();
+        settings.retention.persist_days = Some(2);
+        settings.info.fees = Some(Fees {
+            admission: vec![Fee {
+                amount: 1000,
+                unit: "msats".to_owned(),
+                period: None,
+                kinds: None,
+            }],
+            ..Fees::default()
+        });
+        let info = RelayInfo::from(settings);
+        assert!(info.limitation.unwrap().payment_required);
+        let retention = info.retention.unwrap();
+        assert_eq!(retention[0].time, Some(172_800));
+        assert_eq!(retention[0].count, None);
+        let json = serde_json::to_value(info.fees).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"admission": [{"amount": 1000, "unit": "msats"}]})
+        );
+    }
+}
@@ -6,13 +6,19 @@ pub mod db;
 pub mod delegation;
 pub mod error;
 pub mod event;
+pub mod fanout;
 pub mod hexrange;
 pub mod info;
 pub mod nauthz;
+pub mod negentropy;
 pub mod nip05;
+pub mod nip29;
+pub mod nip86;
+pub mod nip98;
 pub mod notice;
 pub mod repo;
 pub mod subscription;
 pub mod utils;
+pub mod verify;
 // Public API for creating relays programatically
 pub mod server;
@@ -1,9 +1,13 @@
 //! Server process
 use clap::Parser;
 use console_subscriber::ConsoleLayer;
-use nostr_rs_relay::cli::CLIArgs;
+use nostr_rs_relay::cli::{CLIArgs, Command, NamesAction};
 use nostr_rs_relay::config;
-use nostr_rs_relay::server::start_server;
+use nostr_rs_relay::db::build_repo;
+use nostr_rs_relay::error::{Error, Result};
+use nostr_rs_relay::nip05::is_valid_local_name;
+use nostr_rs_relay::server::{create_metrics, start_server};
+use nostr_rs_relay::utils::is_lower_hex;
 use std::sync::mpsc as syncmpsc;
 use std::sync::mpsc::{Receiver as MpscReceiver, Sender as MpscSender};
 use std::thread;
@@ -37,6 +41,14 @@ fn main() {
     if let Some(db_dir) = db_dir_arg {
         settings.database.data_directory = db_dir;
     }
+    // run a management command instead of the server, if requested
+    if let Some(Command::Names { action }) = args.command {
+        if let Err(e) = manage_names(&settings, action) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
     // we should have a 'control plane' channel to monitor and bump
     // the server.  this will let us do stuff like clear the database,
     // shutdown, etc.; for now all this does is initiate shutdown if
@@ -50,3 +62,36 @@ fn main() {
     // block on nostr thread to finish.
     handle.join().unwrap();
 }
+
+/// Manage the names this relay serves as a NIP-05 provider.
+fn manage_names(settings: &config::Settings, action: NamesAction) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let (_, metrics) = create_metrics();
+        let repo = build_repo(settings, metrics).await;
+        match action {
+            NamesAction::List => {
+                for (name, pubkey) in repo.get_local_names().await? {
+                    println!("{name} {pubkey}");
+                }
+            }
+            NamesAction::Add { name, pubkey } => {
+                if !is_valid_local_name(&name) {
+                    return Err(Error::CustomError(format!("invalid name: {name}")));
+                }
+                if pubkey.len() != 64 || !is_lower_hex(&pubkey) {
+                    return Err(Error::CustomError(format!("invalid pubkey: {pubkey}")));
+                }
+                repo.set_local_name(&name, &pubkey).await?;
+                println!("registered {name}");
+            }
+            NamesAction::Remove { name } => {
+                if !repo.remove_local_name(&name).await? {
+                    return Err(Error::CustomError(format!("name not registered: {name}")));
+                }
+                println!("removed {name}");
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,671 @@
+//! Negentropy set reconciliation using NIP-77
+//!
+//! Clients (or peer relays) open a reconciliation session for a
+//! filter with `NEG-OPEN`, and exchange `NEG-MSG` messages until they
+//! know which events only one side has.  Each message is a hex-encoded
+//! list of ranges over the `(created_at, id)` ordered set of events,
+//! described by fingerprints or explicit id lists.  This implements
+//! the responder side of negentropy protocol version 1.
+use crate::subscription::ReqFilter;
+use bitcoin_hashes::{sha256, Hash};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Negentropy protocol version 1
+const PROTOCOL_VERSION: u8 = 0x61;
+
+/// Size of event ids
+const ID_SIZE: usize = 32;
+
+/// Size of range fingerprints
+const FINGERPRINT_SIZE: usize = 16;
+
+/// Number of sub-ranges a mismatched range is split into
+const BUCKETS: usize = 16;
+
+/// Approximate maximum size of a (binary) message; the remaining
+/// ranges are deferred to later rounds.
+const FRAME_SIZE_LIMIT: usize = 60_000;
+
+/// Maximum number of events held in the reconciliation sessions of
+/// one connection
+pub const MAX_ITEMS: usize = 50_000;
+
+/// Errors in negentropy messages
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NegentropyError {
+    #[error("invalid protocol version")]
+    InvalidVersion,
+    #[error("unexpected end of message")]
+    UnexpectedEnd,
+    #[error("invalid varint")]
+    InvalidVarint,
+    #[error("invalid bound")]
+    InvalidBound,
+    #[error("unexpected mode")]
+    UnexpectedMode,
+}
+
+/// `NEG-OPEN` message, starting a reconciliation session
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegOpen {
+    pub id: String,
+    pub filter: ReqFilter,
+    pub message: String,
+}
+
+/// `NEG-MSG` message, continuing a reconciliation session
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegMsg {
+    pub id: String,
+    pub message: String,
+}
+
+/// `NEG-CLOSE` message, ending a reconciliation session
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct NegClose {
+    pub id: String,
+}
+
+/// Parse a `[<cmd>, <subscription id>, ...]` array, returning the
+/// remaining elements.
+fn deserialize_neg<'de, D>(
+    deserializer: D,
+    cmd: &str,
+    len: usize,
+) -> Result<(String, Vec<Value>), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v: Vec<Value> = Deserialize::deserialize(deserializer)?;
+    if v.len() != len {
+        return Err(serde::de::Error::custom("wrong number of fields"));
+    }
+    let mut i = v.into_iter();
+    if i.next().unwrap().as_str() != Some(cmd) {
+        return Err(serde::de::Error::custom(format!("missing {cmd} command")));
+    }
+    let id = i
+        .next()
+        .unwrap()
+        .as_str()
+        .ok_or_else(|| serde::de::Error::custom("missing subscription id"))?
+        .to_owned();
+    Ok((id, i.collect()))
+}
+
+fn message_string<E: serde::de::Error>(v: &Value) -> Result<String, E> {
+    v.as_str()
+        .map(std::borrow::ToOwned::to_owned)
+        .ok_or_else(|| E::custom("missing negentropy message"))
+}
+
+impl<'de> Deserialize<'de> for NegOpen {
+    fn deserialize<D>(deserializer: D) -> Result<NegOpen, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (id, mut rest) = deserialize_neg(deserializer, "NEG-OPEN", 4)?;
+        let filter: ReqFilter = serde_json::from_value(rest[0].take())
+            .map_err(|_| serde::de::Error::custom("could not parse filter"))?;
+        let message = message_string(&rest[1])?;
+        Ok(NegOpen {
+            id,
+            filter,
+            message,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for NegMsg {
+    fn deserialize<D>(deserializer: D) -> Result<NegMsg, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (id, rest) = deserialize_neg(deserializer, "NEG-MSG", 3)?;
+        let message = message_string(&rest[0])?;
+        Ok(NegMsg { id, message })
+    }
+}
+
+impl<'de> Deserialize<'de> for NegClose {
+    fn deserialize<D>(deserializer: D) -> Result<NegClose, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (id, _) = deserialize_neg(deserializer, "NEG-CLOSE", 2)?;
+        Ok(NegClose { id })
+    }
+}
+
+/// An event in the reconciled set, ordered by timestamp and then id
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Item {
+    pub timestamp: u64,
+    pub id: [u8; ID_SIZE],
+}
+
+/// Range boundary; ids are zero-padded prefixes.
+#[derive(Clone, Copy, Debug, Default)]
+struct Bound {
+    item: Item,
+    id_len: usize,
+}
+
+impl Bound {
+    fn timestamp(timestamp: u64) -> Bound {
+        Bound {
+            item: Item {
+                timestamp,
+                id: [0; ID_SIZE],
+            },
+            id_len: 0,
+        }
+    }
+
+    fn item(item: Item) -> Bound {
+        Bound {
+            item,
+            id_len: ID_SIZE,
+        }
+    }
+}
+
+enum Mode {
+    Skip = 0,
+    Fingerprint = 1,
+    IdList = 2,
+}
+
+/// Read state for an incoming message
+struct Reader<'a> {
+    buf: &'a [u8],
+    last_timestamp: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], NegentropyError> {
+        if self.buf.len() < n {
+            return Err(NegentropyError::UnexpectedEnd);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> Result<u64, NegentropyError> {
+        let mut res: u64 = 0;
+        loop {
+            let b = self.bytes(1)?[0];
+            if res > u64::MAX >> 7 {
+                return Err(NegentropyError::InvalidVarint);
+            }
+            res = (res << 7) | u64::from(b & 0x7f);
+            if b & 0x80 == 0 {
+                return Ok(res);
+            }
+        }
+    }
+
+    fn timestamp(&mut self) -> Result<u64, NegentropyError> {
+        // timestamps are delta-encoded, with zero meaning infinity
+        let timestamp = match self.varint()? {
+            0 => u64::MAX,
+            t => t - 1,
+        };
+        if self.last_timestamp == u64::MAX || timestamp == u64::MAX {
+            self.last_timestamp = u64::MAX;
+        } else {
+            self.last_timestamp = self.last_timestamp.saturating_add(timestamp);
+        }
+        Ok(self.last_timestamp)
+    }
+
+    fn bound(&mut self) -> Result<Bound, NegentropyError> {
+        let timestamp = self.timestamp()?;
+        let id_len = usize::try_from(self.varint()?).map_err(|_| NegentropyError::InvalidBound)?;
+        if id_len > ID_SIZE {
+            return Err(NegentropyError::InvalidBound);
+        }
+        let mut bound = Bound::timestamp(timestamp);
+        bound.item.id[..id_len].copy_from_slice(self.bytes(id_len)?);
+        bound.id_len = id_len;
+        Ok(bound)
+    }
+}
+
+/// Write state for an outgoing message
+struct Writer {
+    last_timestamp: u64,
+}
+
+impl Writer {
+    fn timestamp(&mut self, out: &mut Vec<u8>, timestamp: u64) {
+        if timestamp == u64::MAX {
+            self.last_timestamp = u64::MAX;
+            encode_varint(out, 0);
+        } else {
+            let delta = timestamp - self.last_timestamp;
+            self.last_timestamp = timestamp;
+            encode_varint(out, delta + 1);
+        }
+    }
+
+    fn bound(&mut self, out: &mut Vec<u8>, bound: &Bound) {
+        self.timestamp(out, bound.item.timestamp);
+        encode_varint(out, bound.id_len as u64);
+        out.extend_from_slice(&bound.item.id[..bound.id_len]);
+    }
+}
+
+fn encode_varint(out: &mut Vec<u8>, mut n: u64) {
+    let mut bytes = vec![(n & 0x7f) as u8];
+    n >>= 7;
+    while n > 0 {
+        bytes.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+fn exceeded_frame_size(n: usize) -> bool {
+    n > FRAME_SIZE_LIMIT - 200
+}
+
+/// Count of events held by one connection's sessions, shared with
+/// the tasks loading new sessions, and limited to [`MAX_ITEMS`].
+#[derive(Debug, Clone, Default)]
+pub struct ItemLimit(Arc<AtomicUsize>);
+
+impl ItemLimit {
+    /// Start counting the events of a new session.
+    #[must_use]
+    pub fn permit(&self) -> ItemPermit {
+        ItemPermit {
+            limit: self.clone(),
+            count: 0,
+        }
+    }
+}
+
+/// Events counted against an [`ItemLimit`], until dropped.
+#[derive(Debug)]
+pub struct ItemPermit {
+    limit: ItemLimit,
+    count: usize,
+}
+
+impl ItemPermit {
+    /// Count another event, if the connection is below the limit.
+    pub fn add(&mut self) -> bool {
+        if self.limit.0.fetch_add(1, Ordering::Relaxed) >= MAX_ITEMS {
+            self.limit.0.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
+
+impl Drop for ItemPermit {
+    fn drop(&mut self) {
+        self.limit.0.fetch_sub(self.count, Ordering::Relaxed);
+    }
+}
+
+/// A sealed set of events that can be reconciled with a peer.
+#[derive(Debug, Clone)]
+pub struct Negentropy {
+    items: Vec<Item>,
+}
+
+impl Negentropy {
+    #[must_use]
+    pub fn new(mut items: Vec<Item>) -> Negentropy {
+        items.sort_unstable();
+        items.dedup();
+        Negentropy { items }
+    }
+
+    /// Respond to a message from the initiator of a session.
+    pub fn reconcile(&self, query: &[u8]) -> Result<Vec<u8>, NegentropyError> {
+        self.reconcile_aux(query, false, &mut vec![], &mut vec![])
+    }
+
+    /// Process a message, either as the initiator (collecting the ids
+    /// only we have, and the ids only the peer has), or as the
+    /// responder.
+    fn reconcile_aux(
+        &self,
+        query: &[u8],
+        initiator: bool,
+        have_ids: &mut Vec<[u8; ID_SIZE]>,
+        need_ids: &mut Vec<[u8; ID_SIZE]>,
+    ) -> Result<Vec<u8>, NegentropyError> {
+        let mut reader = Reader {
+            buf: query,
+            last_timestamp: 0,
+        };
+        let mut writer = Writer { last_timestamp: 0 };
+        let mut full_output = vec![PROTOCOL_VERSION];
+
+        let version = reader.bytes(1)?[0];
+        if !(0x60..=0x6f).contains(&version) || (initiator && version != PROTOCOL_VERSION) {
+            return Err(NegentropyError::InvalidVersion);
+        }
+        if version != PROTOCOL_VERSION {
+            // reply with the version we support
+            return Ok(full_output);
+        }
+
+        let mut prev_bound = Bound::default();
+        let mut prev_index = 0;
+        let mut skip = false;
+
+        while !reader.buf.is_empty() {
+            let mut o = vec![];
+            let curr_bound = reader.bound()?;
+            let mode = reader.varint()?;
+            let lower = prev_index;
+            let mut upper = self.lower_bound(prev_index, &curr_bound);
+
+            let do_skip = |o: &mut Vec<u8>, skip: &mut bool, writer: &mut Writer| {
+                if *skip {
+                    *skip = false;
+                    writer.bound(o, &prev_bound);
+                    encode_varint(o, Mode::Skip as u64);
+                }
+            };
+
+            if mode == Mode::Skip as u64 {
+                skip = true;
+            } else if mode == Mode::Fingerprint as u64 {
+                let theirs = reader.bytes(FINGERPRINT_SIZE)?;
+                if theirs == self.fingerprint(lower, upper) {
+                    skip = true;
+                } else {
+                    do_skip(&mut o, &mut skip, &mut writer);
+                    self.split_range(&mut o, &mut writer, lower, upper, &curr_bound);
+                }
+            } else if mode == Mode::IdList as u64 {
+                let num_ids = reader.varint()?;
+                let mut their_ids = HashSet::new();
+                for _ in 0..num_ids {
+                    let mut id = [0; ID_SIZE];
+                    id.copy_from_slice(reader.bytes(ID_SIZE)?);
+                    their_ids.insert(id);
+                }
+                for item in &self.items[lower..upper] {
+                    // remove ids both sides have
+                    if !their_ids.remove(&item.id) && initiator {
+                        have_ids.push(item.id);
+                    }
+                }
+                if initiator {
+                    skip = true;
+                    need_ids.extend(their_ids);
+                } else {
+                    do_skip(&mut o, &mut skip, &mut writer);
+                    let mut response_ids = vec![];
+                    let mut num_response_ids: u64 = 0;
+                    let mut end_bound = curr_bound;
+                    for (index, item) in self.items[lower..upper].iter().enumerate() {
+                        if exceeded_frame_size(full_output.len() + response_ids.len()) {
+                            end_bound = Bound::item(*item);
+                            upper = lower + index;
+                            break;
+                        }
+                        response_ids.extend_from_slice(&item.id);
+                        num_response_ids += 1;
+                    }
+                    writer.bound(&mut o, &end_bound);
+                    encode_varint(&mut o, Mode::IdList as u64);
+                    encode_varint(&mut o, num_response_ids);
+                    o.append(&mut response_ids);
+                    full_output.append(&mut o);
+                }
+            } else {
+                return Err(NegentropyError::UnexpectedMode);
+            }
+
+            if exceeded_frame_size(full_output.len() + o.len()) {
+                // defer the remaining ranges to a later round
+                let remaining = self.fingerprint(upper, self.items.len());
+                writer.bound(&mut full_output, &Bound::timestamp(u64::MAX));
+                encode_varint(&mut full_output, Mode::Fingerprint as u64);
+                full_output.extend_from_slice(&remaining);
+                break;
+            }
+            full_output.append(&mut o);
+            prev_index = upper;
+            prev_bound = curr_bound;
+        }
+        if initiator && full_output.len() == 1 {
+            return Ok(vec![]);
+        }
+        Ok(full_output)
+    }
+
+    /// Index of the first item at or after a bound
+    fn lower_bound(&self, first: usize, bound: &Bound) -> usize {
+        first + self.items[first..].partition_point(|item| *item < bound.item)
+    }
+
+    /// Fingerprint of a range: the hash of the sum of ids (as 256-bit
+    /// little-endian integers) and the number of ids.
+    fn fingerprint(&self, lower: usize, upper: usize) -> [u8; FINGERPRINT_SIZE] {
+        let mut sum = [0_u8; ID_SIZE];
+        for item in &self.items[lower..upper] {
+            let mut carry = 0_u16;
+            for (s, b) in sum.iter_mut().zip(item.id.iter()) {
+                let total = u16::from(*s) + u16::from(*b) + carry;
+                *s = total as u8;
+                carry = total >> 8;
+            }
+        }
+        let mut input = sum.to_vec();
+        encode_varint(&mut input, (upper - lower) as u64);
+        let digest = sha256::Hash::hash(&input);
+        let mut fingerprint = [0; FINGERPRINT_SIZE];
+        fingerprint.copy_from_slice(&digest[..FINGERPRINT_SIZE]);
+        fingerprint
+    }
+
+    /// Describe a mismatched range, as an id list if it is small, or
+    /// as fingerprints of sub-ranges.
+    fn split_range(
+        &self,
+        o: &mut Vec<u8>,
+        writer: &mut Writer,
+        lower: usize,
+        upper: usize,
+        upper_bound: &Bound,
+    ) {
+        let num_elems = upper - lower;
+        if num_elems < BUCKETS * 2 {
+            writer.bound(o, upper_bound);
+            encode_varint(o, Mode::IdList as u64);
+            encode_varint(o, num_elems as u64);
+            for item in &self.items[lower..upper] {
+                o.extend_from_slice(&item.id);
+            }
+            return;
+        }
+        let items_per_bucket = num_elems / BUCKETS;
+        let buckets_with_extra = num_elems % BUCKETS;
+        let mut curr = lower;
+        for i in 0..BUCKETS {
+            let bucket_size = items_per_bucket + usize::from(i < buckets_with_extra);
+            let fingerprint = self.fingerprint(curr, curr + bucket_size);
+            curr += bucket_size;
+            let next_bound = if curr == upper {
+                *upper_bound
+            } else {
+                minimal_bound(&self.items[curr - 1], &self.items[curr])
+            };
+            writer.bound(o, &next_bound);
+            encode_varint(o, Mode::Fingerprint as u64);
+            o.extend_from_slice(&fingerprint);
+        }
+    }
+
+    /// Start a session as the initiator.
+    #[cfg(test)]
+    fn initiate(&self) -> Vec<u8> {
+        let mut output = vec![PROTOCOL_VERSION];
+        let mut writer = Writer { last_timestamp: 0 };
+        let bound = Bound::timestamp(u64::MAX);
+        self.split_range(&mut output, &mut writer, 0, self.items.len(), &bound);
+        output
+    }
+}
+
+/// Smallest bound separating two adjacent items
+fn minimal_bound(prev: &Item, curr: &Item) -> Bound {
+    if curr.timestamp != prev.timestamp {
+        return Bound::timestamp(curr.timestamp);
+    }
+    let shared = prev
+        .id
+        .iter()
+        .zip(curr.id.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut bound = Bound::timestamp(curr.timestamp);
+    let id_len = (shared + 1).min(ID_SIZE);
+    bound.item.id[..id_len].copy_from_slice(&curr.id[..id_len]);
+    bound.id_len = id_len;
+    bound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_limited_per_connection() {
+        let limit = ItemLimit::default();
+        let mut first = limit.permit();
+        for _ in 0..MAX_ITEMS - 1 {
+            assert!(first.add());
+        }
+        let mut second = limit.permit();
+        assert!(second.add());
+        assert!(!second.add());
+        assert!(!first.add());
+        // closing a session makes room for others
+        drop(first);
+        assert!(second.add());
+        drop(second);
+        assert_eq!(limit.0.load(Ordering::Relaxed), 0);
+    }
+
+    fn item(timestamp: u64, n: u32) -> Item {
+        let digest = sha256::Hash::hash(&n.to_le_bytes());
+        let mut id = [0; ID_SIZE];
+        id.copy_from_slice(&digest[..]);
+        Item { timestamp, id }
+    }
+
+    /// Reconcile two sets, returning the ids only the initiator has,
+    /// the ids only the responder has, and the number of rounds.
+    fn sync(ours: Vec<Item>, theirs: Vec<Item>) -> (usize, usize, usize) {
+        let client = Negentropy::new(ours);
+        let relay = Negentropy::new(theirs);
+        let (mut have, mut need) = (vec![], vec![]);
+        let mut msg = client.initiate();
+        let mut rounds = 0;
+        while !msg.is_empty() {
+            rounds += 1;
+            let response = relay.reconcile(&msg).unwrap();
+            msg = client
+                .reconcile_aux(&response, true, &mut have, &mut need)
+                .unwrap();
+        }
+        (have.len(), need.len(), rounds)
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for n in [
+            0,
+            1,
+            127,
+            128,
+            16_383,
+            16_384,
+            u64::from(u32::MAX),
+            u64::MAX,
+        ] {
+            let mut out = vec![];
+            encode_varint(&mut out, n);
+            let mut reader = Reader {
+                buf: &out,
+                last_timestamp: 0,
+            };
+            assert_eq!(reader.varint(), Ok(n));
+            assert!(reader.buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn identical_sets() {
+        let items: Vec<Item> = (0..1000).map(|n| item(u64::from(n / 3), n)).collect();
+        assert_eq!(sync(items.clone(), items), (0, 0, 1));
+    }
+
+    #[test]
+    fn small_difference() {
+        let ours: Vec<Item> = (0..10).map(|n| item(100 + u64::from(n), n)).collect();
+        let theirs: Vec<Item> = (5..20).map(|n| item(100 + u64::from(n), n)).collect();
+        let (have, need, _) = sync(ours, theirs);
+        assert_eq!((have, need), (5, 10));
+    }
+
+    #[test]
+    fn large_difference() {
+        // many items share timestamps, and each side is missing some
+        let ours: Vec<Item> = (0..20_000)
+            .filter(|n| n % 7 != 0)
+            .map(|n| item(u64::from(n / 10), n))
+            .collect();
+        let theirs: Vec<Item> = (0..20_000)
+            .filter(|n| n % 11 != 0)
+            .map(|n| item(u64::from(n / 10), n))
+            .collect();
+        let only_ours = (0..20_000).filter(|n| n % 11 == 0 && n % 7 != 0).count();
+        let only_theirs = (0..20_000).filter(|n| n % 7 == 0 && n % 11 != 0).count();
+        let (have, need, rounds) = sync(ours, theirs);
+        assert_eq!((have, need), (only_ours, only_theirs));
+        assert!(rounds > 1);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let relay = Negentropy::new(vec![]);
+        assert_eq!(relay.reconcile(&[0x62]), Ok(vec![PROTOCOL_VERSION]));
+        assert_eq!(
+            relay.reconcile(&[0x10]),
+            Err(NegentropyError::InvalidVersion)
+        );
+        assert_eq!(relay.reconcile(&[]), Err(NegentropyError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn parse_messages() {
+        let open: NegOpen =
+            serde_json::from_str(r#"["NEG-OPEN", "sub", {"kinds": [1]}, "6100"]"#).unwrap();
+        assert_eq!(open.id, "sub");
+        assert_eq!(open.filter.kinds, Some(vec![1]));
+        assert_eq!(open.message, "6100");
+        let msg: NegMsg = serde_json::from_str(r#"["NEG-MSG", "sub", "61"]"#).unwrap();
+        assert_eq!(msg.message, "61");
+        let close: NegClose = serde_json::from_str(r#"["NEG-CLOSE", "sub"]"#).unwrap();
+        assert_eq!(close.id, "sub");
+        assert!(serde_json::from_str::<NegClose>(r#"["CLOSE", "sub"]"#).is_err());
+    }
+}
@@ -4,14 +4,19 @@
 //! address with their public key, in metadata events.  This module
 //! consumes a stream of metadata events, and keeps a database table
 //! updated with the current NIP-05 verification status.
-use crate::config::VerifiedUsers;
+//!
+//! The relay can also act as a NIP-05 provider for its own domain,
+//! serving names registered with it at `/.well-known/nostr.json`.
+use crate::config::{Settings, VerifiedUsers};
 use crate::error::{Error, Result};
-use crate::event::Event;
+use crate::event::{BroadcastEvent, Event};
 use crate::repo::NostrRepo;
 use hyper::body::HttpBody;
 use hyper::client::connect::HttpConnector;
-use hyper::Client;
+use hyper::{Body, Client, Response, StatusCode};
 use hyper_tls::HttpsConnector;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
@@ -26,7 +31,7 @@ pub struct Verifier {
     /// Metadata events for us to inspect
     metadata_rx: tokio::sync::broadcast::Receiver<Event>,
     /// Newly validated events get written and then broadcast on this channel to subscribers
-    event_tx: tokio::sync::broadcast::Sender<Event>,
+    event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
     /// Settings
     settings: crate::config::Settings,
     /// HTTP client
@@ -128,7 +133,7 @@ impl Verifier {
     pub fn new(
         repo: Arc<dyn NostrRepo>,
         metadata_rx: tokio::sync::broadcast::Receiver<Event>,
-        event_tx: tokio::sync::broadcast::Sender<Event>,
+        event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
         settings: crate::config::Settings,
     ) -> Result<Self> {
         info!("creating NIP-05 verifier");
@@ -421,7 +426,7 @@ impl Verifier {
                             event.get_event_id_prefix(),
                             start.elapsed()
                         );
-                        self.event_tx.send(event.clone()).ok();
+                        self.event_tx.send(BroadcastEvent::from(event.clone())).ok();
                     }
                 }
                 Err(err) => {
@@ -544,6 +549,72 @@ impl std::fmt::Display for VerificationRecord {
     }
 }
 
+/// Is this a valid name to register with this relay?
+///
+/// Local parts are restricted to lowercase `a-z0-9-_.`, so lookups
+/// are case-insensitive.
+#[must_use]
+pub fn is_valid_local_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c))
+}
+
+/// Build a `nostr.json` document for the given names and relays.
+fn local_names_json(names: &BTreeMap<String, String>, relays: &[String]) -> Value {
+    let mut doc = json!({ "names": names });
+    if !relays.is_empty() && !names.is_empty() {
+        let relay_map: BTreeMap<&String, &[String]> =
+            names.values().map(|pubkey| (pubkey, relays)).collect();
+        doc["relays"] = json!(relay_map);
+    }
+    doc
+}
+
+/// The valid local name requested in a `nostr.json` query string.
+fn requested_local_name(query: &str) -> Option<String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "name")
+        .map(|(_, v)| v.to_lowercase())
+        .filter(|n| is_valid_local_name(n))
+}
+
+/// Serve a `/.well-known/nostr.json?name=<name>` request from the
+/// names registered with this relay.
+pub async fn handle_nostr_json(
+    repo: Arc<dyn NostrRepo>,
+    settings: &Settings,
+    query: Option<&str>,
+) -> Response<Body> {
+    let name = query.and_then(requested_local_name);
+    let mut names = BTreeMap::new();
+    if let Some(name) = name {
+        match repo.get_local_name(&name).await {
+            Ok(Some(pubkey)) => {
+                names.insert(name, pubkey);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("could not look up local name: {:?}", e);
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from(""))
+                    .unwrap();
+            }
+        }
+    }
+    let doc = local_names_json(&names, &settings.local_names.relays);
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(doc.to_string()))
+        .unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -612,4 +683,44 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn local_names() {
+        assert!(is_valid_local_name("bob"));
+        assert!(is_valid_local_name("_"));
+        assert!(is_valid_local_name("bob.smith-1"));
+        assert!(!is_valid_local_name(""));
+        assert!(!is_valid_local_name("Bob"));
+        assert!(!is_valid_local_name("bob@example.com"));
+    }
+
+    #[test]
+    fn local_name_query() {
+        assert_eq!(requested_local_name("name=Bob"), Some("bob".to_owned()));
+        assert_eq!(
+            requested_local_name("x=1&name=bob%2Esmith"),
+            Some("bob.smith".to_owned())
+        );
+        assert_eq!(requested_local_name("name=bob%40example.com"), None);
+        assert_eq!(requested_local_name("name="), None);
+        assert_eq!(requested_local_name("names=bob"), None);
+    }
+
+    #[test]
+    fn nostr_json() {
+        let mut names = BTreeMap::new();
+        names.insert("bob".to_owned(), "abcd".to_owned());
+        assert_eq!(
+            local_names_json(&names, &[]),
+            json!({"names": {"bob": "abcd"}})
+        );
+        let relays = vec!["wss://relay.example.com".to_owned()];
+        assert_eq!(
+            local_names_json(&names, &relays),
+            json!({
+                "names": {"bob": "abcd"},
+                "relays": {"abcd": ["wss://relay.example.com"]}
+            })
+        );
+    }
 }
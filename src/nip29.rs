@@ -0,0 +1,692 @@
+//! Relay-based groups using NIP-29
+//!
+//! Group messages carry an `h` tag naming the group.  The relay keeps
+//! the state of each group (metadata, members and their roles, and
+//! invite codes), applies moderation events from group admins, and
+//! publishes the state as relay-signed events (kinds 39000-39003).
+use crate::config::Settings;
+use crate::error::Result;
+use crate::event::{BroadcastEvent, Event};
+use crate::notice::EventResultStatus;
+use crate::repo::NostrRepo;
+use crate::subscription::Subscription;
+use crate::utils::{is_lower_hex, unix_time};
+use bitcoin_hashes::{sha256, Hash};
+use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use tracing::{info, warn};
+
+/// Tag naming the group of an event
+pub const GROUP_TAG: &str = "h";
+
+/// Add a user to a group, or change their roles
+pub const KIND_PUT_USER: u64 = 9000;
+/// Remove a user from a group
+pub const KIND_REMOVE_USER: u64 = 9001;
+/// Edit the group metadata
+pub const KIND_EDIT_METADATA: u64 = 9002;
+/// Delete an event from a group
+pub const KIND_DELETE_EVENT: u64 = 9005;
+/// Create a new group
+pub const KIND_CREATE_GROUP: u64 = 9007;
+/// Delete a group
+pub const KIND_DELETE_GROUP: u64 = 9008;
+/// Create an invite code for a closed group
+pub const KIND_CREATE_INVITE: u64 = 9009;
+/// Request to join a group
+pub const KIND_JOIN_REQUEST: u64 = 9021;
+/// Request to leave a group
+pub const KIND_LEAVE_REQUEST: u64 = 9022;
+/// Relay-signed group metadata
+pub const KIND_GROUP_METADATA: u64 = 39000;
+/// Relay-signed group admin list
+pub const KIND_GROUP_ADMINS: u64 = 39001;
+/// Relay-signed group member list
+pub const KIND_GROUP_MEMBERS: u64 = 39002;
+/// Relay-signed group role list
+pub const KIND_GROUP_ROLES: u64 = 39003;
+
+/// Role given to group creators, allowing every moderation action
+pub const ADMIN_ROLE: &str = "admin";
+
+/// State of a single group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Group {
+    pub id: String,
+    pub name: Option<String>,
+    pub about: Option<String>,
+    pub picture: Option<String>,
+    /// Only members may read group messages
+    pub private: bool,
+    /// Join requests need an invite code
+    pub closed: bool,
+    /// Members, with their (possibly empty) roles
+    pub members: BTreeMap<String, Vec<String>>,
+    /// Invite codes for joining a closed group
+    pub invites: BTreeSet<String>,
+}
+
+impl Group {
+    fn new(id: &str) -> Group {
+        Group {
+            id: id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[must_use]
+    pub fn is_member(&self, pubkey: &str) -> bool {
+        self.members.contains_key(pubkey)
+    }
+
+    /// Admins are members with any role.
+    #[must_use]
+    pub fn is_admin(&self, pubkey: &str) -> bool {
+        self.members.get(pubkey).is_some_and(|r| !r.is_empty())
+    }
+}
+
+/// A change to the groups caused by an accepted event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupChange {
+    /// A message (or pending join request) that changes nothing
+    Message,
+    /// A group was created, or its state changed
+    Update(Group),
+    /// A group was deleted
+    Delete(String),
+    /// Events were deleted from a group by an admin
+    DeleteEvents(String, Vec<String>),
+}
+
+/// State of all groups hosted by the relay
+#[derive(Debug, Clone, Default)]
+pub struct GroupState {
+    pub groups: BTreeMap<String, Group>,
+}
+
+/// The group named by an event's `h` tag, if any
+#[must_use]
+pub fn group_id(event: &Event) -> Option<String> {
+    event.tag_values_by_name(GROUP_TAG).into_iter().next()
+}
+
+/// Values of `p` tags that are valid pubkeys, with any extra elements
+fn tagged_pubkeys(event: &Event) -> Vec<(String, Vec<String>)> {
+    event
+        .tags
+        .iter()
+        .filter(|t| t.len() > 1 && t[0] == "p" && t[1].len() == 64 && is_lower_hex(&t[1]))
+        .map(|t| (t[1].clone(), t[2..].to_vec()))
+        .collect()
+}
+
+impl GroupState {
+    #[must_use]
+    pub fn has_private_groups(&self) -> bool {
+        self.groups.values().any(|g| g.private)
+    }
+
+    /// Check if an event may be read by a client authenticated as the
+    /// given pubkey.  Messages of private groups are only readable by
+    /// members.
+    #[must_use]
+    pub fn can_read(&self, event: &Event, reader: Option<&String>) -> bool {
+        match group_id(event).and_then(|id| self.groups.get(&id)) {
+            Some(group) if group.private => reader.is_some_and(|r| group.is_member(r)),
+            _ => true,
+        }
+    }
+
+    /// Check if a serialized event may be read.  Events are only
+    /// parsed if they might belong to a private group.
+    #[must_use]
+    pub fn can_read_serialized(&self, event: &str, reader: Option<&String>) -> bool {
+        if !self.has_private_groups() || !event.contains(r#"["h","#) {
+            return true;
+        }
+        serde_json::from_str::<Event>(event).map_or(true, |e| self.can_read(&e, reader))
+    }
+
+    /// Private groups whose messages the reader may not read.
+    #[must_use]
+    pub fn hidden_groups(&self, reader: Option<&String>) -> Vec<String> {
+        self.groups
+            .values()
+            .filter(|g| g.private && !reader.is_some_and(|r| g.is_member(r)))
+            .map(|g| g.id.clone())
+            .collect()
+    }
+
+    /// Check if a subscription explicitly requests messages from a
+    /// private group the reader is not a member of.
+    #[must_use]
+    pub fn requests_private_groups(&self, sub: &Subscription, reader: Option<&String>) -> bool {
+        sub.filters
+            .iter()
+            .flat_map(|f| [&f.tags, &f.all_tags])
+            .filter_map(|t| t.as_ref().and_then(|t| t.get(GROUP_TAG)))
+            .flatten()
+            .filter_map(|id| self.groups.get(id))
+            .any(|g| g.private && !reader.is_some_and(|r| g.is_member(r)))
+    }
+
+    /// Reason to refuse a subscription, count or reconciliation that
+    /// requests private groups the reader is not a member of.
+    #[must_use]
+    pub fn private_group_refusal(
+        &self,
+        sub: &Subscription,
+        reader: Option<&String>,
+    ) -> Option<(EventResultStatus, &'static str)> {
+        if !self.requests_private_groups(sub, reader) {
+            None
+        } else if reader.is_none() {
+            Some((
+                EventResultStatus::AuthRequired,
+                "private groups require authentication",
+            ))
+        } else {
+            Some((
+                EventResultStatus::Restricted,
+                "private groups are only readable by members",
+            ))
+        }
+    }
+
+    /// Decide whether an event is accepted, and how it changes the
+    /// groups.  Returns `Ok(None)` for events outside any group, and
+    /// an error message for rejected events.
+    pub fn apply(&self, event: &Event) -> Result<Option<GroupChange>, &'static str> {
+        if (KIND_GROUP_METADATA..=KIND_GROUP_ROLES).contains(&event.kind) {
+            return Err("group state events are published by the relay");
+        }
+        let is_group_kind = (KIND_PUT_USER..=KIND_LEAVE_REQUEST).contains(&event.kind);
+        let Some(id) = group_id(event) else {
+            if is_group_kind {
+                return Err("missing group id");
+            }
+            return Ok(None);
+        };
+        let author = &event.pubkey;
+        if event.kind == KIND_CREATE_GROUP {
+            if self.groups.contains_key(&id) {
+                return Err("group already exists");
+            }
+            let mut group = Group::new(&id);
+            group
+                .members
+                .insert(author.clone(), vec![ADMIN_ROLE.to_owned()]);
+            return Ok(Some(GroupChange::Update(group)));
+        }
+        let Some(group) = self.groups.get(&id) else {
+            return Err("group not found");
+        };
+        let mut group = group.clone();
+        let change = match event.kind {
+            KIND_JOIN_REQUEST => {
+                if group.is_member(author) {
+                    return Err("already a member of this group");
+                }
+                let code = event.tag_values_by_name("code").into_iter().next();
+                if group.closed && !code.is_some_and(|c| group.invites.contains(&c)) {
+                    // left for an admin to approve
+                    GroupChange::Message
+                } else {
+                    group.members.insert(author.clone(), vec![]);
+                    GroupChange::Update(group)
+                }
+            }
+            KIND_LEAVE_REQUEST => {
+                if group.members.remove(author).is_none() {
+                    return Err("not a member of this group");
+                }
+                GroupChange::Update(group)
+            }
+            KIND_PUT_USER..=9020 => {
+                if !group.is_admin(author) {
+                    return Err("only group admins may moderate the group");
+                }
+                match event.kind {
+                    KIND_PUT_USER => {
+                        for (pubkey, roles) in tagged_pubkeys(event) {
+                            group.members.insert(pubkey, roles);
+                        }
+                        GroupChange::Update(group)
+                    }
+                    KIND_REMOVE_USER => {
+                        for (pubkey, _) in tagged_pubkeys(event) {
+                            group.members.remove(&pubkey);
+                        }
+                        GroupChange::Update(group)
+                    }
+                    KIND_EDIT_METADATA => {
+                        for tag in event.tags.iter().filter(|t| !t.is_empty()) {
+                            match (tag[0].as_str(), tag.get(1)) {
+                                ("name", Some(v)) => group.name = Some(v.clone()),
+                                ("about", Some(v)) => group.about = Some(v.clone()),
+                                ("picture", Some(v)) => group.picture = Some(v.clone()),
+                                ("private", _) => group.private = true,
+                                ("public", _) => group.private = false,
+                                ("closed", _) => group.closed = true,
+                                ("open", _) => group.closed = false,
+                                _ => {}
+                            }
+                        }
+                        GroupChange::Update(group)
+                    }
+                    KIND_DELETE_EVENT => GroupChange::DeleteEvents(
+                        id,
+                        event
+                            .tag_values_by_name("e")
+                            .into_iter()
+                            .filter(|id| id.len() == 64 && is_lower_hex(id))
+                            .collect(),
+                    ),
+                    KIND_DELETE_GROUP => GroupChange::Delete(id),
+                    KIND_CREATE_INVITE => {
+                        let Some(code) = event.tag_values_by_name("code").into_iter().next() else {
+                            return Err("missing invite code");
+                        };
+                        group.invites.insert(code);
+                        GroupChange::Update(group)
+                    }
+                    _ => return Err("unsupported moderation event"),
+                }
+            }
+            _ => {
+                if !group.is_member(author) {
+                    return Err("only group members may publish to the group");
+                }
+                GroupChange::Message
+            }
+        };
+        Ok(Some(change))
+    }
+}
+
+/// Signs group state events with the relay key
+pub struct RelaySigner {
+    keypair: KeyPair,
+    pubkey: String,
+    /// Timestamp of the most recently signed event, so that newer
+    /// state always replaces older state.
+    last_created_at: u64,
+}
+
+impl RelaySigner {
+    /// Signer for the configured relay key, if groups are enabled.
+    #[must_use]
+    pub fn from_settings(settings: &Settings) -> Option<RelaySigner> {
+        if !settings.groups.enabled {
+            return None;
+        }
+        let secp = Secp256k1::new();
+        let keypair = settings
+            .groups
+            .relay_secret_key
+            .as_ref()
+            .and_then(|k| KeyPair::from_seckey_str(&secp, k).ok());
+        let Some(keypair) = keypair else {
+            warn!("groups require a valid relay_secret_key, groups are disabled");
+            return None;
+        };
+        if !settings.authorization.nip42_auth {
+            warn!("groups require nip42_auth to be enabled");
+        }
+        let pubkey = XOnlyPublicKey::from_keypair(&keypair).to_string();
+        info!("hosting groups with relay pubkey {}", pubkey);
+        Some(RelaySigner {
+            keypair,
+            pubkey,
+            last_created_at: 0,
+        })
+    }
+
+    #[must_use]
+    pub fn pubkey(&self) -> &str {
+        &self.pubkey
+    }
+
+    /// Timestamp for newly signed events
+    fn next_created_at(&mut self) -> u64 {
+        self.last_created_at = unix_time().max(self.last_created_at + 1);
+        self.last_created_at
+    }
+
+    /// Create a signed event
+    fn sign(&self, kind: u64, tags: Vec<Vec<String>>, created_at: u64) -> Event {
+        let mut event = Event {
+            id: String::new(),
+            pubkey: self.pubkey.clone(),
+            delegated_by: None,
+            created_at,
+            kind,
+            tags,
+            content: String::new(),
+            sig: String::new(),
+            tagidx: None,
+        };
+        // canonical serialization of the fields above can not fail
+        let digest = sha256::Hash::hash(event.to_canonical().unwrap_or_default().as_bytes());
+        event.id = format!("{digest:x}");
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).expect("digest is 32 bytes");
+        event.sig = Secp256k1::signing_only()
+            .sign_schnorr(&msg, &self.keypair)
+            .to_string();
+        event.build_index(&[]);
+        event
+    }
+
+    /// Relay-signed events describing the state of a group
+    pub fn group_events(&mut self, group: &Group) -> Vec<Event> {
+        let d_tag = vec!["d".to_owned(), group.id.clone()];
+        let mut metadata = vec![d_tag.clone()];
+        for (name, value) in [
+            ("name", &group.name),
+            ("about", &group.about),
+            ("picture", &group.picture),
+        ] {
+            if let Some(v) = value {
+                metadata.push(vec![name.to_owned(), v.clone()]);
+            }
+        }
+        metadata.push(vec![
+            if group.private { "private" } else { "public" }.to_owned()
+        ]);
+        metadata.push(vec![if group.closed { "closed" } else { "open" }.to_owned()]);
+        let member_tag = |(pubkey, roles): (&String, &Vec<String>)| {
+            let mut tag = vec!["p".to_owned(), pubkey.clone()];
+            tag.extend(roles.iter().cloned());
+            tag
+        };
+        let mut admins = vec![d_tag.clone()];
+        admins.extend(
+            group
+                .members
+                .iter()
+                .filter(|(_, roles)| !roles.is_empty())
+                .map(member_tag),
+        );
+        let mut members = vec![d_tag.clone()];
+        members.extend(
+            group
+                .members
+                .keys()
+                .map(|pubkey| vec!["p".to_owned(), pubkey.clone()]),
+        );
+        let created_at = self.next_created_at();
+        let roles = vec![
+            d_tag,
+            vec![
+                "role".to_owned(),
+                ADMIN_ROLE.to_owned(),
+                "may moderate the group".to_owned(),
+            ],
+        ];
+        vec![
+            self.sign(KIND_GROUP_METADATA, metadata, created_at),
+            self.sign(KIND_GROUP_ADMINS, admins, created_at),
+            self.sign(KIND_GROUP_MEMBERS, members, created_at),
+            self.sign(KIND_GROUP_ROLES, roles, created_at),
+        ]
+    }
+
+    /// Relay-signed deletion of the state events of a group
+    pub fn group_deletion(&mut self, group_id: &str) -> Event {
+        let tags = (KIND_GROUP_METADATA..=KIND_GROUP_ROLES)
+            .map(|kind| vec!["a".to_owned(), format!("{kind}:{}:{group_id}", self.pubkey)])
+            .collect();
+        let created_at = self.next_created_at();
+        self.sign(5, tags, created_at)
+    }
+}
+
+/// Persist a change to the groups, and publish the new group state.
+pub async fn apply_change(
+    repo: &Arc<dyn NostrRepo>,
+    change: GroupChange,
+    signer: &mut RelaySigner,
+    groups: &watch::Sender<GroupState>,
+    bcast_tx: &broadcast::Sender<BroadcastEvent>,
+) -> Result<()> {
+    let events = match change {
+        GroupChange::Message => return Ok(()),
+        GroupChange::Update(group) => {
+            repo.save_group(&group).await?;
+            signer.group_events(&group)
+        }
+        GroupChange::Delete(id) => {
+            repo.delete_group(&id).await?;
+            info!("deleted group {:?}", id);
+            vec![signer.group_deletion(&id)]
+        }
+        GroupChange::DeleteEvents(id, ids) => {
+            let count = repo.delete_group_events(&id, &ids).await?;
+            info!("deleted {} events from group {:?}", count, id);
+            return Ok(());
+        }
+    };
+    groups.send_replace(load_groups(repo).await);
+    for event in events {
+        if repo.write_event(&event).await? > 0 {
+            bcast_tx.send(BroadcastEvent::from(event)).ok();
+        }
+    }
+    Ok(())
+}
+
+/// Load the state of all groups from the database.
+pub async fn load_groups(repo: &Arc<dyn NostrRepo>) -> GroupState {
+    match repo.get_groups().await {
+        Ok(g) => g,
+        Err(e) => {
+            warn!("could not load groups: {:?}", e);
+            GroupState::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::negentropy::NegOpen;
+    use crate::subscription::Count;
+
+    const ADMIN: &str = "a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1";
+    const MEMBER: &str = "b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2";
+    const OTHER: &str = "c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3";
+
+    fn event(author: &str, kind: u64, tags: Vec<Vec<&str>>) -> Event {
+        let mut event = Event::simple_event();
+        event.pubkey = author.to_owned();
+        event.kind = kind;
+        event.tags = tags
+            .into_iter()
+            .map(|t| t.into_iter().map(str::to_owned).collect())
+            .collect();
+        event
+    }
+
+    fn state() -> GroupState {
+        let mut group = Group::new("g");
+        group
+            .members
+            .insert(ADMIN.to_owned(), vec![ADMIN_ROLE.to_owned()]);
+        group.members.insert(MEMBER.to_owned(), vec![]);
+        let mut state = GroupState::default();
+        state.groups.insert("g".to_owned(), group);
+        state
+    }
+
+    fn updated(change: Result<Option<GroupChange>, &'static str>) -> Group {
+        match change {
+            Ok(Some(GroupChange::Update(g))) => g,
+            x => panic!("expected an update: {x:?}"),
+        }
+    }
+
+    #[test]
+    fn create_group() {
+        let state = state();
+        let group = updated(state.apply(&event(OTHER, KIND_CREATE_GROUP, vec![vec!["h", "new"]])));
+        assert!(group.is_admin(OTHER));
+        assert_eq!(
+            state.apply(&event(OTHER, KIND_CREATE_GROUP, vec![vec!["h", "g"]])),
+            Err("group already exists")
+        );
+    }
+
+    #[test]
+    fn membership_enforced_for_writes() {
+        let state = state();
+        let msg = |author| event(author, 9, vec![vec!["h", "g"]]);
+        assert_eq!(state.apply(&msg(MEMBER)), Ok(Some(GroupChange::Message)));
+        assert!(state.apply(&msg(OTHER)).is_err());
+        assert_eq!(
+            state.apply(&event(OTHER, 9, vec![vec!["h", "none"]])),
+            Err("group not found")
+        );
+        // events outside groups are not affected
+        assert_eq!(state.apply(&event(OTHER, 1, vec![])), Ok(None));
+        // group state can only be published by the relay
+        assert!(state
+            .apply(&event(ADMIN, KIND_GROUP_METADATA, vec![vec!["d", "g"]]))
+            .is_err());
+    }
+
+    #[test]
+    fn moderation_by_admins() {
+        let state = state();
+        let put = event(
+            ADMIN,
+            KIND_PUT_USER,
+            vec![vec!["h", "g"], vec!["p", OTHER, "moderator"]],
+        );
+        assert!(updated(state.apply(&put)).is_admin(OTHER));
+        let remove = event(
+            ADMIN,
+            KIND_REMOVE_USER,
+            vec![vec!["h", "g"], vec!["p", MEMBER]],
+        );
+        assert!(!updated(state.apply(&remove)).is_member(MEMBER));
+        let edit = event(
+            ADMIN,
+            KIND_EDIT_METADATA,
+            vec![vec!["h", "g"], vec!["name", "Group"], vec!["private"]],
+        );
+        let group = updated(state.apply(&edit));
+        assert_eq!(group.name, Some("Group".to_owned()));
+        assert!(group.private);
+        // members without roles can not moderate
+        let put = event(
+            MEMBER,
+            KIND_PUT_USER,
+            vec![vec!["h", "g"], vec!["p", OTHER]],
+        );
+        assert!(state.apply(&put).is_err());
+        let delete = event(ADMIN, KIND_DELETE_GROUP, vec![vec!["h", "g"]]);
+        assert_eq!(
+            state.apply(&delete),
+            Ok(Some(GroupChange::Delete("g".to_owned())))
+        );
+    }
+
+    #[test]
+    fn join_and_leave() {
+        let mut state = state();
+        let join = |code: Option<&str>| {
+            let mut tags = vec![vec!["h", "g"]];
+            tags.extend(code.map(|c| vec!["code", c]));
+            event(OTHER, KIND_JOIN_REQUEST, tags)
+        };
+        assert!(updated(state.apply(&join(None))).is_member(OTHER));
+        let group = state.groups.get_mut("g").unwrap();
+        group.closed = true;
+        group.invites.insert("secret".to_owned());
+        // closed groups need an invite, or approval by an admin
+        assert_eq!(state.apply(&join(None)), Ok(Some(GroupChange::Message)));
+        assert!(updated(state.apply(&join(Some("secret")))).is_member(OTHER));
+        let leave = event(MEMBER, KIND_LEAVE_REQUEST, vec![vec!["h", "g"]]);
+        assert!(!updated(state.apply(&leave)).is_member(MEMBER));
+    }
+
+    #[test]
+    fn private_groups_readable_by_members() {
+        let mut state = state();
+        let msg = event(MEMBER, 9, vec![vec!["h", "g"]]);
+        assert!(state.can_read(&msg, None));
+        state.groups.get_mut("g").unwrap().private = true;
+        assert!(!state.can_read(&msg, None));
+        assert!(!state.can_read(&msg, Some(&OTHER.to_owned())));
+        assert!(state.can_read(&msg, Some(&MEMBER.to_owned())));
+        assert_eq!(state.hidden_groups(Some(&OTHER.to_owned())), vec!["g"]);
+        assert!(state.hidden_groups(Some(&MEMBER.to_owned())).is_empty());
+        let sub: Subscription = serde_json::from_str(r##"["REQ","s",{"#h":["g"]}]"##).unwrap();
+        assert!(state.requests_private_groups(&sub, None));
+        assert!(!state.requests_private_groups(&sub, Some(&MEMBER.to_owned())));
+        // groups named by an `&h` filter are checked too
+        let sub: Subscription = serde_json::from_str(r##"["REQ","s",{"&h":["g"]}]"##).unwrap();
+        assert!(state.requests_private_groups(&sub, None));
+        assert!(state.requests_private_groups(&sub, Some(&OTHER.to_owned())));
+        assert!(!state.requests_private_groups(&sub, Some(&MEMBER.to_owned())));
+    }
+
+    #[test]
+    fn private_groups_not_counted_or_reconciled() {
+        let mut state = state();
+        state.groups.get_mut("g").unwrap().private = true;
+        let count: Count = serde_json::from_str(r##"["COUNT","c",{"#h":["g"]}]"##).unwrap();
+        let count = Subscription::from(count);
+        let open: NegOpen =
+            serde_json::from_str(r##"["NEG-OPEN","n",{"#h":["g"]},"6100"]"##).unwrap();
+        let neg = Subscription {
+            id: open.id,
+            filters: vec![open.filter],
+        };
+        for sub in [&count, &neg] {
+            assert_eq!(
+                state.private_group_refusal(sub, None),
+                Some((
+                    EventResultStatus::AuthRequired,
+                    "private groups require authentication"
+                ))
+            );
+            assert_eq!(
+                state.private_group_refusal(sub, Some(&OTHER.to_owned())),
+                Some((
+                    EventResultStatus::Restricted,
+                    "private groups are only readable by members"
+                ))
+            );
+            assert_eq!(
+                state.private_group_refusal(sub, Some(&MEMBER.to_owned())),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn signed_group_events() {
+        let mut settings = Settings::default();
+        settings.groups.enabled = true;
+        settings.groups.relay_secret_key =
+            Some("0000000000000000000000000000000000000000000000000000000000000001".to_owned());
+        let mut signer = RelaySigner::from_settings(&settings).unwrap();
+        let events = signer.group_events(&state().groups["g"]);
+        assert_eq!(events.len(), 4);
+        for e in &events {
+            assert!(e.validate().is_ok());
+            assert_eq!(e.distinct_param(), Some("g".to_owned()));
+        }
+        // later state always replaces earlier state
+        let later = signer.group_events(&state().groups["g"]);
+        assert!(later[0].created_at > events[0].created_at);
+        assert_eq!(events[1].tag_values_by_name("p"), vec![ADMIN.to_owned()]);
+        assert_eq!(events[2].tag_values_by_name("p").len(), 2);
+        let deletion = signer.group_deletion("g");
+        assert_eq!(deletion.deleted_addresses().len(), 4);
+    }
+}
@@ -0,0 +1,556 @@
+//! Relay management API using NIP-86
+//!
+//! NIP-86 defines a JSON-RPC-like API, served over HTTP with the
+//! `application/nostr+json+rpc` content type, that relay operators
+//! can use to moderate the relay without editing configuration.
+//! Requests are authenticated with NIP-98 HTTP Auth events.  The
+//! resulting moderation state is persisted, and shared with the
+//! database writer and client connections through a watch channel.
+use crate::config::Settings;
+use crate::error::Error;
+use crate::event::Event;
+use crate::info::RelayInfo;
+use crate::nip05;
+use crate::nip98;
+use crate::repo::NostrRepo;
+use crate::utils::is_lower_hex;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Content type of management API requests and responses
+pub const RPC_CONTENT_TYPE: &str = "application/nostr+json+rpc";
+
+/// Largest management API request body accepted
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+/// Methods supported by this relay
+const SUPPORTED_METHODS: [&str; 26] = [
+    "supportedmethods",
+    "banpubkey",
+    "allowpubkey",
+    "unbanpubkey",
+    "unallowpubkey",
+    "listbannedpubkeys",
+    "listallowedpubkeys",
+    "banevent",
+    "allowevent",
+    "unbanevent",
+    "listbannedevents",
+    "allowkind",
+    "disallowkind",
+    "unallowkind",
+    "undisallowkind",
+    "listallowedkinds",
+    "listdisallowedkinds",
+    "blockip",
+    "unblockip",
+    "listblockedips",
+    "changerelayname",
+    "changerelaydescription",
+    "changerelayicon",
+    "setname",
+    "removename",
+    "listnames",
+];
+
+/// Moderation state, keyed by pubkey/event id/IP with optional reasons.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Moderation {
+    pub banned_pubkeys: BTreeMap<String, Option<String>>,
+    pub allowed_pubkeys: BTreeMap<String, Option<String>>,
+    pub banned_events: BTreeMap<String, Option<String>>,
+    pub allowed_kinds: BTreeSet<u64>,
+    pub disallowed_kinds: BTreeSet<u64>,
+    pub blocked_ips: BTreeMap<String, Option<String>>,
+    pub relay_name: Option<String>,
+    pub relay_description: Option<String>,
+    pub relay_icon: Option<String>,
+}
+
+impl Moderation {
+    /// Reason an event may not be published, if any.
+    ///
+    /// While any pubkey (or kind) is allowed, only allowed pubkeys
+    /// (or kinds) are accepted.  Removing the last allowed entry
+    /// accepts all of them again.  Authors on the configured pubkey
+    /// whitelist (`whitelisted`) are accepted as if they were allowed.
+    #[must_use]
+    pub fn event_rejection(&self, event: &Event, whitelisted: bool) -> Option<&'static str> {
+        let authors = std::iter::once(&event.pubkey).chain(event.delegated_by.as_ref());
+        if self.banned_events.contains_key(&event.id) {
+            Some("event is banned from this relay")
+        } else if authors.clone().any(|a| self.banned_pubkeys.contains_key(a)) {
+            Some("pubkey is banned from this relay")
+        } else if !whitelisted
+            && !self.allowed_pubkeys.is_empty()
+            && !authors
+                .clone()
+                .any(|a| self.allowed_pubkeys.contains_key(a))
+        {
+            Some("pubkey is not allowed to publish to this relay")
+        } else if self.disallowed_kinds.contains(&event.kind)
+            || (!self.allowed_kinds.is_empty() && !self.allowed_kinds.contains(&event.kind))
+        {
+            Some("event kind is blocked by relay")
+        } else {
+            None
+        }
+    }
+
+    /// Is this client IP address blocked?
+    #[must_use]
+    pub fn is_ip_blocked(&self, ip: &str) -> bool {
+        self.blocked_ips.contains_key(ip)
+    }
+
+    /// Override relay information that was changed through the API.
+    pub fn update_info(&self, info: &mut RelayInfo) {
+        if self.relay_name.is_some() {
+            info.name = self.relay_name.clone();
+        }
+        if self.relay_description.is_some() {
+            info.description = self.relay_description.clone();
+        }
+        if self.relay_icon.is_some() {
+            info.icon = self.relay_icon.clone();
+        }
+    }
+}
+
+/// Management API request
+#[derive(Deserialize, Debug)]
+pub struct RpcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+/// Management API response
+#[derive(Serialize, Debug)]
+pub struct RpcResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RpcResponse {
+    fn ok(result: Value) -> Self {
+        RpcResponse {
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(msg: &str) -> Self {
+        RpcResponse {
+            result: None,
+            error: Some(msg.to_owned()),
+        }
+    }
+}
+
+/// Serve a management API request over HTTP.
+pub async fn handle_http_request(
+    request: Request<Body>,
+    repo: Arc<dyn NostrRepo>,
+    settings: &Settings,
+    moderation: &watch::Sender<Moderation>,
+) -> Response<Body> {
+    let (parts, body) = request.into_parts();
+    let within_limit = body
+        .size_hint()
+        .upper()
+        .is_some_and(|len| len <= MAX_REQUEST_BYTES);
+    if !within_limit {
+        return rpc_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &RpcResponse::error("request is too large"),
+        );
+    }
+    let Ok(body) = hyper::body::to_bytes(body).await else {
+        return rpc_response(
+            StatusCode::BAD_REQUEST,
+            &RpcResponse::error("could not read request"),
+        );
+    };
+    // the request must be signed by a relay administrator
+    let Some(url) = nip98::request_url(settings, &parts.uri) else {
+        return rpc_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &RpcResponse::error("relay_url is not configured"),
+        );
+    };
+    let admin = match nip98::authenticate(&parts.headers, &url, &parts.method, Some(&body)) {
+        Ok(pubkey) if settings.admin_pubkeys().contains(&pubkey) => pubkey,
+        Ok(_) => {
+            info!("rejecting management request from non-administrator");
+            return rpc_response(
+                StatusCode::UNAUTHORIZED,
+                &RpcResponse::error("pubkey is not a relay administrator"),
+            );
+        }
+        Err(e) => {
+            info!("rejecting management request: {}", e);
+            return rpc_response(
+                StatusCode::UNAUTHORIZED,
+                &RpcResponse::error(&e.to_string()),
+            );
+        }
+    };
+    let req: RpcRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(_) => {
+            return rpc_response(
+                StatusCode::BAD_REQUEST,
+                &RpcResponse::error("invalid request"),
+            );
+        }
+    };
+    info!(
+        "management request: {:?} (admin: {:?})",
+        req.method,
+        admin.chars().take(8).collect::<String>()
+    );
+    let response = handle_rpc(repo, moderation, req).await;
+    rpc_response(StatusCode::OK, &response)
+}
+
+fn rpc_response(status: StatusCode, response: &RpcResponse) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(serde_json::to_string(response).unwrap()))
+        .unwrap()
+}
+
+/// Execute a management API request, updating the shared moderation
+/// state after any change.
+pub async fn handle_rpc(
+    repo: Arc<dyn NostrRepo>,
+    moderation: &watch::Sender<Moderation>,
+    req: RpcRequest,
+) -> RpcResponse {
+    let result = match req.method.as_str() {
+        "supportedmethods" => return RpcResponse::ok(json!(SUPPORTED_METHODS)),
+        "listbannedpubkeys" => {
+            return RpcResponse::ok(reason_list("pubkey", &moderation.borrow().banned_pubkeys))
+        }
+        "listallowedpubkeys" => {
+            return RpcResponse::ok(reason_list("pubkey", &moderation.borrow().allowed_pubkeys))
+        }
+        "listbannedevents" => {
+            return RpcResponse::ok(reason_list("id", &moderation.borrow().banned_events))
+        }
+        "listallowedkinds" => return RpcResponse::ok(json!(moderation.borrow().allowed_kinds)),
+        "listdisallowedkinds" => {
+            return RpcResponse::ok(json!(moderation.borrow().disallowed_kinds))
+        }
+        "listblockedips" => {
+            return RpcResponse::ok(reason_list("ip", &moderation.borrow().blocked_ips))
+        }
+        "banpubkey" | "allowpubkey" => match hex_param(&req.params) {
+            Some(pubkey) => {
+                let allowed = req.method == "allowpubkey";
+                repo.set_pubkey_policy(&pubkey, allowed, reason_param(&req.params))
+                    .await
+            }
+            None => return RpcResponse::error("invalid pubkey"),
+        },
+        "unbanpubkey" | "unallowpubkey" => match hex_param(&req.params) {
+            Some(pubkey) => {
+                let allowed = req.method == "unallowpubkey";
+                repo.remove_pubkey_policy(&pubkey, allowed).await
+            }
+            None => return RpcResponse::error("invalid pubkey"),
+        },
+        // allowing a banned event lifts the ban, as does unbanning it
+        "banevent" | "allowevent" | "unbanevent" => match hex_param(&req.params) {
+            Some(id) => {
+                let banned = req.method == "banevent";
+                repo.set_event_banned(&id, banned, reason_param(&req.params))
+                    .await
+            }
+            None => return RpcResponse::error("invalid event id"),
+        },
+        "allowkind" | "disallowkind" => match req.params.first().and_then(Value::as_u64) {
+            Some(kind) => repo.set_kind_policy(kind, req.method == "allowkind").await,
+            None => return RpcResponse::error("invalid kind"),
+        },
+        "unallowkind" | "undisallowkind" => match req.params.first().and_then(Value::as_u64) {
+            Some(kind) => {
+                repo.remove_kind_policy(kind, req.method == "unallowkind")
+                    .await
+            }
+            None => return RpcResponse::error("invalid kind"),
+        },
+        "blockip" | "unblockip" => match req.params.first().and_then(Value::as_str) {
+            Some(ip) if ip.parse::<std::net::IpAddr>().is_ok() => {
+                let blocked = req.method == "blockip";
+                repo.set_ip_blocked(ip, blocked, reason_param(&req.params))
+                    .await
+            }
+            _ => return RpcResponse::error("invalid IP address"),
+        },
+        "changerelayname" | "changerelaydescription" | "changerelayicon" => {
+            match req.params.first().and_then(Value::as_str) {
+                Some(value) => {
+                    let name = req.method.trim_start_matches("changerelay");
+                    repo.set_relay_info(name, value).await
+                }
+                None => return RpcResponse::error("invalid value"),
+            }
+        }
+        "listnames" => {
+            return match repo.get_local_names().await {
+                Ok(names) => RpcResponse::ok(
+                    names
+                        .into_iter()
+                        .map(|(name, pubkey)| json!({"name": name, "pubkey": pubkey}))
+                        .collect(),
+                ),
+                Err(e) => {
+                    warn!("could not list local names: {:?}", e);
+                    RpcResponse::error("internal error")
+                }
+            }
+        }
+        "setname" => match (
+            name_param(&req.params),
+            req.params.get(1..).and_then(hex_param),
+        ) {
+            (Some(name), Some(pubkey)) => repo.set_local_name(&name, &pubkey).await,
+            (None, _) => return RpcResponse::error("invalid name"),
+            (_, None) => return RpcResponse::error("invalid pubkey"),
+        },
+        "removename" => match name_param(&req.params) {
+            Some(name) => repo.remove_local_name(&name).await.map(|_| ()),
+            None => return RpcResponse::error("invalid name"),
+        },
+        _ => return RpcResponse::error("unsupported method"),
+    };
+    match result.and(repo.get_moderation().await) {
+        Ok(state) => {
+            moderation.send_replace(state);
+            RpcResponse::ok(json!(true))
+        }
+        Err(e) => {
+            warn!("management request failed: {:?}", e);
+            RpcResponse::error("internal error")
+        }
+    }
+}
+
+/// First parameter, if it is a 32-byte lowercase hex value
+fn hex_param(params: &[Value]) -> Option<String> {
+    params
+        .first()
+        .and_then(Value::as_str)
+        .filter(|s| s.len() == 64 && is_lower_hex(s))
+        .map(std::borrow::ToOwned::to_owned)
+}
+
+/// First parameter, if it is a valid local name (NIP-05)
+fn name_param(params: &[Value]) -> Option<String> {
+    params
+        .first()
+        .and_then(Value::as_str)
+        .filter(|s| nip05::is_valid_local_name(s))
+        .map(std::borrow::ToOwned::to_owned)
+}
+
+/// Optional reason, given as the second parameter
+fn reason_param(params: &[Value]) -> Option<String> {
+    params
+        .get(1)
+        .and_then(Value::as_str)
+        .map(std::borrow::ToOwned::to_owned)
+}
+
+fn reason_list(key: &str, entries: &BTreeMap<String, Option<String>>) -> Value {
+    entries
+        .iter()
+        .map(|(value, reason)| {
+            let mut entry = json!({ key: value });
+            if let Some(reason) = reason {
+                entry["reason"] = json!(reason);
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Load the moderation state, falling back to an empty state.
+pub async fn load_moderation(repo: &Arc<dyn NostrRepo>) -> Moderation {
+    repo.get_moderation().await.unwrap_or_else(|e: Error| {
+        warn!("could not load moderation state: {:?}", e);
+        Moderation::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banned_pubkey_rejected() {
+        let mut moderation = Moderation::default();
+        let mut event = Event::simple_event();
+        assert!(moderation.event_rejection(&event, false).is_none());
+        moderation.banned_pubkeys.insert("0".to_owned(), None);
+        assert!(moderation.event_rejection(&event, false).is_some());
+        event.pubkey = "1".to_owned();
+        assert!(moderation.event_rejection(&event, false).is_none());
+        // delegators are also subject to bans
+        event.delegated_by = Some("0".to_owned());
+        assert!(moderation.event_rejection(&event, false).is_some());
+    }
+
+    #[test]
+    fn allowlists() {
+        let mut moderation = Moderation::default();
+        let mut event = Event::simple_event();
+        moderation.allowed_pubkeys.insert("1".to_owned(), None);
+        assert!(moderation.event_rejection(&event, false).is_some());
+        event.pubkey = "1".to_owned();
+        assert!(moderation.event_rejection(&event, false).is_none());
+        moderation.allowed_kinds.insert(1);
+        assert!(moderation.event_rejection(&event, false).is_some());
+        event.kind = 1;
+        assert!(moderation.event_rejection(&event, false).is_none());
+        moderation.disallowed_kinds.insert(1);
+        assert!(moderation.event_rejection(&event, false).is_some());
+    }
+
+    #[tokio::test]
+    async fn allowlist_can_be_left() {
+        let dir = std::env::temp_dir().join(format!("nip86-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.display().to_string();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = crate::repo::sqlite::SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await.unwrap();
+        let repo: Arc<dyn NostrRepo> = Arc::new(repo);
+        let (moderation, _) = watch::channel(Moderation::default());
+        let pubkey = "a".repeat(64);
+        let mut event = Event::simple_event();
+        event.pubkey = "b".repeat(64);
+        let call = |method: &str, params: Value| RpcRequest {
+            method: method.to_owned(),
+            params: serde_json::from_value(params).unwrap(),
+        };
+        handle_rpc(
+            repo.clone(),
+            &moderation,
+            call("allowpubkey", json!([pubkey])),
+        )
+        .await;
+        assert!(moderation.borrow().event_rejection(&event, false).is_some());
+        // unbanning does not remove an allowed pubkey
+        handle_rpc(
+            repo.clone(),
+            &moderation,
+            call("unbanpubkey", json!([pubkey])),
+        )
+        .await;
+        assert!(moderation.borrow().event_rejection(&event, false).is_some());
+        handle_rpc(
+            repo.clone(),
+            &moderation,
+            call("unallowpubkey", json!([pubkey])),
+        )
+        .await;
+        assert!(moderation.borrow().event_rejection(&event, false).is_none());
+        // a ban can be lifted without allowing the pubkey
+        handle_rpc(
+            repo.clone(),
+            &moderation,
+            call("banpubkey", json!([event.pubkey])),
+        )
+        .await;
+        assert!(moderation.borrow().event_rejection(&event, false).is_some());
+        handle_rpc(
+            repo.clone(),
+            &moderation,
+            call("unbanpubkey", json!([event.pubkey])),
+        )
+        .await;
+        assert!(moderation.borrow().event_rejection(&event, false).is_none());
+        assert!(moderation.borrow().allowed_pubkeys.is_empty());
+        // kinds work the same way
+        handle_rpc(repo.clone(), &moderation, call("allowkind", json!([1]))).await;
+        assert!(moderation.borrow().event_rejection(&event, false).is_some());
+        handle_rpc(repo.clone(), &moderation, call("unallowkind", json!([1]))).await;
+        assert!(moderation.borrow().event_rejection(&event, false).is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn banned_events_not_served() {
+        let dir = std::env::temp_dir().join(format!("nip86-ban-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.display().to_string();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = crate::repo::sqlite::SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await.unwrap();
+        let repo: Arc<dyn NostrRepo> = Arc::new(repo);
+        let (moderation, _) = watch::channel(Moderation::default());
+        let mut event = Event::simple_event();
+        event.id = "c".repeat(64);
+        event.pubkey = "b".repeat(64);
+        repo.write_event(&event).await.unwrap();
+        let sub: crate::subscription::Subscription =
+            serde_json::from_value(json!(["REQ", "s", {"ids": [event.id]}])).unwrap();
+        assert_eq!(
+            repo.count_subscription(sub.clone(), "c".to_owned())
+                .await
+                .unwrap(),
+            1
+        );
+        let ban = RpcRequest {
+            method: "banevent".to_owned(),
+            params: vec![json!(event.id)],
+        };
+        handle_rpc(repo.clone(), &moderation, ban).await;
+        assert!(moderation.borrow().event_rejection(&event, false).is_some());
+        assert_eq!(
+            repo.count_subscription(sub, "c".to_owned()).await.unwrap(),
+            0
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn relay_info_overrides() {
+        let moderation = Moderation {
+            relay_name: Some("renamed".to_owned()),
+            ..Moderation::default()
+        };
+        let mut settings = Settings::default();
+        settings.info.description = Some("description".to_owned());
+        let mut info = RelayInfo::from(settings);
+        moderation.update_info(&mut info);
+        assert_eq!(info.name, Some("renamed".to_owned()));
+        assert_eq!(info.description, Some("description".to_owned()));
+    }
+
+    #[test]
+    fn reasons_listed() {
+        let mut entries = BTreeMap::new();
+        entries.insert("a".to_owned(), Some("spam".to_owned()));
+        entries.insert("b".to_owned(), None);
+        assert_eq!(
+            reason_list("pubkey", &entries),
+            json!([{"pubkey": "a", "reason": "spam"}, {"pubkey": "b"}])
+        );
+    }
+}
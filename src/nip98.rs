@@ -0,0 +1,244 @@
+//! HTTP authentication using NIP-98
+//!
+//! NIP-98 authenticates HTTP requests with a signed kind 27235 event,
+//! sent base64-encoded in an `Authorization: Nostr <event>` header.
+//! The event names the absolute request URL and method in `u` and
+//! `method` tags, and may commit to the request body with a SHA-256
+//! `payload` tag.
+use crate::config::Settings;
+use crate::event::Event;
+use crate::utils::{is_lower_hex, unix_time};
+use bitcoin_hashes::{sha256, Hash};
+use http::header::AUTHORIZATION;
+use http::{HeaderMap, Method, Uri};
+use thiserror::Error;
+
+/// Kind of HTTP auth events
+pub const HTTP_AUTH_KIND: u64 = 27235;
+
+/// How far (in seconds) an auth event timestamp may be from now
+pub const HTTP_AUTH_WINDOW: u64 = 60;
+
+/// Reasons an HTTP request could not be authenticated
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HttpAuthError {
+    #[error("missing authorization")]
+    MissingHeader,
+    #[error("unsupported authorization scheme")]
+    UnsupportedScheme,
+    #[error("invalid authorization encoding")]
+    InvalidEncoding,
+    #[error("invalid authorization event")]
+    InvalidEvent,
+    #[error("authorization event has the wrong kind")]
+    WrongKind,
+    #[error("authorization event signature is invalid")]
+    InvalidSignature,
+    #[error("authorization event is expired")]
+    Expired,
+    #[error("authorization event URL does not match")]
+    UrlMismatch,
+    #[error("authorization event method does not match")]
+    MethodMismatch,
+    #[error("authorization event payload does not match")]
+    PayloadMismatch,
+}
+
+/// Authenticate a request from its headers, returning the pubkey.
+///
+/// If a body is given, the auth event must commit to it with a
+/// `payload` tag.
+pub fn authenticate(
+    headers: &HeaderMap,
+    url: &str,
+    method: &Method,
+    body: Option<&[u8]>,
+) -> Result<String, HttpAuthError> {
+    let header = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .ok_or(HttpAuthError::MissingHeader)?;
+    verify_header(header, url, method, body)
+}
+
+/// Verify an `Authorization` header value, returning the pubkey.
+pub fn verify_header(
+    header: &str,
+    url: &str,
+    method: &Method,
+    body: Option<&[u8]>,
+) -> Result<String, HttpAuthError> {
+    let encoded = header
+        .strip_prefix("Nostr ")
+        .ok_or(HttpAuthError::UnsupportedScheme)?;
+    let decoded = base64::decode(encoded.trim()).map_err(|_| HttpAuthError::InvalidEncoding)?;
+    let event: Event = serde_json::from_slice(&decoded).map_err(|_| HttpAuthError::InvalidEvent)?;
+    verify_event(&event, url, method, body)?;
+    Ok(event.pubkey)
+}
+
+/// Verify an auth event for a request.
+pub fn verify_event(
+    event: &Event,
+    url: &str,
+    method: &Method,
+    body: Option<&[u8]>,
+) -> Result<(), HttpAuthError> {
+    if event.kind != HTTP_AUTH_KIND {
+        return Err(HttpAuthError::WrongKind);
+    }
+    // malformed signatures are rejected before validation
+    if event.sig.len() != 128 || !is_lower_hex(&event.sig) || event.validate().is_err() {
+        return Err(HttpAuthError::InvalidSignature);
+    }
+    if unix_time().abs_diff(event.created_at) > HTTP_AUTH_WINDOW {
+        return Err(HttpAuthError::Expired);
+    }
+    let tag = |name: &str| event.tag_values_by_name(name).into_iter().next();
+    let url_matches =
+        tag("u").is_some_and(|u| u.trim_end_matches('/') == url.trim_end_matches('/'));
+    if !url_matches {
+        return Err(HttpAuthError::UrlMismatch);
+    }
+    if !tag("method").is_some_and(|m| m.eq_ignore_ascii_case(method.as_str())) {
+        return Err(HttpAuthError::MethodMismatch);
+    }
+    if let Some(body) = body {
+        let digest = sha256::Hash::hash(body);
+        if tag("payload") != Some(format!("{digest:x}")) {
+            return Err(HttpAuthError::PayloadMismatch);
+        }
+    }
+    Ok(())
+}
+
+/// Absolute HTTP URL of a request to this relay, based on the
+/// advertised relay URL.
+#[must_use]
+pub fn request_url(settings: &Settings, uri: &Uri) -> Option<String> {
+    let relay_url = settings.info.relay_url.as_ref()?;
+    let base = relay_url
+        .replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1);
+    let path = uri
+        .path_and_query()
+        .map_or("/", http::uri::PathAndQuery::as_str);
+    Some(format!("{}{}", base.trim_end_matches('/'), path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
+
+    const URL: &str = "https://relay.example.com/";
+
+    fn auth_event(tags: Vec<Vec<&str>>) -> Event {
+        signed_auth_event(tags, unix_time())
+    }
+
+    fn signed_auth_event(tags: Vec<Vec<&str>>, created_at: u64) -> Event {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let mut event = Event::simple_event();
+        event.pubkey = XOnlyPublicKey::from_keypair(&keypair).to_string();
+        event.kind = HTTP_AUTH_KIND;
+        event.created_at = created_at;
+        event.tags = tags
+            .into_iter()
+            .map(|t| t.into_iter().map(str::to_owned).collect())
+            .collect();
+        let digest = sha256::Hash::hash(event.to_canonical().unwrap().as_bytes());
+        event.id = format!("{digest:x}");
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        event.sig = secp.sign_schnorr(&msg, &keypair).to_string();
+        event
+    }
+
+    fn header(event: &Event) -> String {
+        format!(
+            "Nostr {}",
+            base64::encode(serde_json::to_string(event).unwrap())
+        )
+    }
+
+    #[test]
+    fn valid_header() {
+        let event = auth_event(vec![vec!["u", URL], vec!["method", "GET"]]);
+        let pubkey = verify_header(&header(&event), URL, &Method::GET, None);
+        assert_eq!(pubkey, Ok(event.pubkey));
+    }
+
+    #[test]
+    fn url_and_method_checked() {
+        let event = auth_event(vec![vec!["u", URL], vec!["method", "GET"]]);
+        let other_url = "https://relay.example.com/other";
+        assert_eq!(
+            verify_event(&event, other_url, &Method::GET, None),
+            Err(HttpAuthError::UrlMismatch)
+        );
+        assert_eq!(
+            verify_event(&event, URL, &Method::POST, None),
+            Err(HttpAuthError::MethodMismatch)
+        );
+    }
+
+    #[test]
+    fn payload_checked() {
+        let body = b"{}";
+        let digest = format!("{:x}", sha256::Hash::hash(body));
+        let event = auth_event(vec![
+            vec!["u", URL],
+            vec!["method", "POST"],
+            vec!["payload", &digest],
+        ]);
+        assert!(verify_event(&event, URL, &Method::POST, Some(body)).is_ok());
+        assert_eq!(
+            verify_event(&event, URL, &Method::POST, Some(b"[]")),
+            Err(HttpAuthError::PayloadMismatch)
+        );
+    }
+
+    #[test]
+    fn tampered_event_rejected() {
+        let mut event = auth_event(vec![vec!["u", URL], vec!["method", "GET"]]);
+        event.created_at -= 1;
+        assert_eq!(
+            verify_event(&event, URL, &Method::GET, None),
+            Err(HttpAuthError::InvalidSignature)
+        );
+        event.sig = "invalid".to_owned();
+        assert_eq!(
+            verify_event(&event, URL, &Method::GET, None),
+            Err(HttpAuthError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn stale_event_rejected() {
+        let created_at = unix_time() - HTTP_AUTH_WINDOW - 1;
+        let event = signed_auth_event(vec![vec!["u", URL], vec!["method", "GET"]], created_at);
+        assert_eq!(
+            verify_event(&event, URL, &Method::GET, None),
+            Err(HttpAuthError::Expired)
+        );
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert_eq!(
+            verify_header("Basic abc", URL, &Method::GET, None),
+            Err(HttpAuthError::UnsupportedScheme)
+        );
+        assert_eq!(
+            verify_header("Nostr !!!", URL, &Method::GET, None),
+            Err(HttpAuthError::InvalidEncoding)
+        );
+        let mut settings = Settings::default();
+        settings.info.relay_url = Some("wss://relay.example.com".to_owned());
+        assert_eq!(
+            request_url(&settings, &"/admin?x=1".parse().unwrap()),
+            Some("https://relay.example.com/admin?x=1".to_owned())
+        );
+    }
+}
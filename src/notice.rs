@@ -1,9 +1,13 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventResultStatus {
     Saved,
     Duplicate,
     Invalid,
     Blocked,
     RateLimited,
+    AuthRequired,
+    Restricted,
+    Pow,
     Error,
 }
 
@@ -13,10 +17,16 @@ pub struct EventResult {
     pub status: EventResultStatus,
 }
 
+pub struct SubscriptionClosed {
+    pub sub_id: String,
+    pub msg: String,
+}
+
 pub enum Notice {
     Message(String),
     EventResult(EventResult),
     AuthChallenge(String),
+    Closed(SubscriptionClosed),
 }
 
 impl EventResultStatus {
@@ -24,7 +34,13 @@ impl EventResultStatus {
     pub fn to_bool(&self) -> bool {
         match self {
             Self::Duplicate | Self::Saved => true,
-            Self::Invalid | Self::Blocked | Self::RateLimited | Self::Error => false,
+            Self::Invalid
+            | Self::Blocked
+            | Self::RateLimited
+            | Self::AuthRequired
+            | Self::Restricted
+            | Self::Pow
+            | Self::Error => false,
         }
     }
 
@@ -36,6 +52,9 @@ impl EventResultStatus {
             Self::Invalid => "invalid",
             Self::Blocked => "blocked",
             Self::RateLimited => "rate-limited",
+            Self::AuthRequired => "auth-required",
+            Self::Restricted => "restricted",
+            Self::Pow => "pow",
             Self::Error => "error",
         }
     }
@@ -71,6 +90,21 @@ impl Notice {
         Notice::prefixed(id, msg, EventResultStatus::RateLimited)
     }
 
+    #[must_use]
+    pub fn auth_required(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::AuthRequired)
+    }
+
+    #[must_use]
+    pub fn restricted(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::Restricted)
+    }
+
+    #[must_use]
+    pub fn pow(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::Pow)
+    }
+
     #[must_use]
     pub fn duplicate(id: String) -> Notice {
         Notice::prefixed(id, "", EventResultStatus::Duplicate)
@@ -81,6 +115,13 @@ impl Notice {
         Notice::prefixed(id, msg, EventResultStatus::Error)
     }
 
+    /// A subscription was closed by the relay, with a prefixed reason.
+    #[must_use]
+    pub fn closed(sub_id: String, msg: &str, status: EventResultStatus) -> Notice {
+        let msg = format!("{}: {}", status.prefix(), msg);
+        Notice::Closed(SubscriptionClosed { sub_id, msg })
+    }
+
     #[must_use]
     pub fn saved(id: String) -> Notice {
         Notice::EventResult(EventResult {
@@ -1,11 +1,15 @@
 use crate::db::QueryResult;
 use crate::error::Result;
-use crate::event::Event;
+use crate::event::{BroadcastEvent, Event};
 use crate::nip05::VerificationRecord;
-use crate::subscription::Subscription;
+use crate::nip29::{Group, GroupState};
+use crate::nip86::Moderation;
+use crate::subscription::{ReqFilter, Subscription};
 use crate::utils::unix_time;
 use async_trait::async_trait;
 use rand::Rng;
+use std::collections::BTreeMap;
+use tokio::sync::broadcast;
 pub mod postgres;
 pub mod postgres_migration;
 pub mod sqlite;
@@ -22,6 +26,23 @@ pub trait NostrRepo: Send + Sync {
     /// Persist event to database
     async fn write_event(&self, e: &Event) -> Result<u64>;
 
+    /// Persist a batch of events in a single transaction.
+    ///
+    /// Events are written in order, each as by `write_event`, and a
+    /// result is returned for every event.  An event that fails to be
+    /// written does not prevent the others from being persisted.
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<Result<u64>>>;
+
+    /// Rebroadcast events written by other relays sharing this database.
+    ///
+    /// Events persisted by another relay instance are published on
+    /// `bcast_tx`, so they reach subscribers connected here.  Does
+    /// nothing for databases that are not shared.
+    async fn broadcast_remote_writes(
+        &self,
+        bcast_tx: broadcast::Sender<BroadcastEvent>,
+    ) -> Result<()>;
+
     /// Perform a database query using a subscription.
     ///
     /// The [`Subscription`] is converted into a SQL query.  Each result
@@ -36,6 +57,25 @@ pub trait NostrRepo: Send + Sync {
         mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
     ) -> Result<()>;
 
+    /// Count the events matching a subscription (NIP-45).
+    ///
+    /// Events matched by more than one filter are counted once, and
+    /// filter limits are ignored.
+    async fn count_subscription(&self, sub: Subscription, client_id: String) -> Result<u64>;
+
+    /// Stream the `(created_at, id)` pairs of events matching a filter
+    /// (NIP-77).
+    ///
+    /// Pairs are published on the `ids_tx` channel; the query stops
+    /// early if the receiver is dropped.  A filter limit selects the
+    /// most recent events.
+    async fn query_ids(
+        &self,
+        filter: ReqFilter,
+        client_id: String,
+        ids_tx: tokio::sync::mpsc::Sender<(u64, [u8; 32])>,
+    ) -> Result<()>;
+
     /// Perform normal maintenance
     async fn optimize_db(&self) -> Result<()>;
 
@@ -56,6 +96,59 @@ pub trait NostrRepo: Send + Sync {
 
     /// Get oldest verification before timestamp
     async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord>;
+
+    /// Get the moderation state managed through NIP-86
+    async fn get_moderation(&self) -> Result<Moderation>;
+
+    /// Ban a pubkey from publishing, or add it to the allowed pubkeys
+    async fn set_pubkey_policy(
+        &self,
+        pubkey: &str,
+        allowed: bool,
+        reason: Option<String>,
+    ) -> Result<()>;
+
+    /// Remove a pubkey from the banned (or allowed) pubkeys
+    async fn remove_pubkey_policy(&self, pubkey: &str, allowed: bool) -> Result<()>;
+
+    /// Ban an event (deleting it, if stored), or lift a ban
+    async fn set_event_banned(&self, id: &str, banned: bool, reason: Option<String>) -> Result<()>;
+
+    /// Add a kind to the allowed or disallowed kinds
+    async fn set_kind_policy(&self, kind: u64, allowed: bool) -> Result<()>;
+
+    /// Remove a kind from the allowed (or disallowed) kinds
+    async fn remove_kind_policy(&self, kind: u64, allowed: bool) -> Result<()>;
+
+    /// Block or unblock connections from an IP address
+    async fn set_ip_blocked(&self, ip: &str, blocked: bool, reason: Option<String>) -> Result<()>;
+
+    /// Override a relay information field (name, description or icon)
+    async fn set_relay_info(&self, field: &str, value: &str) -> Result<()>;
+
+    /// Get the state of all groups (NIP-29)
+    async fn get_groups(&self) -> Result<GroupState>;
+
+    /// Create a group, or replace its state
+    async fn save_group(&self, group: &Group) -> Result<()>;
+
+    /// Delete a group, with its members and invites
+    async fn delete_group(&self, group_id: &str) -> Result<()>;
+
+    /// Delete events posted to a group, returning the number removed
+    async fn delete_group_events(&self, group_id: &str, ids: &[String]) -> Result<u64>;
+
+    /// Get all registered local names, with their pubkeys (NIP-05)
+    async fn get_local_names(&self) -> Result<BTreeMap<String, String>>;
+
+    /// Get the pubkey registered for a local name
+    async fn get_local_name(&self, name: &str) -> Result<Option<String>>;
+
+    /// Register a local name for a pubkey, replacing any existing entry
+    async fn set_local_name(&self, name: &str, pubkey: &str) -> Result<()>;
+
+    /// Remove a local name, returning whether it was registered
+    async fn remove_local_name(&self, name: &str) -> Result<bool>;
 }
 
 // Current time, with a slight forward jitter in seconds
@@ -1,22 +1,28 @@
+use crate::config::{Retention, Settings};
 use crate::db::QueryResult;
 use crate::error::Result;
-use crate::event::{single_char_tagname, Event};
+use crate::event::{is_indexed_tagname, BroadcastEvent, Event, PRIVATE_KINDS};
 use crate::nip05::{Nip05Name, VerificationRecord};
+use crate::nip29::{Group, GroupState};
+use crate::nip86::Moderation;
+use crate::notice::EventResultStatus;
 use crate::repo::{now_jitter, NostrRepo};
 use crate::subscription::{ReqFilter, Subscription};
 use async_std::stream::StreamExt;
 use async_trait::async_trait;
 use chrono::{DateTime, TimeZone, Utc};
-use sqlx::postgres::PgRow;
+use sqlx::postgres::{PgListener, PgRow};
 use sqlx::Error::RowNotFound;
-use sqlx::{Error, Execute, FromRow, Postgres, QueryBuilder, Row};
+use sqlx::{Acquire, Error, Execute, FromRow, Postgres, QueryBuilder, Row, Transaction};
+use std::collections::{BTreeMap, HashSet};
 use std::time::{Duration, Instant};
 
 use crate::error;
 use crate::hexrange::{hex_range, HexSearch};
-use crate::repo::postgres_migration::run_migrations;
+use crate::repo::postgres_migration::{reindex_tag_names, run_migrations};
 use crate::server::NostrMetrics;
 use crate::utils::{self, is_hex, is_lower_hex};
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot::Receiver;
 use tracing::log::trace;
@@ -28,78 +34,104 @@ pub struct PostgresRepo {
     conn: PostgresPool,
     conn_write: PostgresPool,
     metrics: NostrMetrics,
+    retention: Retention,
+    search_kinds: Vec<u64>,
+    indexed_tags: Vec<String>,
+    relay_url: Option<String>,
+    notify_events: bool,
+    instance_id: String,
 }
 
+/// Channel on which relays sharing a database announce new events.
+const EVENT_NOTIFY_CHANNEL: &str = "nostr_events";
+
 impl PostgresRepo {
-    pub fn new(c: PostgresPool, cw: PostgresPool, m: NostrMetrics) -> PostgresRepo {
+    pub fn new(
+        c: PostgresPool,
+        cw: PostgresPool,
+        settings: &Settings,
+        m: NostrMetrics,
+    ) -> PostgresRepo {
         PostgresRepo {
             conn: c,
             conn_write: cw,
             metrics: m,
+            retention: settings.retention.clone(),
+            search_kinds: settings.options.searchable_kinds().to_vec(),
+            indexed_tags: settings.options.indexed_tag_names().to_vec(),
+            relay_url: settings.info.relay_url.clone(),
+            notify_events: settings.database.notify_events,
+            // distinguishes our own announcements from other relays'
+            instance_id: format!("{:016x}", rand::random::<u64>()),
         }
     }
-}
-
-/// Cleanup expired events on a regular basis
-async fn cleanup_expired(conn: PostgresPool, frequency: Duration) -> Result<()> {
-    tokio::task::spawn(async move {
-        loop {
-            tokio::select! {
-                _ = tokio::time::sleep(frequency) => {
-                    let start = Instant::now();
-                    let exp_res = delete_expired(conn.clone()).await;
-                    match exp_res {
-                        Ok(exp_count) => {
-                            if exp_count > 0 {
-                                info!("removed {} expired events in: {:?}", exp_count, start.elapsed());
-                            }
-                        },
-                        Err(e) => {
-                            warn!("could not remove expired events due to error: {:?}", e);
-                        }
-                    }
-                }
-            };
-        }
-    });
-    Ok(())
-}
-
-/// One-time deletion of all expired events
-async fn delete_expired(conn: PostgresPool) -> Result<u64> {
-    let mut tx = conn.begin().await?;
-    let update_count = sqlx::query("DELETE FROM \"event\" WHERE expires_at <= $1;")
-        .bind(Utc.timestamp_opt(utils::unix_time() as i64, 0).unwrap())
-        .execute(&mut tx)
-        .await?
-        .rows_affected();
-    tx.commit().await?;
-    Ok(update_count)
-}
 
-#[async_trait]
-impl NostrRepo for PostgresRepo {
-    async fn start(&self) -> Result<()> {
-        // begin a cleanup task for expired events.
-        cleanup_expired(self.conn_write.clone(), Duration::from_secs(600)).await?;
+    /// Announce a new event to other relays, once the transaction
+    /// commits.
+    async fn notify_event(&self, tx: &mut Transaction<'_, Postgres>, e: &Event) -> Result<()> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(EVENT_NOTIFY_CHANNEL)
+            .bind(format!("{}:{}", self.instance_id, e.id))
+            .execute(&mut *tx)
+            .await?;
         Ok(())
     }
 
-    async fn migrate_up(&self) -> Result<usize> {
-        Ok(run_migrations(&self.conn_write).await?)
-    }
-
-    async fn write_event(&self, e: &Event) -> Result<u64> {
-        // start transaction
-        let mut tx = self.conn_write.begin().await?;
-        let start = Instant::now();
-
+    /// Insert an event within a transaction, returning rows added.
+    async fn persist_event(&self, tx: &mut Transaction<'_, Postgres>, e: &Event) -> Result<u64> {
         // get relevant fields from event and convert to blobs.
         let id_blob = hex::decode(&e.id).ok();
         let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
         let delegator_blob: Option<Vec<u8>> =
             e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
         let event_str = serde_json::to_string(&e).unwrap();
+        let created_at = Utc.timestamp_opt(e.created_at as i64, 0).unwrap();
+
+        // refuse events from authors that asked to vanish at or after
+        // their creation (NIP-62).
+        let vanished = sqlx::query(
+            "SELECT 1 FROM vanish_request WHERE pub_key=$1 AND created_at >= $2 LIMIT 1;",
+        )
+        .bind(&pubkey_blob)
+        .bind(created_at)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if vanished.is_some() {
+            return Err(error::Error::AuthorVanished);
+        }
+        // a request to vanish removes every event from the author up
+        // to the request, and gift wraps addressed to them, and is
+        // remembered instead of stored.
+        if e.is_vanish_request(self.relay_url.as_deref()) {
+            let in_events = "SELECT id FROM \"event\" WHERE pub_key=$1 AND created_at <= $2";
+            for query in [
+                format!("DELETE FROM user_verification WHERE event_id IN ({in_events});"),
+                format!("DELETE FROM tag WHERE event_id IN ({in_events});"),
+                "DELETE FROM \"event\" WHERE pub_key=$1 AND created_at <= $2;".to_owned(),
+            ] {
+                sqlx::query(&query)
+                    .bind(&pubkey_blob)
+                    .bind(created_at)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            // hex tag values are stored decoded; tags of gift wraps
+            // are removed with them.
+            sqlx::query("DELETE FROM \"event\" WHERE kind = 1059 AND id IN (SELECT event_id FROM tag WHERE \"name\" = 'p' AND value_hex = $1)")
+                .bind(&pubkey_blob)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("INSERT INTO vanish_request (pub_key, created_at) VALUES ($1, $2) ON CONFLICT (pub_key) DO UPDATE SET created_at = $2")
+                .bind(&pubkey_blob)
+                .bind(created_at)
+                .execute(&mut *tx)
+                .await?;
+            info!(
+                "removed events for vanished author: {:?}",
+                e.get_author_prefix()
+            );
+            return Ok(1);
+        }
 
         // determine if this event would be shadowed by an existing
         // replaceable event or parameterized replaceable event.
@@ -109,7 +141,7 @@ impl NostrRepo for PostgresRepo {
                 .bind(&pubkey_blob)
                 .bind(e.kind as i64)
                 .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                .fetch_optional(&mut tx)
+                .fetch_optional(&mut *tx)
                 .await?;
             if repl_count.is_some() {
                 return Ok(0);
@@ -123,7 +155,7 @@ impl NostrRepo for PostgresRepo {
                     .bind(e.kind as i64)
                     .bind(hex::decode(d_tag).ok())
                     .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                    .fetch_one(&mut tx)
+                    .fetch_one(&mut *tx)
                     .await?
             } else {
                 sqlx::query_scalar(
@@ -132,7 +164,7 @@ impl NostrRepo for PostgresRepo {
                     .bind(e.kind as i64)
                     .bind(d_tag.as_bytes())
                     .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                    .fetch_one(&mut tx)
+                    .fetch_one(&mut *tx)
                     .await?
             };
             // if any rows were returned, then some newer event with
@@ -145,8 +177,8 @@ impl NostrRepo for PostgresRepo {
         // ignore if the event hash is a duplicate.
         let mut ins_count = sqlx::query(
             r#"INSERT INTO "event"
-(id, pub_key, created_at, expires_at, kind, "content", delegated_by)
-VALUES($1, $2, $3, $4, $5, $6, $7)
+(id, pub_key, created_at, expires_at, kind, "content", delegated_by, search_vector)
+VALUES($1, $2, $3, $4, $5, $6, $7, to_tsvector('simple', $8))
 ON CONFLICT (id) DO NOTHING"#,
         )
         .bind(&id_blob)
@@ -159,7 +191,13 @@ ON CONFLICT (id) DO NOTHING"#,
         .bind(e.kind as i64)
         .bind(event_str.into_bytes())
         .bind(delegator_blob)
-        .execute(&mut tx)
+        // only searchable kinds are indexed for full-text search (NIP-50)
+        .bind(
+            self.search_kinds
+                .contains(&e.kind)
+                .then(|| e.content.clone()),
+        )
+        .execute(&mut *tx)
         .await?
         .rows_affected();
 
@@ -175,30 +213,26 @@ ON CONFLICT (id) DO NOTHING"#,
             if tag.len() >= 2 {
                 let tag_name = &tag[0];
                 let tag_val = &tag[1];
-                // only single-char tags are searchable
-                let tag_char_opt = single_char_tagname(tag_name);
-                match &tag_char_opt {
-                    Some(_) => {
-                        // if tag value is lowercase hex;
-                        if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
-                            sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
+                // only single-char and configured tags are searchable
+                if is_indexed_tagname(tag_name, &self.indexed_tags) {
+                    // if tag value is lowercase hex;
+                    if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
+                        sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
                     ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(hex::decode(tag_val).ok())
-                                .execute(&mut tx)
-                                .await?;
-                        } else {
-                            sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
+                            .bind(&id_blob)
+                            .bind(tag_name)
+                            .bind(hex::decode(tag_val).ok())
+                            .execute(&mut *tx)
+                            .await?;
+                    } else {
+                        sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
                     ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(tag_val.as_bytes())
-                                .execute(&mut tx)
-                                .await?;
-                        }
+                            .bind(&id_blob)
+                            .bind(tag_name)
+                            .bind(tag_val.as_bytes())
+                            .execute(&mut *tx)
+                            .await?;
                     }
-                    None => {}
                 }
             }
         }
@@ -206,7 +240,7 @@ ON CONFLICT (id) DO NOTHING"#,
             let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc limit 1);")
                 .bind(e.kind as i64)
                 .bind(hex::decode(&e.pubkey).ok())
-                .execute(&mut tx)
+                .execute(&mut *tx)
                 .await?.rows_affected();
             if update_count > 0 {
                 info!(
@@ -225,14 +259,14 @@ ON CONFLICT (id) DO NOTHING"#,
                     .bind(e.kind as i64)
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(hex::decode(d_tag).ok())
-                    .execute(&mut tx)
+                    .execute(&mut *tx)
                     .await?.rows_affected()
             } else {
                 sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 ORDER BY created_at DESC OFFSET 1);")
                     .bind(e.kind as i64)
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(d_tag.as_bytes())
-                    .execute(&mut tx)
+                    .execute(&mut *tx)
                     .await?.rows_affected()
             };
             if update_count > 0 {
@@ -265,12 +299,40 @@ ON CONFLICT (id) DO NOTHING"#,
             }
             sep.push_unseparated(")");
 
-            let update_count = builder.build().execute(&mut tx).await?.rows_affected();
+            let update_count = builder.build().execute(&mut *tx).await?.rows_affected();
             info!(
                 "hid {} deleted events for author {:?}",
                 update_count,
                 e.get_author_prefix()
             );
+            // hide parameterized replaceable events referenced by
+            // address, which were created before the deletion.
+            for (kind, d_tag) in e.deleted_addresses() {
+                let mut builder =
+                    QueryBuilder::new("UPDATE \"event\" SET hidden = 1::bit(1) WHERE kind = ");
+                builder
+                    .push_bind(kind as i64)
+                    .push(" AND pub_key = ")
+                    .push_bind(&pubkey_blob)
+                    .push(" AND created_at <= ")
+                    .push_bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                    .push(" AND id IN (SELECT t.event_id FROM tag t WHERE t.\"name\" = 'd' AND ");
+                if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
+                    builder
+                        .push("t.value_hex = ")
+                        .push_bind(hex::decode(&d_tag).ok());
+                } else {
+                    builder.push("t.value = ").push_bind(d_tag.as_bytes());
+                }
+                builder.push(")");
+                let update_count = builder.build().execute(&mut *tx).await?.rows_affected();
+                info!(
+                    "hid {} deleted kind {} events for author {:?}",
+                    update_count,
+                    kind,
+                    e.get_author_prefix()
+                );
+            }
         } else {
             // check if a deletion has already been recorded for this event.
             // Only relevant for non-deletion events
@@ -281,12 +343,30 @@ ON CONFLICT (id) DO NOTHING"#,
             )
             .bind(&pubkey_blob)
             .bind(&id_blob)
-            .fetch_optional(&mut tx)
+            .fetch_optional(&mut *tx)
             .await?;
 
+            // check if a deletion has already been recorded for this
+            // event's address, at or after its creation.
+            let addr_del_count = match e.address() {
+                Some(addr) => {
+                    sqlx::query(
+                        "SELECT e.id FROM \"event\" e \
+                    LEFT JOIN tag t ON e.id = t.event_id \
+                    WHERE e.pub_key = $1 AND t.\"name\" = 'a' AND e.kind = 5 AND e.created_at >= $2 AND t.value = $3 LIMIT 1",
+                    )
+                    .bind(&pubkey_blob)
+                    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                    .bind(addr.into_bytes())
+                    .fetch_optional(&mut *tx)
+                    .await?
+                }
+                None => None,
+            };
+
             // check if a the query returned a result, meaning we should
             // hid the current event
-            if del_count.is_some() {
+            if del_count.is_some() || addr_del_count.is_some() {
                 // a deletion already existed, mark original event as hidden.
                 info!(
                     "hid event: {:?} due to existing deletion by author: {:?}",
@@ -295,7 +375,7 @@ ON CONFLICT (id) DO NOTHING"#,
                 );
                 sqlx::query("UPDATE \"event\" SET hidden = 1::bit(1) WHERE id = $1")
                     .bind(&id_blob)
-                    .execute(&mut tx)
+                    .execute(&mut *tx)
                     .await?;
                 // event was deleted, so let caller know nothing new
                 // arrived, preventing this from being sent to active
@@ -303,6 +383,252 @@ ON CONFLICT (id) DO NOTHING"#,
                 ins_count = 0;
             }
         }
+        Ok(ins_count)
+    }
+}
+
+/// Cleanup expired events on a regular basis
+async fn cleanup_expired(conn: PostgresPool, frequency: Duration) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    let start = Instant::now();
+                    let exp_res = delete_expired(conn.clone()).await;
+                    match exp_res {
+                        Ok(exp_count) => {
+                            if exp_count > 0 {
+                                info!("removed {} expired events in: {:?}", exp_count, start.elapsed());
+                            }
+                        },
+                        Err(e) => {
+                            warn!("could not remove expired events due to error: {:?}", e);
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Rebroadcast events announced by other relays sharing the database
+async fn listen_for_events(
+    conn: PostgresPool,
+    instance_id: String,
+    bcast_tx: broadcast::Sender<BroadcastEvent>,
+    indexed_tags: Vec<String>,
+) -> Result<()> {
+    let mut listener = PgListener::connect_with(&conn).await?;
+    listener.listen(EVENT_NOTIFY_CHANNEL).await?;
+    info!("listening for events from other relays");
+    tokio::task::spawn(async move {
+        loop {
+            let notification = match listener.recv().await {
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("could not receive event notification: {:?}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let Some((origin, id)) = notification.payload().split_once(':') else {
+                continue;
+            };
+            // our own writes were already broadcast by the writer.
+            if origin == instance_id {
+                continue;
+            }
+            match fetch_event(&conn, id, &indexed_tags).await {
+                Ok(Some(event)) => {
+                    trace!("broadcasting event from another relay: {:?}", id);
+                    bcast_tx.send(BroadcastEvent::from(event)).ok();
+                }
+                Ok(None) => {
+                    debug!("announced event was not found: {:?}", id);
+                }
+                Err(e) => {
+                    warn!("could not fetch announced event: {:?}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Retrieve a visible event by id
+async fn fetch_event(
+    conn: &PostgresPool,
+    id: &str,
+    indexed_tags: &[String],
+) -> Result<Option<Event>> {
+    let Ok(id_blob) = hex::decode(id) else {
+        return Ok(None);
+    };
+    let row =
+        sqlx::query("SELECT \"content\" FROM \"event\" WHERE id = $1 AND hidden != 1::bit(1)")
+            .bind(id_blob)
+            .fetch_optional(conn)
+            .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let event_json: Vec<u8> = row.get(0);
+    let mut event: Event = serde_json::from_slice(&event_json)?;
+    event.build_index(indexed_tags);
+    event.update_delegation();
+    Ok(Some(event))
+}
+
+/// One-time deletion of all expired events
+async fn delete_expired(conn: PostgresPool) -> Result<u64> {
+    let mut tx = conn.begin().await?;
+    let update_count = sqlx::query("DELETE FROM \"event\" WHERE expires_at <= $1;")
+        .bind(Utc.timestamp_opt(utils::unix_time() as i64, 0).unwrap())
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+    Ok(update_count)
+}
+
+/// Enforce the retention policy on a regular basis
+async fn cleanup_retention(
+    conn: PostgresPool,
+    frequency: Duration,
+    retention: Retention,
+    metrics: NostrMetrics,
+) -> Result<()> {
+    info!("enabling retention policy: {:?}", retention);
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    let start = Instant::now();
+                    match delete_retained(conn.clone(), &retention).await {
+                        Ok(counts) => {
+                            for (reason, count) in counts {
+                                if count > 0 {
+                                    metrics.retention_deletes.with_label_values(&[reason]).inc_by(count);
+                                    info!("removed {} events exceeding retention {} limit in: {:?}", count, reason, start.elapsed());
+                                }
+                            }
+                        },
+                        Err(e) => {
+                            warn!("could not enforce retention due to error: {:?}", e);
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Add a clause excluding whitelisted authors from retention deletes
+fn push_retention_whitelist(query: &mut QueryBuilder<Postgres>, whitelist: &[Vec<u8>]) {
+    if !whitelist.is_empty() {
+        query.push(" AND pub_key NOT IN (");
+        let mut sep = query.separated(", ");
+        for pk in whitelist {
+            sep.push_bind(pk.clone());
+        }
+        query.push(")");
+    }
+}
+
+/// Delete events that exceed the retention policy, oldest first.
+///
+/// Table files do not shrink until they are vacuumed, so the size
+/// limit is measured against the stored event content rather than
+/// the size of the database on disk.
+async fn delete_retained(
+    conn: PostgresPool,
+    retention: &Retention,
+) -> Result<Vec<(&'static str, u64)>> {
+    let whitelist = retention.whitelist_blobs();
+    let mut counts = vec![];
+    let mut tx = conn.begin().await?;
+    if let Some(days) = retention.persist_days {
+        let cutoff = utils::unix_time().saturating_sub(days as u64 * 86400);
+        let mut query = QueryBuilder::new("DELETE FROM \"event\" WHERE created_at < ");
+        query.push_bind(Utc.timestamp_opt(cutoff as i64, 0).unwrap());
+        push_retention_whitelist(&mut query, &whitelist);
+        let count = query.build().execute(&mut tx).await?.rows_affected();
+        counts.push(("age", count));
+    }
+    if let Some(max_events) = retention.max_events {
+        let total: i64 = sqlx::query_scalar("SELECT count(*) FROM \"event\";")
+            .fetch_one(&mut tx)
+            .await?;
+        if total > max_events as i64 {
+            let mut query = QueryBuilder::new(
+                "DELETE FROM \"event\" WHERE id IN (SELECT id FROM \"event\" WHERE true",
+            );
+            push_retention_whitelist(&mut query, &whitelist);
+            query
+                .push(" ORDER BY created_at ASC LIMIT ")
+                .push_bind(total - max_events as i64)
+                .push(")");
+            let count = query.build().execute(&mut tx).await?.rows_affected();
+            counts.push(("count", count));
+        }
+    }
+    if let Some(max_bytes) = retention.max_bytes {
+        let content_bytes: i64 = sqlx::query_scalar(
+            "SELECT coalesce(sum(octet_length(content)), 0)::bigint FROM \"event\";",
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        if content_bytes > max_bytes as i64 {
+            let mut query = QueryBuilder::new(
+                "DELETE FROM \"event\" WHERE id IN (SELECT id FROM (SELECT id, sum(octet_length(content)) OVER (ORDER BY created_at ASC, id ASC) - octet_length(content) AS preceding FROM \"event\" WHERE true",
+            );
+            push_retention_whitelist(&mut query, &whitelist);
+            query
+                .push(") r WHERE preceding < ")
+                .push_bind(content_bytes - max_bytes as i64)
+                .push(")");
+            let count = query.build().execute(&mut tx).await?.rows_affected();
+            counts.push(("size", count));
+        }
+    }
+    tx.commit().await?;
+    Ok(counts)
+}
+
+#[async_trait]
+impl NostrRepo for PostgresRepo {
+    async fn start(&self) -> Result<()> {
+        // begin a cleanup task for expired events.
+        cleanup_expired(self.conn_write.clone(), Duration::from_secs(600)).await?;
+        // begin a task to enforce the retention policy, if any.
+        if self.retention.is_active() {
+            cleanup_retention(
+                self.conn_write.clone(),
+                Duration::from_secs(600),
+                self.retention.clone(),
+                self.metrics.clone(),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        let version = run_migrations(&self.conn_write).await?;
+        reindex_tag_names(&self.conn_write, &self.indexed_tags).await?;
+        Ok(version)
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<u64> {
+        // start transaction
+        let mut tx = self.conn_write.begin().await?;
+        let start = Instant::now();
+        let ins_count = self.persist_event(&mut tx, e).await?;
+        if self.notify_events && ins_count > 0 {
+            self.notify_event(&mut tx, e).await?;
+        }
         tx.commit().await?;
         self.metrics
             .write_events
@@ -310,6 +636,50 @@ ON CONFLICT (id) DO NOTHING"#,
         Ok(ins_count)
     }
 
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<Result<u64>>> {
+        let mut tx = self.conn_write.begin().await?;
+        let start = Instant::now();
+        let mut results = Vec::with_capacity(events.len());
+        for e in events {
+            // each event is written under its own savepoint, so one
+            // that fails is rolled back without affecting the others.
+            let mut sp = tx.begin().await?;
+            let res = self.persist_event(&mut sp, e).await;
+            if res.is_ok() {
+                sp.commit().await?;
+            } else {
+                sp.rollback().await?;
+            }
+            if self.notify_events && matches!(res, Ok(n) if n > 0) {
+                self.notify_event(&mut tx, e).await?;
+            }
+            results.push(res);
+        }
+        tx.commit().await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        Ok(results)
+    }
+
+    async fn broadcast_remote_writes(
+        &self,
+        bcast_tx: broadcast::Sender<BroadcastEvent>,
+    ) -> Result<()> {
+        if !self.notify_events {
+            return Ok(());
+        }
+        // announcements come from the primary, so read the events
+        // back from it rather than a replica that may lag behind.
+        listen_for_events(
+            self.conn_write.clone(),
+            self.instance_id.clone(),
+            bcast_tx,
+            self.indexed_tags.clone(),
+        )
+        .await
+    }
+
     async fn query_subscription(
         &self,
         sub: Subscription,
@@ -406,6 +776,16 @@ ON CONFLICT (id) DO NOTHING"#,
                                 .query_aborts
                                 .with_label_values(&["slowclient"])
                                 .inc();
+                            // the queue is full, so don't wait for
+                            // room before returning.
+                            let closed = QueryResult::closed(
+                                sub.get_id(),
+                                EventResultStatus::Error,
+                                "client is not reading results fast enough",
+                                abandon_query_rx,
+                            );
+                            let tx = query_tx.clone();
+                            tokio::spawn(async move { tx.send(closed).await.ok() });
                             return Ok(());
                         }
                         // give the queue a chance to clear before trying again
@@ -420,6 +800,7 @@ ON CONFLICT (id) DO NOTHING"#,
                     .send(QueryResult {
                         sub_id: sub.get_id(),
                         event: String::from_utf8(event_json).unwrap(),
+                        closed: None,
                     })
                     .await
                     .ok();
@@ -430,6 +811,7 @@ ON CONFLICT (id) DO NOTHING"#,
             .send(QueryResult {
                 sub_id: sub.get_id(),
                 event: "EOSE".to_string(),
+                closed: None,
             })
             .await
             .ok();
@@ -447,6 +829,73 @@ ON CONFLICT (id) DO NOTHING"#,
         Ok(())
     }
 
+    async fn count_subscription(&self, sub: Subscription, client_id: String) -> Result<u64> {
+        let start = Instant::now();
+        let count: i64 = match count_query_from_sub(&sub) {
+            Some(mut q) => q.build().fetch_one(&self.conn).await?.get(0),
+            None => 0,
+        };
+        self.metrics
+            .query_sub
+            .observe(start.elapsed().as_secs_f64());
+        debug!(
+            "count completed in {:?} (cid: {}, sub: {:?}, count: {})",
+            start.elapsed(),
+            client_id,
+            sub.id,
+            count
+        );
+        Ok(count as u64)
+    }
+
+    async fn query_ids(
+        &self,
+        filter: ReqFilter,
+        client_id: String,
+        ids_tx: Sender<(u64, [u8; 32])>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        if filter.force_no_match {
+            return Ok(());
+        }
+        let mut query = QueryBuilder::new("SELECT e.id, e.created_at FROM \"event\" e WHERE ");
+        if push_filter_conditions(&mut query, &filter).is_none() {
+            return Ok(());
+        }
+        if let Some(lim) = filter.limit {
+            query.push(" ORDER BY e.created_at DESC LIMIT ");
+            query.push(lim);
+        }
+        let mut row_count: usize = 0;
+        let mut results = query.build().fetch(&self.conn);
+        while let Some(row) = results.next().await {
+            let row = row?;
+            let created_at: DateTime<Utc> = row.get(1);
+            let Ok(id) = <[u8; 32]>::try_from(row.get::<Vec<u8>, _>(0)) else {
+                continue;
+            };
+            if ids_tx
+                .send((created_at.timestamp() as u64, id))
+                .await
+                .is_err()
+            {
+                // the receiver has what it needs
+                break;
+            }
+            row_count += 1;
+        }
+        self.metrics
+            .query_sub
+            .observe(start.elapsed().as_secs_f64());
+        debug!(
+            "id query completed in {:?} (cid: {}, rows: {})",
+            start.elapsed(),
+            client_id,
+            row_count
+        );
+        Ok(())
+    }
+
     async fn optimize_db(&self) -> Result<()> {
         // Not implemented
         Ok(())
@@ -546,17 +995,360 @@ ON CONFLICT (id) DO NOTHING"#,
             .await?
             .ok_or(error::Error::SqlxError(RowNotFound))
     }
+
+    async fn get_moderation(&self) -> Result<Moderation> {
+        let mut m = Moderation::default();
+        let rows = sqlx::query("SELECT pub_key, allowed, reason FROM pubkey_policy")
+            .fetch_all(&self.conn)
+            .await?;
+        for r in rows {
+            let pubkey = hex::encode(r.get::<Vec<u8>, _>(0));
+            if r.get(1) {
+                m.allowed_pubkeys.insert(pubkey, r.get(2));
+            } else {
+                m.banned_pubkeys.insert(pubkey, r.get(2));
+            }
+        }
+        let rows = sqlx::query("SELECT id, reason FROM banned_event")
+            .fetch_all(&self.conn)
+            .await?;
+        for r in rows {
+            m.banned_events
+                .insert(hex::encode(r.get::<Vec<u8>, _>(0)), r.get(1));
+        }
+        let rows = sqlx::query("SELECT kind, allowed FROM kind_policy")
+            .fetch_all(&self.conn)
+            .await?;
+        for r in rows {
+            let kind = r.get::<i32, _>(0) as u64;
+            if r.get(1) {
+                m.allowed_kinds.insert(kind);
+            } else {
+                m.disallowed_kinds.insert(kind);
+            }
+        }
+        let rows = sqlx::query("SELECT ip, reason FROM blocked_ip")
+            .fetch_all(&self.conn)
+            .await?;
+        for r in rows {
+            m.blocked_ips.insert(r.get(0), r.get(1));
+        }
+        let rows = sqlx::query("SELECT \"name\", value FROM relay_info")
+            .fetch_all(&self.conn)
+            .await?;
+        for r in rows {
+            let value = Some(r.get(1));
+            match r.get::<String, _>(0).as_str() {
+                "name" => m.relay_name = value,
+                "description" => m.relay_description = value,
+                "icon" => m.relay_icon = value,
+                _ => {}
+            }
+        }
+        Ok(m)
+    }
+
+    async fn set_pubkey_policy(
+        &self,
+        pubkey: &str,
+        allowed: bool,
+        reason: Option<String>,
+    ) -> Result<()> {
+        sqlx::query("INSERT INTO pubkey_policy (pub_key, allowed, reason) VALUES ($1, $2, $3) ON CONFLICT (pub_key) DO UPDATE SET allowed = $2, reason = $3, created_at = now()")
+            .bind(hex::decode(pubkey)?)
+            .bind(allowed)
+            .bind(reason)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_pubkey_policy(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        sqlx::query("DELETE FROM pubkey_policy WHERE pub_key = $1 AND allowed = $2")
+            .bind(hex::decode(pubkey)?)
+            .bind(allowed)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_event_banned(&self, id: &str, banned: bool, reason: Option<String>) -> Result<()> {
+        let id = hex::decode(id)?;
+        let mut tx = self.conn_write.begin().await?;
+        if banned {
+            sqlx::query("INSERT INTO banned_event (id, reason) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET reason = $2, created_at = now()")
+                .bind(&id)
+                .bind(reason)
+                .execute(&mut tx)
+                .await?;
+            sqlx::query("DELETE FROM \"event\" WHERE id = $1")
+                .bind(&id)
+                .execute(&mut tx)
+                .await?;
+        } else {
+            sqlx::query("DELETE FROM banned_event WHERE id = $1")
+                .bind(&id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_kind_policy(&self, kind: u64, allowed: bool) -> Result<()> {
+        sqlx::query("INSERT INTO kind_policy (kind, allowed) VALUES ($1, $2) ON CONFLICT (kind) DO UPDATE SET allowed = $2, created_at = now()")
+            .bind(kind as i64)
+            .bind(allowed)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_kind_policy(&self, kind: u64, allowed: bool) -> Result<()> {
+        sqlx::query("DELETE FROM kind_policy WHERE kind = $1 AND allowed = $2")
+            .bind(kind as i64)
+            .bind(allowed)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_ip_blocked(&self, ip: &str, blocked: bool, reason: Option<String>) -> Result<()> {
+        if blocked {
+            sqlx::query("INSERT INTO blocked_ip (ip, reason) VALUES ($1, $2) ON CONFLICT (ip) DO UPDATE SET reason = $2, created_at = now()")
+                .bind(ip)
+                .bind(reason)
+                .execute(&self.conn_write)
+                .await?;
+        } else {
+            sqlx::query("DELETE FROM blocked_ip WHERE ip = $1")
+                .bind(ip)
+                .execute(&self.conn_write)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn set_relay_info(&self, field: &str, value: &str) -> Result<()> {
+        sqlx::query("INSERT INTO relay_info (\"name\", value) VALUES ($1, $2) ON CONFLICT (\"name\") DO UPDATE SET value = $2")
+            .bind(field)
+            .bind(value)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_groups(&self) -> Result<GroupState> {
+        let mut state = GroupState::default();
+        let rows = sqlx::query(
+            "SELECT group_id, \"name\", about, picture, private, closed FROM group_info",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        for r in rows {
+            let group = Group {
+                id: r.get(0),
+                name: r.get(1),
+                about: r.get(2),
+                picture: r.get(3),
+                private: r.get(4),
+                closed: r.get(5),
+                ..Default::default()
+            };
+            state.groups.insert(group.id.clone(), group);
+        }
+        let rows = sqlx::query("SELECT group_id, pub_key, roles FROM group_member")
+            .fetch_all(&self.conn)
+            .await?;
+        for r in rows {
+            if let Some(g) = state.groups.get_mut(&r.get::<String, _>(0)) {
+                g.members.insert(
+                    hex::encode(r.get::<Vec<u8>, _>(1)),
+                    serde_json::from_str(r.get(2)).unwrap_or_default(),
+                );
+            }
+        }
+        let rows = sqlx::query("SELECT group_id, code FROM group_invite")
+            .fetch_all(&self.conn)
+            .await?;
+        for r in rows {
+            if let Some(g) = state.groups.get_mut(&r.get::<String, _>(0)) {
+                g.invites.insert(r.get(1));
+            }
+        }
+        Ok(state)
+    }
+
+    async fn save_group(&self, group: &Group) -> Result<()> {
+        let mut tx = self.conn_write.begin().await?;
+        sqlx::query("INSERT INTO group_info (group_id, \"name\", about, picture, private, closed) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (group_id) DO UPDATE SET \"name\" = $2, about = $3, picture = $4, private = $5, closed = $6")
+            .bind(&group.id)
+            .bind(&group.name)
+            .bind(&group.about)
+            .bind(&group.picture)
+            .bind(group.private)
+            .bind(group.closed)
+            .execute(&mut tx)
+            .await?;
+        for table in ["group_member", "group_invite"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE group_id = $1"))
+                .bind(&group.id)
+                .execute(&mut tx)
+                .await?;
+        }
+        for (pubkey, roles) in &group.members {
+            sqlx::query("INSERT INTO group_member (group_id, pub_key, roles) VALUES ($1, $2, $3)")
+                .bind(&group.id)
+                .bind(hex::decode(pubkey)?)
+                .bind(serde_json::to_string(roles)?)
+                .execute(&mut tx)
+                .await?;
+        }
+        for code in &group.invites {
+            sqlx::query("INSERT INTO group_invite (group_id, code) VALUES ($1, $2)")
+                .bind(&group.id)
+                .bind(code)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_group(&self, group_id: &str) -> Result<()> {
+        // members and invites are removed by cascade
+        sqlx::query("DELETE FROM group_info WHERE group_id = $1")
+            .bind(group_id)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_group_events(&self, group_id: &str, ids: &[String]) -> Result<u64> {
+        let ids: Vec<Vec<u8>> = ids.iter().filter_map(|id| hex::decode(id).ok()).collect();
+        // hex tag values are stored decoded
+        let count = sqlx::query("DELETE FROM \"event\" WHERE id = ANY($1) AND id IN (SELECT event_id FROM tag WHERE \"name\" = 'h' AND (value = $2 OR value_hex = $3))")
+            .bind(ids)
+            .bind(group_id.as_bytes())
+            .bind(hex::decode(group_id).ok())
+            .execute(&self.conn_write)
+            .await?
+            .rows_affected();
+        Ok(count)
+    }
+
+    async fn get_local_names(&self) -> Result<BTreeMap<String, String>> {
+        let rows = sqlx::query("SELECT \"name\", pub_key FROM local_name")
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|r| (r.get(0), hex::encode(r.get::<Vec<u8>, _>(1))))
+            .collect())
+    }
+
+    async fn get_local_name(&self, name: &str) -> Result<Option<String>> {
+        let row = sqlx::query("SELECT pub_key FROM local_name WHERE \"name\" = $1")
+            .bind(name)
+            .fetch_optional(&self.conn)
+            .await?;
+        Ok(row.map(|r| hex::encode(r.get::<Vec<u8>, _>(0))))
+    }
+
+    async fn set_local_name(&self, name: &str, pubkey: &str) -> Result<()> {
+        sqlx::query("INSERT INTO local_name (\"name\", pub_key) VALUES ($1, $2) ON CONFLICT (\"name\") DO UPDATE SET pub_key = EXCLUDED.pub_key")
+            .bind(name)
+            .bind(hex::decode(pubkey)?)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_local_name(&self, name: &str) -> Result<bool> {
+        let count = sqlx::query("DELETE FROM local_name WHERE \"name\" = $1")
+            .bind(name)
+            .execute(&self.conn_write)
+            .await?
+            .rows_affected();
+        Ok(count > 0)
+    }
 }
 
 /// Create a dynamic SQL query and params from a subscription filter.
-fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
+fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<'_, Postgres>> {
     // if the filter is malformed, don't return anything.
     if f.force_no_match {
         return None;
     }
 
     let mut query = QueryBuilder::new("SELECT e.\"content\", e.created_at FROM \"event\" e WHERE ");
+    push_filter_conditions(&mut query, f)?;
+
+    // Apply per-filter limit to this query.
+    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
+    if let Some(lim) = f.limit {
+        query.push(" ORDER BY e.created_at DESC LIMIT ");
+        query.push(lim.min(1000));
+    } else {
+        query.push(" ORDER BY e.created_at ASC LIMIT ");
+        query.push(1000);
+    }
+    Some(query)
+}
 
+/// Create a dynamic SQL query counting the distinct events matched by
+/// any filter in a subscription.  Limits are not applied.
+fn count_query_from_sub(sub: &Subscription) -> Option<QueryBuilder<'_, Postgres>> {
+    // skip filters that can never match an event
+    let filters: Vec<&ReqFilter> = sub
+        .filters
+        .iter()
+        .filter(|f| query_from_filter(f).is_some())
+        .collect();
+    if filters.is_empty() {
+        return None;
+    }
+    let mut query = QueryBuilder::new("SELECT COUNT(*) FROM (");
+    for (i, f) in filters.into_iter().enumerate() {
+        if i > 0 {
+            query.push(" UNION ");
+        }
+        query.push("SELECT e.id FROM \"event\" e WHERE ");
+        push_filter_conditions(&mut query, f)?;
+    }
+    query.push(") c");
+    Some(query)
+}
+
+/// Add the conditions of a subscription filter to a query, returning
+/// `None` if the filter cannot match any events.
+/// Push a clause matching events with a tag of the given name, and
+/// any of the given values.
+fn push_tag_clause<'a>(
+    query: &mut QueryBuilder<'a, Postgres>,
+    key: &str,
+    val: impl Iterator<Item = &'a String>,
+) {
+    query.push("e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = ")
+        .push_bind(key.to_string())
+        .push(" AND (value in (");
+
+    // plain value match first
+    let mut tag_query = query.separated(", ");
+    for v in val {
+        if (v.len() % 2 != 0) && !is_lower_hex(v) {
+            tag_query.push_bind(v.as_bytes());
+        } else {
+            tag_query.push_bind(hex::decode(v).ok());
+        }
+    }
+    query.push("))))");
+}
+
+fn push_filter_conditions<'a>(
+    query: &mut QueryBuilder<'a, Postgres>,
+    f: &'a ReqFilter,
+) -> Option<()> {
     // This tracks whether we need to push a prefix AND before adding another clause
     let mut push_and = false;
     // Query for "authors", allowing prefix matches
@@ -714,31 +1506,104 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
             }
             push_and = true;
 
-            for (key, val) in map.iter() {
+            for (i, (key, val)) in map.iter().enumerate() {
                 if val.len() > 256 {
                     // abort query if too many tag search
-                    return None
+                    return None;
                 }
-                query.push("e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = ")
-                    .push_bind(key.to_string())
-                    .push(" AND (value in (");
-
-                // plain value match first
-                let mut tag_query = query.separated(", ");
-                for v in val.iter() {
-                    if (v.len() % 2 != 0) && !is_lower_hex(v) {
-                        tag_query.push_bind(v.as_bytes());
-                    } else {
-                        tag_query.push_bind(hex::decode(v).ok());
-                    }
+                if i > 0 {
+                    query.push(" AND ");
                 }
-                query.push("))))");
+                push_tag_clause(query, key, val.iter());
             }
         } else {
             return None;
         }
     }
 
+    // Query for tags that must have every value; each value is
+    // found separately, so the results intersect.
+    if let Some(map) = &f.all_tags {
+        if map.values().map(HashSet::len).sum::<usize>() > 256 {
+            // abort query if too many tag search
+            return None;
+        }
+        for (key, val) in map.iter() {
+            for v in val {
+                if push_and {
+                    query.push(" AND ");
+                }
+                push_and = true;
+                push_tag_clause(query, key, std::iter::once(v));
+            }
+        }
+    }
+
+    // Query for full-text search
+    let search_terms = f.search_terms();
+    if !search_terms.is_empty() {
+        if push_and {
+            query.push(" AND ");
+        }
+        push_and = true;
+        query
+            .push("e.search_vector @@ plainto_tsquery('simple', ")
+            .push_bind(search_terms.join(" "))
+            .push(")");
+    }
+
+    // Query for private kinds, which only participants may read
+    if let Some(readers) = &f.private_readers {
+        if push_and {
+            query.push(" AND ");
+        }
+        push_and = true;
+        query.push("(e.kind NOT IN (");
+        let mut kind_sep = query.separated(", ");
+        for k in PRIVATE_KINDS {
+            kind_sep.push_bind(k as i64);
+        }
+        query.push(")");
+        let readers: Vec<Vec<u8>> = readers
+            .iter()
+            .filter(|r| r.len() == 64 && is_lower_hex(r))
+            .filter_map(|r| hex::decode(r).ok())
+            .collect();
+        if !readers.is_empty() {
+            query.push(" OR e.pub_key IN (");
+            let mut author_sep = query.separated(", ");
+            for r in &readers {
+                author_sep.push_bind(r.clone());
+            }
+            query.push(") OR e.id IN (SELECT t.event_id FROM tag t WHERE t.\"name\" = 'p' AND t.value_hex IN (");
+            let mut tag_sep = query.separated(", ");
+            for r in readers {
+                tag_sep.push_bind(r);
+            }
+            query.push("))");
+        }
+        query.push(")");
+    }
+
+    // Query for private groups, which the reader may not read; hex
+    // tag values are stored decoded.
+    if let Some(groups) = f.hidden_groups.as_ref().filter(|g| !g.is_empty()) {
+        if push_and {
+            query.push(" AND ");
+        }
+        push_and = true;
+        query.push("e.id NOT IN (SELECT t.event_id FROM tag t WHERE t.\"name\" = 'h' AND (");
+        let mut group_sep = query.separated(" OR ");
+        for g in groups {
+            group_sep
+                .push("t.value = ")
+                .push_bind_unseparated(g.as_bytes())
+                .push_unseparated(" OR t.value_hex = ")
+                .push_bind_unseparated(hex::decode(g).ok());
+        }
+        query.push("))");
+    }
+
     // Query for timestamp
     if f.since.is_some() {
         if push_and {
@@ -772,17 +1637,7 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
         .push(" AND (e.expires_at IS NULL OR e.expires_at > ")
         .push_bind(Utc.timestamp_opt(utils::unix_time() as i64, 0).unwrap())
         .push(")");
-
-    // Apply per-filter limit to this query.
-    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        query.push(" ORDER BY e.created_at DESC LIMIT ");
-        query.push(lim.min(1000));
-    } else {
-        query.push(" ORDER BY e.created_at ASC LIMIT ");
-        query.push(1000);
-    }
-    Some(query)
+    Some(())
 }
 
 impl FromRow<'_, PgRow> for VerificationRecord {
@@ -1,6 +1,8 @@
+use crate::event::single_char_tagname;
 use crate::repo::postgres::PostgresPool;
 use async_trait::async_trait;
 use sqlx::{Executor, Postgres, Transaction};
+use tracing::info;
 
 #[async_trait]
 pub trait Migration {
@@ -26,6 +28,53 @@ impl Migration for SimpleSqlMigration {
     }
 }
 
+/// Bring the tag index in line with the configured multi-character
+/// tag names.  Stored events are indexed for newly added names, and
+/// the index is dropped for names that were removed.
+pub async fn reindex_tag_names(db: &PostgresPool, names: &[String]) -> crate::error::Result<()> {
+    let indexed: Vec<String> = sqlx::query_scalar("SELECT \"name\" FROM indexed_tag")
+        .fetch_all(db)
+        .await?;
+    let mut tx = db.begin().await?;
+    for name in indexed.iter().filter(|n| !names.contains(n)) {
+        info!("removing tag index for {:?}", name);
+        sqlx::query("DELETE FROM tag WHERE \"name\" = $1")
+            .bind(name)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM indexed_tag WHERE \"name\" = $1")
+            .bind(name)
+            .execute(&mut tx)
+            .await?;
+    }
+    for name in names {
+        if indexed.contains(name) || single_char_tagname(name).is_some() {
+            continue;
+        }
+        // lowercase hex values are stored decoded, as for new events
+        let count = sqlx::query(
+            "INSERT INTO tag (event_id, \"name\", value, value_hex) \
+             SELECT e.id, $1, \
+             CASE WHEN t->>1 ~ '^([0-9a-f]{2})*$' THEN NULL ELSE convert_to(t->>1, 'UTF8') END, \
+             CASE WHEN t->>1 ~ '^([0-9a-f]{2})*$' THEN decode(t->>1, 'hex') END \
+             FROM \"event\" e, jsonb_array_elements(convert_from(e.\"content\", 'UTF8')::jsonb -> 'tags') t \
+             WHERE t->>0 = $1 AND jsonb_array_length(t) >= 2 \
+             ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING",
+        )
+        .bind(name)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        sqlx::query("INSERT INTO indexed_tag (\"name\") VALUES ($1)")
+            .bind(name)
+            .execute(&mut tx)
+            .await?;
+        info!("indexed {} tags for {:?}", count, name);
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
 /// Execute all migrations on the database.
 pub async fn run_migrations(db: &PostgresPool) -> crate::error::Result<usize> {
     prepare_migrations_table(db).await;
@@ -36,6 +85,12 @@ pub async fn run_migrations(db: &PostgresPool) -> crate::error::Result<usize> {
     }
     run_migration(m003::migration(), db).await;
     run_migration(m004::migration(), db).await;
+    run_migration(m005::migration(), db).await;
+    run_migration(m006::migration(), db).await;
+    run_migration(m007::migration(), db).await;
+    run_migration(m008::migration(), db).await;
+    run_migration(m009::migration(), db).await;
+    run_migration(m010::migration(), db).await;
     Ok(current_version(db).await as usize)
 }
 
@@ -277,3 +332,176 @@ CREATE INDEX event_expires_at_idx ON "event" (expires_at);
         }
     }
 }
+
+mod m005 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 5;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Add full-text search vector for event content (NIP-50)
+ALTER TABLE event ADD COLUMN search_vector tsvector;
+-- Index search vector
+CREATE INDEX event_search_vector_idx ON "event" USING GIN (search_vector);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m006 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 6;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- NIP-86 relay management
+CREATE TABLE "pubkey_policy" (
+	pub_key bytea NOT NULL,
+	allowed bool NOT NULL,
+	reason varchar NULL,
+	created_at timestamp with time zone NOT NULL DEFAULT now(),
+	CONSTRAINT pubkey_policy_pkey PRIMARY KEY (pub_key)
+);
+CREATE TABLE "banned_event" (
+	id bytea NOT NULL,
+	reason varchar NULL,
+	created_at timestamp with time zone NOT NULL DEFAULT now(),
+	CONSTRAINT banned_event_pkey PRIMARY KEY (id)
+);
+CREATE TABLE "kind_policy" (
+	kind integer NOT NULL,
+	allowed bool NOT NULL,
+	created_at timestamp with time zone NOT NULL DEFAULT now(),
+	CONSTRAINT kind_policy_pkey PRIMARY KEY (kind)
+);
+CREATE TABLE "blocked_ip" (
+	ip varchar NOT NULL,
+	reason varchar NULL,
+	created_at timestamp with time zone NOT NULL DEFAULT now(),
+	CONSTRAINT blocked_ip_pkey PRIMARY KEY (ip)
+);
+CREATE TABLE "relay_info" (
+	"name" varchar NOT NULL,
+	value varchar NOT NULL,
+	CONSTRAINT relay_info_pkey PRIMARY KEY ("name")
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m007 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 7;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- NIP-62 requests to vanish
+CREATE TABLE "vanish_request" (
+	pub_key bytea NOT NULL,
+	created_at timestamp with time zone NOT NULL,
+	CONSTRAINT vanish_request_pkey PRIMARY KEY (pub_key)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m008 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 8;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- NIP-29 relay-based groups
+CREATE TABLE "group_info" (
+	group_id varchar NOT NULL,
+	"name" varchar NULL,
+	about varchar NULL,
+	picture varchar NULL,
+	private bool NOT NULL DEFAULT false,
+	closed bool NOT NULL DEFAULT false,
+	created_at timestamp with time zone NOT NULL DEFAULT now(),
+	CONSTRAINT group_info_pkey PRIMARY KEY (group_id)
+);
+CREATE TABLE "group_member" (
+	group_id varchar NOT NULL,
+	pub_key bytea NOT NULL,
+	roles varchar NOT NULL,
+	CONSTRAINT group_member_pkey PRIMARY KEY (group_id, pub_key),
+	CONSTRAINT group_member_fk FOREIGN KEY (group_id) REFERENCES "group_info"(group_id) ON DELETE CASCADE
+);
+CREATE TABLE "group_invite" (
+	group_id varchar NOT NULL,
+	code varchar NOT NULL,
+	CONSTRAINT group_invite_pkey PRIMARY KEY (group_id, code),
+	CONSTRAINT group_invite_fk FOREIGN KEY (group_id) REFERENCES "group_info"(group_id) ON DELETE CASCADE
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m009 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 9;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- NIP-05 names registered with this relay
+CREATE TABLE "local_name" (
+	"name" varchar NOT NULL,
+	pub_key bytea NOT NULL,
+	created_at timestamp with time zone NOT NULL DEFAULT now(),
+	CONSTRAINT local_name_pkey PRIMARY KEY ("name")
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m010 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 10;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Multi-character tag names indexed in the tag table
+CREATE TABLE "indexed_tag" (
+	"name" varchar NOT NULL,
+	CONSTRAINT indexed_tag_pkey PRIMARY KEY ("name")
+);
+        "#,
+            ],
+        }
+    }
+}
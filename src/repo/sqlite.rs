@@ -1,16 +1,19 @@
 //! Event persistence and querying
 //use crate::config::SETTINGS;
-use crate::config::Settings;
+use crate::config::{Retention, Settings};
 use crate::db::QueryResult;
-use crate::error::{Error::SqlError, Result};
-use crate::event::{single_char_tagname, Event};
+use crate::error::{Error, Error::SqlError, Result};
+use crate::event::{is_indexed_tagname, BroadcastEvent, Event, PRIVATE_KINDS};
 use crate::hexrange::hex_range;
 use crate::hexrange::HexSearch;
 use crate::nip05::{Nip05Name, VerificationRecord};
-use crate::repo::sqlite_migration::{upgrade_db, STARTUP_SQL};
+use crate::nip29::{Group, GroupState};
+use crate::nip86::Moderation;
+use crate::notice::EventResultStatus;
+use crate::repo::sqlite_migration::{reindex_tag_names, upgrade_db, STARTUP_SQL};
 use crate::server::NostrMetrics;
 use crate::subscription::{ReqFilter, Subscription};
-use crate::utils::{is_hex, unix_time};
+use crate::utils::{is_hex, is_lower_hex, unix_time};
 use async_trait::async_trait;
 use hex;
 use r2d2;
@@ -18,13 +21,15 @@ use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::params;
 use rusqlite::types::ToSql;
 use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
+use std::collections::BTreeMap;
 use std::fmt::Write as _;
 use std::path::Path;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
-use tokio::sync::{Mutex, MutexGuard, Semaphore};
+use tokio::sync::{broadcast, Mutex, MutexGuard, Semaphore};
 use tokio::task;
 use tracing::{debug, info, trace, warn};
 
@@ -34,6 +39,11 @@ pub type SqlitePool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 pub type PooledConnection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 pub const DB_FILE: &str = "nostr.db";
 
+/// Reason given to clients whose query was stopped for a checkpoint
+const CHECKPOINT_ABORT_MSG: &str = "query interrupted by database maintenance, try again";
+/// Reason given to clients that were not keeping up with query results
+const SLOW_CLIENT_ABORT_MSG: &str = "client is not reading results fast enough";
+
 #[derive(Clone)]
 pub struct SqliteRepo {
     /// Metrics
@@ -50,6 +60,14 @@ pub struct SqliteRepo {
     write_in_progress: Arc<Mutex<u64>>,
     /// Semaphore for readers to acquire blocking threads
     reader_threads_ready: Arc<Semaphore>,
+    /// Retention policy for stored events
+    retention: Retention,
+    /// Event kinds indexed for full-text search
+    search_kinds: Vec<u64>,
+    /// Multi-character tag names indexed for search
+    indexed_tags: Vec<String>,
+    /// Advertised relay URL, for matching vanish requests
+    relay_url: Option<String>,
 }
 
 impl SqliteRepo {
@@ -98,22 +116,133 @@ impl SqliteRepo {
             checkpoint_in_progress,
             write_in_progress,
             reader_threads_ready,
+            retention: settings.retention.clone(),
+            search_kinds: settings.options.searchable_kinds().to_vec(),
+            indexed_tags: settings.options.indexed_tag_names().to_vec(),
+            relay_url: settings.info.relay_url.clone(),
         }
     }
 
     /// Persist an event to the database, returning rows added.
-    pub fn persist_event(conn: &mut PooledConnection, e: &Event) -> Result<u64> {
+    ///
+    /// Vanish requests (NIP-62) addressed to this relay are not stored,
+    /// but are counted as added once their author's events are removed.
+    pub fn persist_event(
+        conn: &mut PooledConnection,
+        e: &Event,
+        search_kinds: &[u64],
+        indexed_tags: &[String],
+        relay_url: Option<&str>,
+    ) -> Result<u64> {
         // enable auto vacuum
         conn.execute_batch("pragma auto_vacuum = FULL")?;
 
         // start transaction
         let tx = conn.transaction()?;
+        let ins_count = Self::insert_event(&tx, e, search_kinds, indexed_tags, relay_url)?;
+        tx.commit()?;
+        Ok(ins_count)
+    }
+
+    /// Persist a batch of events in a single transaction, returning
+    /// rows added for each event.
+    ///
+    /// Each event is written under its own savepoint, so one that
+    /// fails is rolled back without affecting the others.  A busy
+    /// database fails the entire batch, so it can be retried.
+    pub fn persist_events(
+        conn: &mut PooledConnection,
+        events: &[Event],
+        search_kinds: &[u64],
+        indexed_tags: &[String],
+        relay_url: Option<&str>,
+    ) -> Result<Vec<Result<u64>>> {
+        // enable auto vacuum
+        conn.execute_batch("pragma auto_vacuum = FULL")?;
+
+        let mut tx = conn.transaction()?;
+        let mut results = Vec::with_capacity(events.len());
+        for e in events {
+            let sp = tx.savepoint()?;
+            let res = Self::insert_event(&sp, e, search_kinds, indexed_tags, relay_url);
+            match &res {
+                Ok(_) => sp.commit()?,
+                Err(SqlError(rusqlite::Error::SqliteFailure(f, _)))
+                    if matches!(
+                        f.code,
+                        rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+                    ) =>
+                {
+                    return res.map(|_| vec![]);
+                }
+                // dropping the savepoint rolls back this event
+                Err(_) => {}
+            }
+            results.push(res);
+        }
+        tx.commit()?;
+        Ok(results)
+    }
+
+    /// Insert an event within a transaction, returning rows added.
+    fn insert_event(
+        tx: &rusqlite::Connection,
+        e: &Event,
+        search_kinds: &[u64],
+        indexed_tags: &[String],
+        relay_url: Option<&str>,
+    ) -> Result<u64> {
         // get relevant fields from event and convert to blobs.
         let id_blob = hex::decode(&e.id).ok();
         let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
         let delegator_blob: Option<Vec<u8>> =
             e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
         let event_str = serde_json::to_string(&e).ok();
+        // refuse events from authors that asked to vanish at or after
+        // their creation (NIP-62).
+        let vanished = tx
+            .query_row(
+                "SELECT 1 FROM vanish_request WHERE pubkey=? AND created_at >= ?;",
+                params![pubkey_blob, e.created_at],
+                |row| row.get::<usize, usize>(0),
+            )
+            .optional()?;
+        if vanished.is_some() {
+            return Err(Error::AuthorVanished);
+        }
+        // a request to vanish removes every event from the author up
+        // to the request, and gift wraps addressed to them, and is
+        // remembered instead of stored.
+        if e.is_vanish_request(relay_url) {
+            let in_events = "SELECT id FROM event WHERE author=? AND created_at <= ?";
+            tx.execute(
+                &format!("DELETE FROM user_verification WHERE metadata_event IN ({in_events});"),
+                params![pubkey_blob, e.created_at],
+            )?;
+            tx.execute(
+                &format!("DELETE FROM tag WHERE event_id IN ({in_events});"),
+                params![pubkey_blob, e.created_at],
+            )?;
+            let mut delete_count = tx.execute(
+                "DELETE FROM event WHERE author=? AND created_at <= ?;",
+                params![pubkey_blob, e.created_at],
+            )?;
+            // tags of gift wraps are removed with them
+            delete_count += tx.execute(
+                "DELETE FROM event WHERE kind=1059 AND id IN (SELECT event_id FROM tag WHERE name='p' AND value=?);",
+                params![e.pubkey],
+            )?;
+            tx.execute(
+                "INSERT INTO vanish_request (pubkey, created_at) VALUES (?, ?) ON CONFLICT (pubkey) DO UPDATE SET created_at=excluded.created_at;",
+                params![pubkey_blob, e.created_at],
+            )?;
+            info!(
+                "removed {} events for vanished author: {:?}",
+                delete_count,
+                e.get_author_prefix()
+            );
+            return Ok(1);
+        }
         // check for replaceable events that would hide this one; we won't even attempt to insert these.
         if e.is_replaceable() {
             let repl_count = tx.query_row(
@@ -143,7 +272,6 @@ impl SqliteRepo {
         if ins_count == 0 {
             // if the event was a duplicate, no need to insert event or
             // pubkey references.
-            tx.rollback().ok();
             return Ok(ins_count);
         }
         // remember primary key of the event most recently inserted.
@@ -154,19 +282,22 @@ impl SqliteRepo {
             if tag.len() >= 2 {
                 let tagname = &tag[0];
                 let tagval = &tag[1];
-                // only single-char tags are searchable
-                let tagchar_opt = single_char_tagname(tagname);
-                match &tagchar_opt {
-                    Some(_) => {
-                        tx.execute(
-                            "INSERT OR IGNORE INTO tag (event_id, name, value, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
-                            params![ev_id, &tagname, &tagval, e.kind, e.created_at],
-                        )?;
-                    }
-                    None => {}
+                // only single-char and configured tags are searchable
+                if is_indexed_tagname(tagname, indexed_tags) {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO tag (event_id, name, value, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![ev_id, &tagname, &tagval, e.kind, e.created_at],
+                    )?;
                 }
             }
         }
+        // index the content of searchable kinds (NIP-50)
+        if search_kinds.contains(&e.kind) {
+            tx.execute(
+                "INSERT INTO event_fts (rowid, content) VALUES (?1, ?2)",
+                params![ev_id, &e.content],
+            )?;
+        }
         // if this event is replaceable update, remove other replaceable
         // event with the same kind from the same author that was issued
         // earlier than this.
@@ -221,15 +352,34 @@ impl SqliteRepo {
                 update_count,
                 e.get_author_prefix()
             );
+            // hide parameterized replaceable events referenced by
+            // address, which were created before the deletion.
+            for (kind, d_tag) in e.deleted_addresses() {
+                let update_count = tx.execute(
+                    "UPDATE event SET hidden=TRUE WHERE kind=? AND author=? AND created_at <= ? AND id IN (SELECT t.event_id FROM tag t WHERE t.name='d' AND t.kind=? AND t.value=?)",
+                    params![kind, pubkey_blob, e.created_at, kind, d_tag],
+                )?;
+                info!(
+                    "hid {} deleted kind {} events for author {:?}",
+                    update_count,
+                    kind,
+                    e.get_author_prefix()
+                );
+            }
         } else {
             // check if a deletion has already been recorded for this event.
             // Only relevant for non-deletion events
             let del_count = tx.query_row(
                 "SELECT e.id FROM event e WHERE e.author=? AND e.id IN (SELECT t.event_id FROM tag t WHERE t.name='e' AND t.kind=5 AND t.value=?) LIMIT 1;",
                 params![pubkey_blob, e.id], |row| row.get::<usize, usize>(0));
+            // check if a deletion has already been recorded for this
+            // event's address, at or after its creation.
+            let addr_del_count = e.address().map(|addr| tx.query_row(
+                "SELECT e.id FROM event e WHERE e.kind=5 AND e.author=? AND e.created_at >= ? AND e.id IN (SELECT t.event_id FROM tag t WHERE t.name='a' AND t.kind=5 AND t.value=?) LIMIT 1;",
+                params![pubkey_blob, e.created_at, addr], |row| row.get::<usize, usize>(0)));
             // check if a the query returned a result, meaning we should
             // hid the current event
-            if del_count.ok().is_some() {
+            if del_count.ok().is_some() || addr_del_count.and_then(|r| r.ok()).is_some() {
                 // a deletion already existed, mark original event as hidden.
                 info!(
                     "hid event: {:?} due to existing deletion by author: {:?}",
@@ -244,7 +394,6 @@ impl SqliteRepo {
                 ins_count = 0;
             }
         }
-        tx.commit()?;
         Ok(ins_count)
     }
 }
@@ -263,13 +412,30 @@ impl NostrRepo for SqliteRepo {
             Duration::from_secs(600),
             self.write_in_progress.clone(),
         )
-        .await
+        .await?;
+        if self.retention.is_active() {
+            cleanup_retention(
+                self.maint_pool.clone(),
+                Duration::from_secs(600),
+                self.retention.clone(),
+                self.write_in_progress.clone(),
+                self.metrics.clone(),
+            )
+            .await?;
+        }
+        Ok(())
     }
 
     async fn migrate_up(&self) -> Result<usize> {
         let _write_guard = self.write_in_progress.lock().await;
         let mut conn = self.write_pool.get()?;
-        task::spawn_blocking(move || upgrade_db(&mut conn)).await?
+        let indexed_tags = self.indexed_tags.clone();
+        task::spawn_blocking(move || {
+            let version = upgrade_db(&mut conn)?;
+            reindex_tag_names(&mut conn, &indexed_tags)?;
+            Ok(version)
+        })
+        .await?
     }
     /// Persist event to database
     async fn write_event(&self, e: &Event) -> Result<u64> {
@@ -280,6 +446,9 @@ impl NostrRepo for SqliteRepo {
         // spawn a blocking thread
         //let mut conn = self.write_pool.get()?;
         let pool = self.write_pool.clone();
+        let search_kinds = self.search_kinds.clone();
+        let indexed_tags = self.indexed_tags.clone();
+        let relay_url = self.relay_url.clone();
         let e = e.clone();
         let event_count = task::spawn_blocking(move || {
             let mut conn = pool.get()?;
@@ -287,7 +456,13 @@ impl NostrRepo for SqliteRepo {
             // multiple times before giving up.
             loop {
                 attempts += 1;
-                let wr = SqliteRepo::persist_event(&mut conn, &e);
+                let wr = SqliteRepo::persist_event(
+                    &mut conn,
+                    &e,
+                    &search_kinds,
+                    &indexed_tags,
+                    relay_url.as_deref(),
+                );
                 match wr {
                     Err(SqlError(rusqlite::Error::SqliteFailure(e, _))) => {
                         // this basically means that NIP-05 or another
@@ -315,6 +490,61 @@ impl NostrRepo for SqliteRepo {
         event_count
     }
 
+    /// Persist a batch of events to the database, in one transaction
+    async fn write_events(&self, events: &[Event]) -> Result<Vec<Result<u64>>> {
+        let start = Instant::now();
+        let max_write_attempts = 10;
+        let mut attempts = 0;
+        let _write_guard = self.write_in_progress.lock().await;
+        let pool = self.write_pool.clone();
+        let search_kinds = self.search_kinds.clone();
+        let indexed_tags = self.indexed_tags.clone();
+        let relay_url = self.relay_url.clone();
+        let events = events.to_vec();
+        let results = task::spawn_blocking(move || {
+            let mut conn = pool.get()?;
+            // the database may be busy; retry the whole batch
+            // multiple times before giving up.
+            loop {
+                attempts += 1;
+                let wr = SqliteRepo::persist_events(
+                    &mut conn,
+                    &events,
+                    &search_kinds,
+                    &indexed_tags,
+                    relay_url.as_deref(),
+                );
+                match wr {
+                    Err(SqlError(rusqlite::Error::SqliteFailure(e, _))) => {
+                        info!(
+                            "batch write failed, DB locked (attempt: {}); sqlite err: {}",
+                            attempts, e.extended_code
+                        );
+                    }
+                    _ => {
+                        return wr;
+                    }
+                }
+                if attempts >= max_write_attempts {
+                    return wr;
+                }
+            }
+        })
+        .await?;
+        self.metrics
+            .write_events
+            .observe(start.elapsed().as_secs_f64());
+        results
+    }
+
+    /// SQLite databases are not shared between relays; nothing to do.
+    async fn broadcast_remote_writes(
+        &self,
+        _bcast_tx: broadcast::Sender<BroadcastEvent>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
     /// Perform a database query using a subscription.
     ///
     /// The [`Subscription`] is converted into a SQL query.  Each result
@@ -354,6 +584,14 @@ impl NostrRepo for SqliteRepo {
                     db_queue_time, client_id, sub.id
                 );
                 metrics.query_aborts.with_label_values(&["loadshed"]).inc();
+                query_tx
+                    .blocking_send(QueryResult::closed(
+                        sub.get_id(),
+                        EventResultStatus::RateLimited,
+                        "relay is under heavy load, try again later",
+                        abandon_query_rx,
+                    ))
+                    .ok();
                 return Ok(());
             }
             // otherwise, report queuing time if it is slow
@@ -439,6 +677,14 @@ impl NostrRepo for SqliteRepo {
                                         .query_aborts
                                         .with_label_values(&["checkpoint"])
                                         .inc();
+                                    query_tx
+                                        .blocking_send(QueryResult::closed(
+                                            sub.get_id(),
+                                            EventResultStatus::Error,
+                                            CHECKPOINT_ABORT_MSG,
+                                            abandon_query_rx,
+                                        ))
+                                        .ok();
                                     return Ok(());
                                 }
                             }
@@ -469,6 +715,16 @@ impl NostrRepo for SqliteRepo {
                                     .query_aborts
                                     .with_label_values(&["slowclient"])
                                     .inc();
+                                // the queue is full, so don't wait for
+                                // room before returning.
+                                let closed = QueryResult::closed(
+                                    sub.get_id(),
+                                    EventResultStatus::Error,
+                                    SLOW_CLIENT_ABORT_MSG,
+                                    abandon_query_rx,
+                                );
+                                let tx = query_tx.clone();
+                                tokio::spawn(async move { tx.send(closed).await.ok() });
                                 let ok: Result<()> = Ok(());
                                 return ok;
                             }
@@ -483,6 +739,14 @@ impl NostrRepo for SqliteRepo {
                                     .query_aborts
                                     .with_label_values(&["checkpoint"])
                                     .inc();
+                                let closed = QueryResult::closed(
+                                    sub.get_id(),
+                                    EventResultStatus::Error,
+                                    CHECKPOINT_ABORT_MSG,
+                                    abandon_query_rx,
+                                );
+                                let tx = query_tx.clone();
+                                tokio::spawn(async move { tx.send(closed).await.ok() });
                                 return Ok(());
                             }
                             // give the queue a chance to clear before trying again
@@ -499,6 +763,7 @@ impl NostrRepo for SqliteRepo {
                             .blocking_send(QueryResult {
                                 sub_id: sub.get_id(),
                                 event: event_json,
+                                closed: None,
                             })
                             .ok();
                         last_successful_send = Instant::now();
@@ -533,6 +798,7 @@ impl NostrRepo for SqliteRepo {
                 .blocking_send(QueryResult {
                     sub_id: sub.get_id(),
                     event: "EOSE".to_string(),
+                    closed: None,
                 })
                 .ok();
             metrics
@@ -544,6 +810,99 @@ impl NostrRepo for SqliteRepo {
         Ok(())
     }
 
+    /// Count the events matching a subscription.
+    async fn count_subscription(&self, sub: Subscription, client_id: String) -> Result<u64> {
+        let start = Instant::now();
+        // counts share the reader thread limit with queries
+        let sem = self
+            .reader_threads_ready
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+        let self_clone = self.clone();
+        let metrics = self.metrics.clone();
+        task::spawn_blocking(move || {
+            {
+                // if we are waiting on a checkpoint, stop until it is complete
+                let _x = self_clone.checkpoint_in_progress.blocking_lock();
+            }
+            let mut conn = self_clone.read_pool.get()?;
+            let (q, p) = count_query_from_sub(&sub);
+            conn.trace(Some(|x| trace!("SQL trace: {:?}", x)));
+            let count: i64 = conn
+                .prepare_cached(&q)?
+                .query_row(rusqlite::params_from_iter(p), |row| row.get(0))?;
+            drop(sem);
+            debug!(
+                "count completed in {:?} (cid: {}, sub: {:?}, count: {})",
+                start.elapsed(),
+                client_id,
+                sub.id,
+                count
+            );
+            metrics.query_sub.observe(start.elapsed().as_secs_f64());
+            Ok(count as u64)
+        })
+        .await?
+    }
+
+    /// Stream the `(created_at, id)` pairs of events matching a filter.
+    async fn query_ids(
+        &self,
+        filter: ReqFilter,
+        client_id: String,
+        ids_tx: tokio::sync::mpsc::Sender<(u64, [u8; 32])>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        // id queries share the reader thread limit with queries
+        let sem = self
+            .reader_threads_ready
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+        let self_clone = self.clone();
+        let metrics = self.metrics.clone();
+        task::spawn_blocking(move || {
+            {
+                // if we are waiting on a checkpoint, stop until it is complete
+                let _x = self_clone.checkpoint_in_progress.blocking_lock();
+            }
+            let mut conn = self_clone.read_pool.get()?;
+            let (mut q, p, _) = select_from_filter("e.created_at, e.event_hash", &filter);
+            if let Some(lim) = filter.limit {
+                let _ = write!(q, " ORDER BY e.created_at DESC LIMIT {lim}");
+            }
+            conn.trace(Some(|x| trace!("SQL trace: {:?}", x)));
+            let mut stmt = conn.prepare_cached(&q)?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(p))?;
+            let mut row_count: usize = 0;
+            while let Some(row) = rows.next()? {
+                let created_at: u64 = row.get(0)?;
+                let hash: Vec<u8> = row.get(1)?;
+                let Ok(id) = <[u8; 32]>::try_from(hash) else {
+                    continue;
+                };
+                if ids_tx.blocking_send((created_at, id)).is_err() {
+                    // the receiver has what it needs
+                    break;
+                }
+                row_count += 1;
+            }
+            drop(sem);
+            debug!(
+                "id query completed in {:?} (cid: {}, rows: {})",
+                start.elapsed(),
+                client_id,
+                row_count
+            );
+            metrics.query_sub.observe(start.elapsed().as_secs_f64());
+            Ok(())
+        })
+        .await?
+    }
+
     /// Perform normal maintenance
     async fn optimize_db(&self) -> Result<()> {
         let conn = self.write_pool.get()?;
@@ -717,6 +1076,331 @@ impl NostrRepo for SqliteRepo {
             Ok(vr)
         }).await?
     }
+
+    /// Get the moderation state managed through NIP-86
+    async fn get_moderation(&self) -> Result<Moderation> {
+        let mut conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            let mut m = Moderation::default();
+            {
+                let mut stmt = tx.prepare("SELECT pubkey, allowed, reason FROM pubkey_policy;")?;
+                let mut rows = stmt.query([])?;
+                while let Some(r) = rows.next()? {
+                    let pubkey = hex::encode(r.get::<_, Vec<u8>>(0)?);
+                    if r.get(1)? {
+                        m.allowed_pubkeys.insert(pubkey, r.get(2)?);
+                    } else {
+                        m.banned_pubkeys.insert(pubkey, r.get(2)?);
+                    }
+                }
+                let mut stmt = tx.prepare("SELECT event_hash, reason FROM banned_event;")?;
+                let mut rows = stmt.query([])?;
+                while let Some(r) = rows.next()? {
+                    m.banned_events
+                        .insert(hex::encode(r.get::<_, Vec<u8>>(0)?), r.get(1)?);
+                }
+                let mut stmt = tx.prepare("SELECT kind, allowed FROM kind_policy;")?;
+                let mut rows = stmt.query([])?;
+                while let Some(r) = rows.next()? {
+                    if r.get(1)? {
+                        m.allowed_kinds.insert(r.get(0)?);
+                    } else {
+                        m.disallowed_kinds.insert(r.get(0)?);
+                    }
+                }
+                let mut stmt = tx.prepare("SELECT ip, reason FROM blocked_ip;")?;
+                let mut rows = stmt.query([])?;
+                while let Some(r) = rows.next()? {
+                    m.blocked_ips.insert(r.get(0)?, r.get(1)?);
+                }
+                let mut stmt = tx.prepare("SELECT name, value FROM relay_info;")?;
+                let mut rows = stmt.query([])?;
+                while let Some(r) = rows.next()? {
+                    let value = Some(r.get(1)?);
+                    match r.get::<_, String>(0)?.as_str() {
+                        "name" => m.relay_name = value,
+                        "description" => m.relay_description = value,
+                        "icon" => m.relay_icon = value,
+                        _ => {}
+                    }
+                }
+            }
+            Ok(m)
+        })
+        .await?
+    }
+
+    /// Ban a pubkey from publishing, or add it to the allowed pubkeys
+    async fn set_pubkey_policy(
+        &self,
+        pubkey: &str,
+        allowed: bool,
+        reason: Option<String>,
+    ) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let pubkey = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT OR REPLACE INTO pubkey_policy (pubkey, allowed, reason, created_at) VALUES (?, ?, ?, ?);",
+                params![pubkey, allowed, reason, unix_time()],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Remove a pubkey from the banned (or allowed) pubkeys
+    async fn remove_pubkey_policy(&self, pubkey: &str, allowed: bool) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let pubkey = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "DELETE FROM pubkey_policy WHERE pubkey=? AND allowed=?;",
+                params![pubkey, allowed],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Ban an event (hiding it, if stored), or lift a ban
+    async fn set_event_banned(&self, id: &str, banned: bool, reason: Option<String>) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let id = hex::decode(id)?;
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            if banned {
+                tx.execute(
+                    "INSERT OR REPLACE INTO banned_event (event_hash, reason, created_at) VALUES (?, ?, ?);",
+                    params![id, reason, unix_time()],
+                )?;
+                tx.execute("DELETE FROM event WHERE event_hash=?;", params![id])?;
+            } else {
+                tx.execute("DELETE FROM banned_event WHERE event_hash=?;", params![id])?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Add a kind to the allowed or disallowed kinds
+    async fn set_kind_policy(&self, kind: u64, allowed: bool) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT OR REPLACE INTO kind_policy (kind, allowed, created_at) VALUES (?, ?, ?);",
+                params![kind, allowed, unix_time()],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Remove a kind from the allowed (or disallowed) kinds
+    async fn remove_kind_policy(&self, kind: u64, allowed: bool) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "DELETE FROM kind_policy WHERE kind=? AND allowed=?;",
+                params![kind, allowed],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Block or unblock connections from an IP address
+    async fn set_ip_blocked(&self, ip: &str, blocked: bool, reason: Option<String>) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let ip = ip.to_owned();
+        tokio::task::spawn_blocking(move || {
+            if blocked {
+                conn.execute(
+                    "INSERT OR REPLACE INTO blocked_ip (ip, reason, created_at) VALUES (?, ?, ?);",
+                    params![ip, reason, unix_time()],
+                )?;
+            } else {
+                conn.execute("DELETE FROM blocked_ip WHERE ip=?;", params![ip])?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Override a relay information field (name, description or icon)
+    async fn set_relay_info(&self, field: &str, value: &str) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let field = field.to_owned();
+        let value = value.to_owned();
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT OR REPLACE INTO relay_info (name, value) VALUES (?, ?);",
+                params![field, value],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_groups(&self) -> Result<GroupState> {
+        let mut conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            let mut state = GroupState::default();
+            {
+                let mut stmt = tx.prepare(
+                    "SELECT group_id, name, about, picture, private, closed FROM group_info;",
+                )?;
+                let mut rows = stmt.query([])?;
+                while let Some(r) = rows.next()? {
+                    let group = Group {
+                        id: r.get(0)?,
+                        name: r.get(1)?,
+                        about: r.get(2)?,
+                        picture: r.get(3)?,
+                        private: r.get(4)?,
+                        closed: r.get(5)?,
+                        ..Default::default()
+                    };
+                    state.groups.insert(group.id.clone(), group);
+                }
+                let mut stmt = tx.prepare("SELECT group_id, pubkey, roles FROM group_member;")?;
+                let mut rows = stmt.query([])?;
+                while let Some(r) = rows.next()? {
+                    if let Some(g) = state.groups.get_mut(&r.get::<_, String>(0)?) {
+                        let roles: String = r.get(2)?;
+                        g.members.insert(
+                            hex::encode(r.get::<_, Vec<u8>>(1)?),
+                            serde_json::from_str(&roles).unwrap_or_default(),
+                        );
+                    }
+                }
+                let mut stmt = tx.prepare("SELECT group_id, code FROM group_invite;")?;
+                let mut rows = stmt.query([])?;
+                while let Some(r) = rows.next()? {
+                    if let Some(g) = state.groups.get_mut(&r.get::<_, String>(0)?) {
+                        g.invites.insert(r.get(1)?);
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(state)
+        })
+        .await?
+    }
+
+    async fn save_group(&self, group: &Group) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let group = group.clone();
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO group_info (group_id, name, about, picture, private, closed, created_at) VALUES (?, ?, ?, ?, ?, ?, strftime('%s','now')) ON CONFLICT (group_id) DO UPDATE SET name=excluded.name, about=excluded.about, picture=excluded.picture, private=excluded.private, closed=excluded.closed;",
+                params![group.id, group.name, group.about, group.picture, group.private, group.closed],
+            )?;
+            tx.execute("DELETE FROM group_member WHERE group_id=?;", params![group.id])?;
+            tx.execute("DELETE FROM group_invite WHERE group_id=?;", params![group.id])?;
+            for (pubkey, roles) in &group.members {
+                tx.execute(
+                    "INSERT INTO group_member (group_id, pubkey, roles) VALUES (?, ?, ?);",
+                    params![group.id, hex::decode(pubkey)?, serde_json::to_string(roles)?],
+                )?;
+            }
+            for code in &group.invites {
+                tx.execute(
+                    "INSERT INTO group_invite (group_id, code) VALUES (?, ?);",
+                    params![group.id, code],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn delete_group(&self, group_id: &str) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let group_id = group_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            // members and invites are removed by cascade
+            conn.execute(
+                "DELETE FROM group_info WHERE group_id=?;",
+                params![group_id],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn delete_group_events(&self, group_id: &str, ids: &[String]) -> Result<u64> {
+        let conn = self.write_pool.get()?;
+        let group_id = group_id.to_owned();
+        let ids: Vec<Vec<u8>> = ids.iter().filter_map(|id| hex::decode(id).ok()).collect();
+        tokio::task::spawn_blocking(move || {
+            let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(group_id)];
+            ids.into_iter().for_each(|id| params.push(Box::new(id)));
+            let query = format!(
+                "DELETE FROM event WHERE id IN (SELECT event_id FROM tag WHERE name='h' AND value=?) AND event_hash IN ({});",
+                repeat_vars(params.len() - 1)
+            );
+            let count = conn.execute(&query, rusqlite::params_from_iter(params))?;
+            Ok(count as u64)
+        })
+        .await?
+    }
+
+    async fn get_local_names(&self) -> Result<BTreeMap<String, String>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare_cached("SELECT name, pubkey FROM local_name;")?;
+            let names = stmt
+                .query_map([], |r| {
+                    Ok((r.get(0)?, hex::encode(r.get::<_, Vec<u8>>(1)?)))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(names)
+        })
+        .await?
+    }
+
+    async fn get_local_name(&self, name: &str) -> Result<Option<String>> {
+        let conn = self.read_pool.get()?;
+        let name = name.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare_cached("SELECT pubkey FROM local_name WHERE name=?;")?;
+            let pubkey = stmt
+                .query_row(params![name], |r| r.get::<_, Vec<u8>>(0))
+                .optional()?;
+            Ok(pubkey.map(hex::encode))
+        })
+        .await?
+    }
+
+    async fn set_local_name(&self, name: &str, pubkey: &str) -> Result<()> {
+        let conn = self.write_pool.get()?;
+        let name = name.to_owned();
+        let pubkey = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            conn.execute(
+                "INSERT INTO local_name (name, pubkey, created_at) VALUES (?, ?, strftime('%s','now')) ON CONFLICT (name) DO UPDATE SET pubkey=excluded.pubkey;",
+                params![name, pubkey],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn remove_local_name(&self, name: &str) -> Result<bool> {
+        let conn = self.write_pool.get()?;
+        let name = name.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let count = conn.execute("DELETE FROM local_name WHERE name=?;", params![name])?;
+            Ok(count > 0)
+        })
+        .await?
+    }
 }
 
 /// Decide if there is an index that should be used explicitly
@@ -732,6 +1416,7 @@ fn override_index(f: &ReqFilter) -> Option<String> {
             && f.since.is_none()
             && f.until.is_none()
             && f.tags.is_none()
+            && f.all_tags.is_none()
             && f.authors.is_none()
         {
             return Some("kind_created_at_index".into());
@@ -753,8 +1438,88 @@ fn override_index(f: &ReqFilter) -> Option<String> {
     None
 }
 
+/// Create a clause matching events with a tag of the given name, and
+/// any of the given values.
+fn tag_clause<'a>(
+    f: &ReqFilter,
+    key: &str,
+    val: impl Iterator<Item = &'a String>,
+    params: &mut Vec<Box<dyn ToSql>>,
+) -> String {
+    let mut str_vals: Vec<Box<dyn ToSql>> = vec![];
+    for v in val {
+        str_vals.push(Box::new(v.clone()));
+    }
+    // create clauses with "?" params for each tag value being searched
+    let str_clause = format!("AND value IN ({})", repeat_vars(str_vals.len()));
+    // find evidence of the target tag name/value existing for this event.
+    // Query for Kind/Since/Until additionally, to reduce the number of tags that come back.
+    let kind_clause = if let Some(ks) = &f.kinds {
+        // kind is number, no escaping needed
+        let str_kinds: Vec<String> = ks.iter().map(std::string::ToString::to_string).collect();
+        format!("AND kind IN ({})", str_kinds.join(", "))
+    } else {
+        String::new()
+    };
+    let since_clause = if let Some(since) = f.since {
+        format!("AND created_at > {since}")
+    } else {
+        String::new()
+    };
+    // Query for timestamp
+    let until_clause = if let Some(until) = f.until {
+        format!("AND created_at < {until}")
+    } else {
+        String::new()
+    };
+
+    let tag_clause = format!(
+        "e.id IN (SELECT t.event_id FROM tag t WHERE (name=? {str_clause} {kind_clause} {since_clause} {until_clause}))"
+    );
+
+    // add the tag name as the first parameter
+    params.push(Box::new(key.to_string()));
+    // add all tag values that are blobs as params
+    params.append(&mut str_vals);
+    tag_clause
+}
+
 /// Create a dynamic SQL subquery and params from a subscription filter (and optional explicit index used)
 fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
+    let (mut query, params, idx_name) = select_from_filter("e.content", f);
+    if f.force_no_match {
+        return (query, params, idx_name);
+    }
+    // Apply per-filter limit to this subquery.
+    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
+    if let Some(lim) = f.limit {
+        let _ = write!(query, " ORDER BY e.created_at DESC LIMIT {lim}");
+    } else {
+        query.push_str(" ORDER BY e.created_at ASC");
+    }
+    (query, params, idx_name)
+}
+
+/// Create a dynamic SQL query and params counting the distinct events
+/// matched by any filter in a subscription.  Limits are not applied.
+fn count_query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>) {
+    let mut subqueries: Vec<String> = Vec::new();
+    let mut params: Vec<Box<dyn ToSql>> = vec![];
+    for f in &sub.filters {
+        let (f_subquery, mut f_params, _) = select_from_filter("e.id", f);
+        subqueries.push(f_subquery);
+        params.append(&mut f_params);
+    }
+    let query = format!("SELECT COUNT(*) FROM ({})", subqueries.join(" UNION "));
+    (query, params)
+}
+
+/// Create an unordered SQL select of the given columns from a
+/// subscription filter (and optional explicit index used)
+fn select_from_filter(
+    columns: &str,
+    f: &ReqFilter,
+) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
     // build a dynamic SQL query.  all user-input is either an integer
     // (sqli-safe), or a string that is filtered to only contain
     // hexadecimal characters.  Strings that require escaping (tag
@@ -762,7 +1527,7 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
 
     // if the filter is malformed, don't return anything.
     if f.force_no_match {
-        let empty_query = "SELECT e.content FROM event e WHERE 1=0".to_owned();
+        let empty_query = format!("SELECT {columns} FROM event e WHERE 1=0");
         // query parameters for SQLite
         let empty_params: Vec<Box<dyn ToSql>> = vec![];
         return (empty_query, empty_params, None);
@@ -773,7 +1538,7 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     let idx_stmt = idx_name
         .as_ref()
         .map_or_else(|| "".to_owned(), |i| format!("INDEXED BY {i}"));
-    let mut query = format!("SELECT e.content FROM event e {idx_stmt}");
+    let mut query = format!("SELECT {columns} FROM event e {idx_stmt}");
     // query parameters for SQLite
     let mut params: Vec<Box<dyn ToSql>> = vec![];
 
@@ -853,46 +1618,59 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     // Query for tags
     if let Some(map) = &f.tags {
         for (key, val) in map.iter() {
-            let mut str_vals: Vec<Box<dyn ToSql>> = vec![];
+            filter_components.push(tag_clause(f, key, val.iter(), &mut params));
+        }
+    }
+    // Query for tags that must have every value; each value is
+    // found separately, so the results intersect.
+    if let Some(map) = &f.all_tags {
+        for (key, val) in map.iter() {
             for v in val {
-                str_vals.push(Box::new(v.clone()));
+                filter_components.push(tag_clause(f, key, std::iter::once(v), &mut params));
             }
-            // create clauses with "?" params for each tag value being searched
-            let str_clause = format!("AND value IN ({})", repeat_vars(str_vals.len()));
-            // find evidence of the target tag name/value existing for this event.
-            // Query for Kind/Since/Until additionally, to reduce the number of tags that come back.
-            let kind_clause;
-            let since_clause;
-            let until_clause;
-            if let Some(ks) = &f.kinds {
-                // kind is number, no escaping needed
-                let str_kinds: Vec<String> =
-                    ks.iter().map(std::string::ToString::to_string).collect();
-                kind_clause = format!("AND kind IN ({})", str_kinds.join(", "));
-            } else {
-                kind_clause = format!("");
-            };
-            if f.since.is_some() {
-                since_clause = format!("AND created_at > {}", f.since.unwrap());
-            } else {
-                since_clause = format!("");
-            };
-            // Query for timestamp
-            if f.until.is_some() {
-                until_clause = format!("AND created_at < {}", f.until.unwrap());
-            } else {
-                until_clause = format!("");
-            };
-
-            let tag_clause = format!(
-		"e.id IN (SELECT t.event_id FROM tag t WHERE (name=? {str_clause} {kind_clause} {since_clause} {until_clause}))"
+        }
+    }
+    // Query for full-text search; terms are alphanumeric, and
+    // quoted so they are matched as plain words.
+    let search_terms = f.search_terms();
+    if !search_terms.is_empty() {
+        let fts_query: Vec<String> = search_terms.iter().map(|t| format!("\"{t}\"")).collect();
+        filter_components
+            .push("e.id IN (SELECT rowid FROM event_fts WHERE event_fts MATCH ?)".to_owned());
+        params.push(Box::new(fts_query.join(" ")));
+    }
+    // Query for private kinds, which only participants may read
+    if let Some(readers) = &f.private_readers {
+        let str_kinds: Vec<String> = PRIVATE_KINDS.iter().map(ToString::to_string).collect();
+        let readers: Vec<&String> = readers
+            .iter()
+            .filter(|r| r.len() == 64 && is_lower_hex(r))
+            .collect();
+        let mut private_clause = format!("(kind NOT IN ({})", str_kinds.join(", "));
+        if !readers.is_empty() {
+            let vars = repeat_vars(readers.len());
+            let _ = write!(
+                private_clause,
+                " OR author IN ({vars}) OR e.id IN (SELECT t.event_id FROM tag t WHERE t.name='p' AND t.value IN ({vars}))"
             );
-
-            // add the tag name as the first parameter
-            params.push(Box::new(key.to_string()));
-            // add all tag values that are blobs as params
-            params.append(&mut str_vals);
-            filter_components.push(tag_clause);
+            for r in &readers {
+                params.push(Box::new(hex::decode(r).ok()));
+            }
+            for r in &readers {
+                params.push(Box::new((*r).clone()));
+            }
+        }
+        private_clause.push(')');
+        filter_components.push(private_clause);
+    }
+    // Query for private groups, which the reader may not read
+    if let Some(groups) = f.hidden_groups.as_ref().filter(|g| !g.is_empty()) {
+        filter_components.push(format!(
+            "e.id NOT IN (SELECT t.event_id FROM tag t WHERE t.name='h' AND t.value IN ({}))",
+            repeat_vars(groups.len())
+        ));
+        for g in groups {
+            params.push(Box::new(g.clone()));
         }
     }
     // Query for timestamp
@@ -915,13 +1693,6 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
         query.push_str(" AND ");
         query.push_str(&filter_components.join(" AND "));
     }
-    // Apply per-filter limit to this subquery.
-    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        let _ = write!(query, " ORDER BY e.created_at DESC LIMIT {lim}");
-    } else {
-        query.push_str(" ORDER BY e.created_at ASC");
-    }
     (query, params, idx_name)
 }
 
@@ -1051,6 +1822,126 @@ pub fn delete_expired(conn: &mut PooledConnection) -> Result<usize> {
     Ok(update_count)
 }
 
+/// Enforce the retention policy on a regular basis
+async fn cleanup_retention(
+    pool: SqlitePool,
+    frequency: Duration,
+    retention: Retention,
+    write_in_progress: Arc<Mutex<u64>>,
+    metrics: NostrMetrics,
+) -> Result<()> {
+    info!("enabling retention policy: {:?}", retention);
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    if let Ok(mut conn) = pool.get() {
+                        // take a write lock, retention can remove
+                        // large numbers of events at once.
+                        let _guard = write_in_progress.lock().await;
+                        let start = Instant::now();
+                        let retention = retention.clone();
+                        let ret_res = tokio::task::spawn_blocking(move || {
+                            delete_retained(&mut conn, &retention)
+                        }).await;
+                        match ret_res {
+                            Ok(Ok(counts)) => {
+                                for (reason, count) in counts {
+                                    if count > 0 {
+                                        metrics.retention_deletes.with_label_values(&[reason]).inc_by(count as u64);
+                                        info!("removed {} events exceeding retention {} limit in: {:?}", count, reason, start.elapsed());
+                                    }
+                                }
+                            },
+                            _ => {
+                                // either the task or underlying query failed
+                                info!("there was an error enforcing retention: {:?}", ret_res);
+                            }
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Delete events that exceed the retention policy, oldest first.
+/// Returns the number of events removed for each limit ("age",
+/// "count", and "size").  Events from whitelisted addresses are
+/// never removed.
+pub fn delete_retained(
+    conn: &mut PooledConnection,
+    retention: &Retention,
+) -> Result<Vec<(&'static str, usize)>> {
+    let whitelist = retention.whitelist_blobs();
+    // clause excluding whitelisted authors from deletion
+    let wl_clause = if whitelist.is_empty() {
+        "".to_owned()
+    } else {
+        format!("AND author NOT IN ({})", repeat_vars(whitelist.len()))
+    };
+    let mut counts = vec![];
+    let tx = conn.transaction()?;
+    if let Some(days) = retention.persist_days {
+        let cutoff = unix_time().saturating_sub(days as u64 * 86400);
+        let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(cutoff)];
+        whitelist
+            .iter()
+            .for_each(|x| params.push(Box::new(x.clone())));
+        let query = format!("DELETE FROM event WHERE created_at < ? {wl_clause}");
+        let count = tx.execute(&query, rusqlite::params_from_iter(params))?;
+        counts.push(("age", count));
+    }
+    if let Some(max_events) = retention.max_events {
+        let total: usize = tx.query_row("SELECT count(*) FROM event;", [], |row| row.get(0))?;
+        if total > max_events {
+            let mut params: Vec<Box<dyn ToSql>> = vec![];
+            whitelist
+                .iter()
+                .for_each(|x| params.push(Box::new(x.clone())));
+            params.push(Box::new(total - max_events));
+            let query = format!(
+                "DELETE FROM event WHERE id IN (SELECT id FROM event WHERE 1 {wl_clause} ORDER BY created_at ASC LIMIT ?)"
+            );
+            let count = tx.execute(&query, rusqlite::params_from_iter(params))?;
+            counts.push(("count", count));
+        }
+    }
+    if let Some(max_bytes) = retention.max_bytes {
+        // bytes in use by the database (ignoring free pages)
+        let used_bytes: u64 = tx.query_row(
+            "SELECT (p.page_count - f.freelist_count) * s.page_size FROM pragma_page_count() p, pragma_freelist_count() f, pragma_page_size() s;",
+            [],
+            |row| row.get(0),
+        )?;
+        let content_bytes: u64 = tx.query_row(
+            "SELECT coalesce(sum(length(CAST(content AS BLOB))), 0) FROM event;",
+            [],
+            |row| row.get(0),
+        )?;
+        if used_bytes > max_bytes as u64 && content_bytes > 0 {
+            // indexes and tags take up space as well, so scale the
+            // amount of event content we remove by the overall
+            // database size.
+            let excess = used_bytes - max_bytes as u64;
+            let target = (excess as f64 * content_bytes as f64 / used_bytes as f64).ceil() as u64;
+            let mut params: Vec<Box<dyn ToSql>> = vec![];
+            whitelist
+                .iter()
+                .for_each(|x| params.push(Box::new(x.clone())));
+            params.push(Box::new(target));
+            let query = format!(
+                "DELETE FROM event WHERE id IN (SELECT id FROM (SELECT id, sum(length(CAST(content AS BLOB))) OVER (ORDER BY created_at ASC, id ASC) - length(CAST(content AS BLOB)) AS preceding FROM event WHERE 1 {wl_clause}) WHERE preceding < ?)"
+            );
+            let count = tx.execute(&query, rusqlite::params_from_iter(params))?;
+            counts.push(("size", count));
+        }
+    }
+    tx.commit()?;
+    Ok(counts)
+}
+
 /// Perform database WAL checkpoint on a regular basis
 pub async fn db_checkpoint_task(
     pool: SqlitePool,
@@ -1102,7 +1993,7 @@ enum SqliteStatus {
     Ok,
     Busy,
     Error,
-    Other(u64),
+    Other,
 }
 
 /// Checkpoint/Truncate WAL.  Returns the number of WAL pages remaining.
@@ -1119,7 +2010,7 @@ pub fn checkpoint_db(conn: &mut PooledConnection) -> Result<usize> {
         0 => SqliteStatus::Ok,
         1 => SqliteStatus::Busy,
         2 => SqliteStatus::Error,
-        x => SqliteStatus::Other(x),
+        _ => SqliteStatus::Other,
     };
     info!(
         "checkpoint ran in {:?} (result: {:?}, WAL size: {})",
@@ -1168,3 +2059,122 @@ fn _pool_at_capacity(pool: &SqlitePool) -> bool {
     let state: r2d2::State = pool.state();
     state.idle_connections == 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{ALL_RELAYS, VANISH_KIND};
+
+    #[tokio::test]
+    async fn vanish_removes_author_events_and_gift_wraps() {
+        let dir = std::env::temp_dir().join(format!("sqlite-vanish-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.display().to_string();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await.unwrap();
+        let author = "a".repeat(64);
+        let mut note = Event::simple_event();
+        note.id = "1".repeat(64);
+        note.pubkey = author.clone();
+        note.created_at = 100;
+        repo.write_event(&note).await.unwrap();
+        // a gift wrap addressed to the author, and one to someone else
+        let mut wrap = Event::simple_event();
+        wrap.id = "2".repeat(64);
+        wrap.pubkey = "b".repeat(64);
+        wrap.kind = 1059;
+        wrap.created_at = 100;
+        wrap.tags = vec![vec!["p".to_owned(), author.clone()]];
+        wrap.build_index(&[]);
+        repo.write_event(&wrap).await.unwrap();
+        let mut other_wrap = wrap.clone();
+        other_wrap.id = "3".repeat(64);
+        other_wrap.tags = vec![vec!["p".to_owned(), "c".repeat(64)]];
+        other_wrap.build_index(&[]);
+        repo.write_event(&other_wrap).await.unwrap();
+        let mut vanish = Event::simple_event();
+        vanish.id = "4".repeat(64);
+        vanish.pubkey = author.clone();
+        vanish.kind = VANISH_KIND;
+        vanish.created_at = 200;
+        vanish.tags = vec![vec!["relay".to_owned(), ALL_RELAYS.to_owned()]];
+        repo.write_event(&vanish).await.unwrap();
+        let count = |ids: Vec<&String>| {
+            let sub: Subscription =
+                serde_json::from_value(serde_json::json!(["REQ", "s", {"ids": ids}])).unwrap();
+            repo.count_subscription(sub, "c".to_owned())
+        };
+        assert_eq!(
+            count(vec![&note.id, &wrap.id, &vanish.id]).await.unwrap(),
+            0
+        );
+        assert_eq!(count(vec![&other_wrap.id]).await.unwrap(), 1);
+        // older events from the author are refused
+        note.id = "5".repeat(64);
+        assert!(matches!(
+            repo.write_event(&note).await,
+            Err(Error::AuthorVanished)
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn retention_limits() {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        let pool = build_pool("retention", &settings, OpenFlags::default(), 1, 1, false);
+        let mut conn = pool.get().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        let now = unix_time();
+        let whitelisted = "a".repeat(64);
+        // a whitelisted and an ordinary event past the age limit,
+        // followed by four recent events.
+        let ages = [
+            (1, 10 * 86400),
+            (2, 10 * 86400),
+            (3, 100),
+            (4, 200),
+            (5, 300),
+            (6, 400),
+        ];
+        for (i, age) in ages {
+            let mut event = Event::simple_event();
+            event.id = i.to_string().repeat(64);
+            event.kind = 1;
+            event.pubkey = if i == 1 {
+                whitelisted.clone()
+            } else {
+                "b".repeat(64)
+            };
+            event.created_at = now - age;
+            SqliteRepo::insert_event(&conn, &event, &[], &[], None).unwrap();
+        }
+        let retention = Retention {
+            max_events: Some(3),
+            max_bytes: None,
+            persist_days: Some(1),
+            whitelist_addresses: Some(vec![whitelisted]),
+        };
+        let counts = delete_retained(&mut conn, &retention).unwrap();
+        assert_eq!(counts, vec![("age", 1), ("count", 2)]);
+        // the oldest events go first, but whitelisted ones are kept
+        let remaining: Vec<String> = conn
+            .prepare("SELECT hex(event_hash) FROM event ORDER BY created_at DESC;")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            remaining,
+            vec!["3".repeat(64), "4".repeat(64), "1".repeat(64)]
+        );
+        // nothing more to remove once within the limits
+        assert_eq!(
+            delete_retained(&mut conn, &retention).unwrap(),
+            vec![("age", 0)]
+        );
+    }
+}
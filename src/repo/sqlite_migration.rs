@@ -23,7 +23,7 @@ pragma mmap_size = 17179869184; -- cap mmap at 16GB
 "##;
 
 /// Latest database version
-pub const DB_VERSION: usize = 17;
+pub const DB_VERSION: usize = 23;
 
 /// Schema definition
 const INIT_SQL: &str = formatcp!(
@@ -96,6 +96,82 @@ FOREIGN KEY(metadata_event) REFERENCES event(id) ON UPDATE CASCADE ON DELETE CAS
 );
 CREATE INDEX IF NOT EXISTS user_verification_name_index ON user_verification(name);
 CREATE INDEX IF NOT EXISTS user_verification_event_index ON user_verification(metadata_event);
+
+-- NIP-50 Full-Text Search
+-- Rows are keyed by event id, and only exist for searchable kinds.
+CREATE VIRTUAL TABLE IF NOT EXISTS event_fts USING fts5(content);
+CREATE TRIGGER IF NOT EXISTS event_fts_delete AFTER DELETE ON event BEGIN
+  DELETE FROM event_fts WHERE rowid=old.id;
+END;
+
+-- NIP-86 Relay Management
+CREATE TABLE IF NOT EXISTS pubkey_policy (
+pubkey BLOB PRIMARY KEY, -- pubkey that was banned or allowed
+allowed INTEGER NOT NULL, -- 1 if allowed, 0 if banned
+reason TEXT, -- reason given by the relay operator
+created_at INTEGER NOT NULL -- when the policy was set
+);
+CREATE TABLE IF NOT EXISTS banned_event (
+event_hash BLOB PRIMARY KEY, -- banned event id
+reason TEXT, -- reason given by the relay operator
+created_at INTEGER NOT NULL -- when the event was banned
+);
+CREATE TABLE IF NOT EXISTS kind_policy (
+kind INTEGER PRIMARY KEY, -- event kind that was allowed or disallowed
+allowed INTEGER NOT NULL, -- 1 if allowed, 0 if disallowed
+created_at INTEGER NOT NULL -- when the policy was set
+);
+CREATE TABLE IF NOT EXISTS blocked_ip (
+ip TEXT PRIMARY KEY, -- blocked client IP address
+reason TEXT, -- reason given by the relay operator
+created_at INTEGER NOT NULL -- when the address was blocked
+);
+CREATE TABLE IF NOT EXISTS relay_info (
+name TEXT PRIMARY KEY, -- relay information field (name, description, icon)
+value TEXT NOT NULL -- value overriding the configuration
+);
+
+-- NIP-62 Request to Vanish
+CREATE TABLE IF NOT EXISTS vanish_request (
+pubkey BLOB PRIMARY KEY, -- pubkey that requested to vanish
+created_at INTEGER NOT NULL -- timestamp of the most recent request
+);
+
+-- NIP-29 Relay-based Groups
+CREATE TABLE IF NOT EXISTS group_info (
+group_id TEXT PRIMARY KEY, -- group identifier (h tag value)
+name TEXT, -- group name
+about TEXT, -- group description
+picture TEXT, -- group picture URL
+private INTEGER NOT NULL DEFAULT 0, -- 1 if only members may read
+closed INTEGER NOT NULL DEFAULT 0, -- 1 if joining requires an invite
+created_at INTEGER NOT NULL -- when the group was created
+);
+CREATE TABLE IF NOT EXISTS group_member (
+group_id TEXT NOT NULL, -- group identifier
+pubkey BLOB NOT NULL, -- member pubkey
+roles TEXT NOT NULL, -- JSON array of roles
+PRIMARY KEY (group_id, pubkey),
+FOREIGN KEY(group_id) REFERENCES group_info(group_id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS group_invite (
+group_id TEXT NOT NULL, -- group identifier
+code TEXT NOT NULL, -- invite code
+PRIMARY KEY (group_id, code),
+FOREIGN KEY(group_id) REFERENCES group_info(group_id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+-- NIP-05 Local Names
+CREATE TABLE IF NOT EXISTS local_name (
+name TEXT PRIMARY KEY, -- local part of the identifier
+pubkey BLOB NOT NULL, -- pubkey the name resolves to
+created_at INTEGER NOT NULL -- when the name was registered
+);
+
+-- Multi-character tag names indexed in the tag table
+CREATE TABLE IF NOT EXISTS indexed_tag (
+name TEXT PRIMARY KEY -- tag name
+);
 "##,
     DB_VERSION
 );
@@ -213,6 +289,24 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
             if curr_version == 16 {
                 curr_version = mig_16_to_17(conn)?;
             }
+            if curr_version == 17 {
+                curr_version = mig_17_to_18(conn)?;
+            }
+            if curr_version == 18 {
+                curr_version = mig_18_to_19(conn)?;
+            }
+            if curr_version == 19 {
+                curr_version = mig_19_to_20(conn)?;
+            }
+            if curr_version == 20 {
+                curr_version = mig_20_to_21(conn)?;
+            }
+            if curr_version == 21 {
+                curr_version = mig_21_to_22(conn)?;
+            }
+            if curr_version == 22 {
+                curr_version = mig_22_to_23(conn)?;
+            }
 
             if curr_version == DB_VERSION {
                 info!(
@@ -239,6 +333,44 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
     Ok(DB_VERSION)
 }
 
+/// Bring the tag index in line with the configured multi-character
+/// tag names.  Stored events are indexed for newly added names, and
+/// the index is dropped for names that were removed.
+pub fn reindex_tag_names(conn: &mut PooledConnection, names: &[String]) -> Result<()> {
+    let indexed: Vec<String> = conn
+        .prepare("SELECT name FROM indexed_tag;")?
+        .query_map([], |r| r.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let tx = conn.transaction()?;
+    for name in indexed.iter().filter(|n| !names.contains(n)) {
+        info!("removing tag index for {:?}", name);
+        tx.execute("DELETE FROM tag WHERE name=?;", params![name])?;
+        tx.execute("DELETE FROM indexed_tag WHERE name=?;", params![name])?;
+    }
+    for name in names {
+        if indexed.contains(name) || single_char_tagname(name).is_some() {
+            continue;
+        }
+        let start = Instant::now();
+        let count = tx.execute(
+            "INSERT INTO tag (event_id, name, value, kind, created_at) \
+             SELECT e.id, ?1, json_extract(t.value, '$[1]'), e.kind, e.created_at \
+             FROM event e, json_each(e.content, '$.tags') t \
+             WHERE json_extract(t.value, '$[0]')=?1 AND json_array_length(t.value) >= 2;",
+            params![name],
+        )?;
+        tx.execute("INSERT INTO indexed_tag (name) VALUES (?);", params![name])?;
+        info!(
+            "indexed {} tags for {:?} in {:?}",
+            count,
+            name,
+            start.elapsed()
+        );
+    }
+    tx.commit()?;
+    Ok(())
+}
+
 pub fn rebuild_tags(conn: &mut PooledConnection) -> Result<()> {
     // Check how many events we have to process
     let count = db_event_count(conn)?;
@@ -760,3 +892,168 @@ PRAGMA user_version = 17;
     }
     Ok(17)
 }
+
+fn mig_17_to_18(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 17->18");
+    let upgrade_sql = r##"
+CREATE VIRTUAL TABLE IF NOT EXISTS event_fts USING fts5(content);
+CREATE TRIGGER IF NOT EXISTS event_fts_delete AFTER DELETE ON event BEGIN
+  DELETE FROM event_fts WHERE rowid=old.id;
+END;
+PRAGMA user_version = 18;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v17 -> v18");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(18)
+}
+
+fn mig_18_to_19(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 18->19");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS pubkey_policy (
+pubkey BLOB PRIMARY KEY, -- pubkey that was banned or allowed
+allowed INTEGER NOT NULL, -- 1 if allowed, 0 if banned
+reason TEXT, -- reason given by the relay operator
+created_at INTEGER NOT NULL -- when the policy was set
+);
+CREATE TABLE IF NOT EXISTS banned_event (
+event_hash BLOB PRIMARY KEY, -- banned event id
+reason TEXT, -- reason given by the relay operator
+created_at INTEGER NOT NULL -- when the event was banned
+);
+CREATE TABLE IF NOT EXISTS kind_policy (
+kind INTEGER PRIMARY KEY, -- event kind that was allowed or disallowed
+allowed INTEGER NOT NULL, -- 1 if allowed, 0 if disallowed
+created_at INTEGER NOT NULL -- when the policy was set
+);
+CREATE TABLE IF NOT EXISTS blocked_ip (
+ip TEXT PRIMARY KEY, -- blocked client IP address
+reason TEXT, -- reason given by the relay operator
+created_at INTEGER NOT NULL -- when the address was blocked
+);
+CREATE TABLE IF NOT EXISTS relay_info (
+name TEXT PRIMARY KEY, -- relay information field (name, description, icon)
+value TEXT NOT NULL -- value overriding the configuration
+);
+PRAGMA user_version = 19;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v18 -> v19");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(19)
+}
+
+fn mig_19_to_20(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 19->20");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS vanish_request (
+pubkey BLOB PRIMARY KEY, -- pubkey that requested to vanish
+created_at INTEGER NOT NULL -- timestamp of the most recent request
+);
+PRAGMA user_version = 20;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v19 -> v20");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(20)
+}
+
+fn mig_20_to_21(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 20->21");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS group_info (
+group_id TEXT PRIMARY KEY, -- group identifier (h tag value)
+name TEXT, -- group name
+about TEXT, -- group description
+picture TEXT, -- group picture URL
+private INTEGER NOT NULL DEFAULT 0, -- 1 if only members may read
+closed INTEGER NOT NULL DEFAULT 0, -- 1 if joining requires an invite
+created_at INTEGER NOT NULL -- when the group was created
+);
+CREATE TABLE IF NOT EXISTS group_member (
+group_id TEXT NOT NULL, -- group identifier
+pubkey BLOB NOT NULL, -- member pubkey
+roles TEXT NOT NULL, -- JSON array of roles
+PRIMARY KEY (group_id, pubkey),
+FOREIGN KEY(group_id) REFERENCES group_info(group_id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS group_invite (
+group_id TEXT NOT NULL, -- group identifier
+code TEXT NOT NULL, -- invite code
+PRIMARY KEY (group_id, code),
+FOREIGN KEY(group_id) REFERENCES group_info(group_id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+PRAGMA user_version = 21;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v20 -> v21");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(21)
+}
+
+fn mig_21_to_22(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 21->22");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS local_name (
+name TEXT PRIMARY KEY, -- local part of the identifier
+pubkey BLOB NOT NULL, -- pubkey the name resolves to
+created_at INTEGER NOT NULL -- when the name was registered
+);
+PRAGMA user_version = 22;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v21 -> v22");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(22)
+}
+
+fn mig_22_to_23(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 22->23");
+    let upgrade_sql = r##"
+CREATE TABLE IF NOT EXISTS indexed_tag (
+name TEXT PRIMARY KEY -- tag name
+);
+PRAGMA user_version = 23;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v22 -> v23");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(23)
+}
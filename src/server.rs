@@ -2,20 +2,25 @@
 use crate::close::Close;
 use crate::close::CloseCmd;
 use crate::config::{Settings, VerifiedUsersMode};
-use crate::conn;
+use crate::conn::{self, MAX_SUBSCRIPTIONS, MAX_SUBSCRIPTION_ID_LEN};
 use crate::db;
 use crate::db::SubmittedEvent;
 use crate::error::{Error, Result};
-use crate::event::Event;
 use crate::event::EventCmd;
 use crate::event::EventWrapper;
+use crate::event::{BroadcastEvent, Event};
+use crate::fanout::{self, SubscriptionIndex};
 use crate::info::RelayInfo;
+use crate::negentropy::{Item, ItemLimit, ItemPermit, NegClose, NegMsg, NegOpen, Negentropy};
 use crate::nip05;
-use crate::notice::Notice;
+use crate::nip29::{self, GroupState};
+use crate::nip86::{self, Moderation};
+use crate::notice::{EventResultStatus, Notice};
 use crate::repo::NostrRepo;
 use crate::server::Error::CommandUnknownError;
 use crate::server::EventWrapper::{WrappedAuth, WrappedEvent};
-use crate::subscription::Subscription;
+use crate::subscription::{Count, ReqFilter, Subscription};
+use crate::verify::VerifierPool;
 use futures::SinkExt;
 use futures::StreamExt;
 use governor::{Jitter, Quota, RateLimiter};
@@ -41,12 +46,15 @@ use std::path::Path;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc::Receiver as MpscReceiver;
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::runtime::Builder;
-use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::broadcast::{self, Receiver};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio_tungstenite::WebSocketStream;
 use tracing::{debug, error, info, trace, warn};
 use tungstenite::error::CapacityError::MessageTooLong;
@@ -62,12 +70,15 @@ async fn handle_web_request(
     repo: Arc<dyn NostrRepo>,
     settings: Settings,
     remote_addr: SocketAddr,
-    broadcast: Sender<Event>,
+    fanout: Arc<SubscriptionIndex>,
+    verifier: VerifierPool,
     event_tx: mpsc::Sender<SubmittedEvent>,
     shutdown: Receiver<()>,
     favicon: Option<Vec<u8>>,
     registry: Registry,
     metrics: NostrMetrics,
+    moderation: Arc<watch::Sender<Moderation>>,
+    groups: watch::Receiver<GroupState>,
 ) -> Result<Response<Body>, Infallible> {
     match (
         request.uri().path(),
@@ -126,10 +137,13 @@ async fn handle_web_request(
                                     client_info,
                                     settings,
                                     ws_stream,
-                                    broadcast,
+                                    fanout,
+                                    verifier,
                                     event_tx,
                                     shutdown,
                                     metrics,
+                                    moderation.subscribe(),
+                                    groups,
                                 ));
                             }
                             // todo: trace, don't print...
@@ -156,6 +170,15 @@ async fn handle_web_request(
         // Request for Relay info
         ("/", false) => {
             // handle request at root with no upgrade header
+            // Check if this is a relay management request (NIP-86)
+            let is_rpc = request
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|ct| ct.to_str().ok())
+                .is_some_and(|ct| ct.starts_with(nip86::RPC_CONTENT_TYPE));
+            if is_rpc && request.method() == hyper::Method::POST {
+                return Ok(nip86::handle_http_request(request, repo, &settings, &moderation).await);
+            }
             // Check if this is a nostr server info request
             let accept_header = &request.headers().get(ACCEPT);
             // check if application/nostr+json is included
@@ -164,7 +187,8 @@ async fn handle_web_request(
                     if mt_str.contains("application/nostr+json") {
                         // build a relay info response
                         debug!("Responding to server info request");
-                        let rinfo = RelayInfo::from(settings);
+                        let mut rinfo = RelayInfo::from(settings);
+                        moderation.borrow().update_info(&mut rinfo);
                         let b = Body::from(serde_json::to_string_pretty(&rinfo).unwrap());
                         return Ok(Response::builder()
                             .status(200)
@@ -193,6 +217,10 @@ async fn handle_web_request(
                 .body(Body::from(buffer))
                 .unwrap())
         }
+        ("/.well-known/nostr.json", false) if settings.local_names.enabled => {
+            let query = request.uri().query();
+            Ok(nip05::handle_nostr_json(repo, &settings, query).await)
+        }
         ("/favicon.ico", false) => {
             if let Some(favicon_bytes) = favicon {
                 info!("returning favicon");
@@ -249,7 +277,9 @@ async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>) {
     }
 }
 
-fn create_metrics() -> (Registry, NostrMetrics) {
+/// Create the relay metrics, registered with a new registry.
+#[must_use]
+pub fn create_metrics() -> (Registry, NostrMetrics) {
     // setup prometheus registry
     let registry = Registry::new();
 
@@ -280,6 +310,11 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         "Active database connections",
     ))
     .unwrap();
+    let verify_queue = IntGauge::with_opts(Opts::new(
+        "nostr_verify_queue_depth",
+        "Events waiting for signature verification",
+    ))
+    .unwrap();
     let query_aborts = IntCounterVec::new(
         Opts::new("nostr_query_abort_total", "Aborted queries"),
         vec!["reason"].as_slice(),
@@ -290,25 +325,43 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         IntCounter::with_opts(Opts::new("nostr_cmd_event_total", "EVENT commands")).unwrap();
     let cmd_close =
         IntCounter::with_opts(Opts::new("nostr_cmd_close_total", "CLOSE commands")).unwrap();
+    let cmd_count =
+        IntCounter::with_opts(Opts::new("nostr_cmd_count_total", "COUNT commands")).unwrap();
     let cmd_auth =
         IntCounter::with_opts(Opts::new("nostr_cmd_auth_total", "AUTH commands")).unwrap();
+    let cmd_neg =
+        IntCounter::with_opts(Opts::new("nostr_cmd_neg_total", "NEG-OPEN commands")).unwrap();
     let disconnects = IntCounterVec::new(
         Opts::new("nostr_disconnects_total", "Client disconnects"),
         vec!["reason"].as_slice(),
     )
     .unwrap();
+    let retention_deletes = IntCounterVec::new(
+        Opts::new(
+            "nostr_retention_deleted_total",
+            "Events removed by retention policy",
+        ),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
     registry.register(Box::new(query_sub.clone())).unwrap();
     registry.register(Box::new(query_db.clone())).unwrap();
     registry.register(Box::new(write_events.clone())).unwrap();
     registry.register(Box::new(sent_events.clone())).unwrap();
     registry.register(Box::new(connections.clone())).unwrap();
     registry.register(Box::new(db_connections.clone())).unwrap();
+    registry.register(Box::new(verify_queue.clone())).unwrap();
     registry.register(Box::new(query_aborts.clone())).unwrap();
     registry.register(Box::new(cmd_req.clone())).unwrap();
     registry.register(Box::new(cmd_event.clone())).unwrap();
     registry.register(Box::new(cmd_close.clone())).unwrap();
+    registry.register(Box::new(cmd_count.clone())).unwrap();
     registry.register(Box::new(cmd_auth.clone())).unwrap();
+    registry.register(Box::new(cmd_neg.clone())).unwrap();
     registry.register(Box::new(disconnects.clone())).unwrap();
+    registry
+        .register(Box::new(retention_deletes.clone()))
+        .unwrap();
     let metrics = NostrMetrics {
         query_sub,
         query_db,
@@ -316,12 +369,16 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         sent_events,
         connections,
         db_connections,
+        verify_queue,
         disconnects,
         query_aborts,
         cmd_req,
         cmd_event,
         cmd_close,
+        cmd_count,
         cmd_auth,
+        cmd_neg,
+        retention_deletes,
     };
     (registry, metrics)
 }
@@ -406,7 +463,7 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         // other client on this channel.  This should be large enough
         // to accomodate slower readers (messages are dropped if
         // clients can not keep up).
-        let (bcast_tx, _) = broadcast::channel::<Event>(broadcast_buffer_limit);
+        let (bcast_tx, _) = broadcast::channel::<BroadcastEvent>(broadcast_buffer_limit);
         // validated events that need to be persisted are sent to the
         // database on via this channel.
         let (event_tx, event_rx) = mpsc::channel::<SubmittedEvent>(persist_buffer_limit);
@@ -426,15 +483,46 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         let (registry, metrics) = create_metrics();
         // build a repository for events
         let repo = db::build_repo(&settings, metrics.clone()).await;
+        // moderation state from the management API (NIP-86) is
+        // shared with the database writer and every connection.
+        let (moderation_tx, moderation_rx) = watch::channel(nip86::load_moderation(&repo).await);
+        let moderation_tx = Arc::new(moderation_tx);
+        // group state (NIP-29) is updated by the database writer.
+        let (groups_tx, groups_rx) = watch::channel(nip29::load_groups(&repo).await);
         // start the database writer task.  Give it a channel for
         // writing events, and for publishing events that have been
         // written (to all connected clients).
+        // new events are delivered to matching subscriptions through
+        // a shared index, rather than checked by every connection.
+        let fanout = Arc::new(SubscriptionIndex::new());
+        tokio::task::spawn(fanout::dispatch(fanout.clone(), bcast_tx.subscribe()));
+        // events written by other relays sharing the database are
+        // broadcast here as well.
+        if let Err(e) = repo.broadcast_remote_writes(bcast_tx.clone()).await {
+            warn!("could not listen for events from other relays: {:?}", e);
+        }
+        // signatures are verified on their own threads, off the
+        // connection tasks.
+        let verify_workers = settings
+            .limits
+            .verify_workers
+            .or_else(|| thread::available_parallelism().ok().map(usize::from))
+            .unwrap_or(1);
+        let verifier = VerifierPool::new(
+            verify_workers,
+            settings.limits.verify_queue,
+            settings.limits.verified_id_cache,
+            metrics.verify_queue.clone(),
+            settings.options.indexed_tag_names(),
+        );
         tokio::task::spawn(db::db_writer(
             repo.clone(),
             settings.clone(),
             event_rx,
             bcast_tx.clone(),
             metadata_tx.clone(),
+            moderation_rx,
+            groups_tx,
             shutdown_listen,
         ));
         info!("db writer created");
@@ -496,13 +584,16 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         let make_svc = make_service_fn(|conn: &AddrStream| {
             let repo = repo.clone();
             let remote_addr = conn.remote_addr();
-            let bcast = bcast_tx.clone();
+            let fanout = fanout.clone();
+            let verifier = verifier.clone();
             let event = event_tx.clone();
             let stop = invoke_shutdown.clone();
             let settings = settings.clone();
             let favicon = favicon.clone();
             let registry = registry.clone();
             let metrics = metrics.clone();
+            let moderation = moderation_tx.clone();
+            let groups = groups_rx.clone();
             async move {
                 // service_fn converts our function into a `Service`
                 Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
@@ -511,12 +602,15 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
                         repo.clone(),
                         settings.clone(),
                         remote_addr,
-                        bcast.clone(),
+                        fanout.clone(),
+                        verifier.clone(),
                         event.clone(),
                         stop.subscribe(),
                         favicon.clone(),
                         registry.clone(),
                         metrics.clone(),
+                        moderation.clone(),
+                        groups.clone(),
                     )
                 }))
             }
@@ -540,8 +634,16 @@ pub enum NostrMessage {
     EventMsg(EventCmd),
     /// A `REQ` message
     SubMsg(Subscription),
+    /// A `NEG-OPEN` message
+    NegOpenMsg(NegOpen),
+    /// A `NEG-MSG` message
+    NegMsg(NegMsg),
+    /// A `NEG-CLOSE` message (before `CLOSE`, which would also match it)
+    NegCloseMsg(NegClose),
     /// A `CLOSE` message
     CloseMsg(CloseCmd),
+    /// A `COUNT` message
+    CountMsg(Count),
 }
 
 /// Convert Message to `NostrMessage`
@@ -578,11 +680,49 @@ fn make_notice_message(notice: &Notice) -> Message {
         Notice::Message(ref msg) => json!(["NOTICE", msg]),
         Notice::EventResult(ref res) => json!(["OK", res.id, res.status.to_bool(), res.msg]),
         Notice::AuthChallenge(ref challenge) => json!(["AUTH", challenge]),
+        Notice::Closed(ref closed) => json!(["CLOSED", closed.sub_id, closed.msg]),
     };
 
     Message::text(json.to_string())
 }
 
+/// Make a `NEG-ERR` message, ending a negentropy session
+fn make_neg_err_message(sub_id: &str, msg: &str) -> Message {
+    Message::text(json!(["NEG-ERR", sub_id, msg]).to_string())
+}
+
+/// A loaded negentropy session, with its answer to the opening
+/// message, or the reason it could not be opened.
+type NegOpened = std::result::Result<(Negentropy, ItemPermit, Vec<u8>), String>;
+
+/// Load the events matching a negentropy (NIP-77) filter, within the
+/// connection's item limit, and answer the session's opening message.
+async fn open_neg_session(
+    repo: Arc<dyn NostrRepo>,
+    filter: ReqFilter,
+    client_id: String,
+    query: Vec<u8>,
+    limit: ItemLimit,
+) -> NegOpened {
+    let (ids_tx, mut ids_rx) = mpsc::channel::<(u64, [u8; 32])>(4096);
+    let query_handle = tokio::spawn(async move { repo.query_ids(filter, client_id, ids_tx).await });
+    let mut permit = limit.permit();
+    let mut items = vec![];
+    while let Some((timestamp, id)) = ids_rx.recv().await {
+        // dropping the receiver stops the query
+        if !permit.add() {
+            return Err("blocked: too many results".to_owned());
+        }
+        items.push(Item { timestamp, id });
+    }
+    if !matches!(query_handle.await, Ok(Ok(()))) {
+        return Err("error: could not query events".to_owned());
+    }
+    let neg = Negentropy::new(items);
+    let response = neg.reconcile(&query).map_err(|e| format!("error: {e}"))?;
+    Ok((neg, permit, response))
+}
+
 struct ClientInfo {
     remote_ip: String,
     user_agent: Option<String>,
@@ -597,15 +737,18 @@ async fn nostr_server(
     client_info: ClientInfo,
     settings: Settings,
     mut ws_stream: WebSocketStream<Upgraded>,
-    broadcast: Sender<Event>,
+    fanout: Arc<SubscriptionIndex>,
+    verifier: VerifierPool,
     event_tx: mpsc::Sender<SubmittedEvent>,
     mut shutdown: Receiver<()>,
     metrics: NostrMetrics,
+    mut moderation: watch::Receiver<Moderation>,
+    groups: watch::Receiver<GroupState>,
 ) {
     // the time this websocket nostr server started
     let orig_start = Instant::now();
-    // get a broadcast channel for clients to communicate on
-    let mut bcast_rx = broadcast.subscribe();
+    // receive new events matching our subscriptions
+    let (listener, mut realtime_rx) = fanout.register(settings.limits.broadcast_buffer);
     // Track internal client state
     let mut conn = conn::ClientConn::new(client_info.remote_ip, &settings);
     // subscription creation rate limiting
@@ -630,6 +773,9 @@ async fn nostr_server(
     let (query_tx, mut query_rx) = mpsc::channel::<db::QueryResult>(20_000);
     // Create channel for receiving NOTICEs
     let (notice_tx, mut notice_rx) = mpsc::channel::<Notice>(128);
+    // Create channel for receiving COUNT results, which run outside
+    // of this loop like subscription queries.
+    let (count_tx, mut count_rx) = mpsc::channel::<(String, Result<u64>)>(32);
 
     // last time this client sent data (message, ping, etc.)
     let mut last_message_time = Instant::now();
@@ -647,6 +793,15 @@ async fn nostr_server(
     // when these subscriptions are cancelled, make a message
     // available to the executing query so it knows to stop.
     let mut running_queries: HashMap<String, oneshot::Sender<()>> = HashMap::new();
+    // open negentropy (NIP-77) reconciliation sessions, and the
+    // number of events they hold.
+    let mut neg_sessions: HashMap<String, (Negentropy, ItemPermit)> = HashMap::new();
+    let neg_items = ItemLimit::default();
+    // sessions being loaded, with a sequence number to recognize
+    // results for sessions that were since replaced.
+    let mut neg_loading: HashMap<String, (u64, JoinHandle<()>)> = HashMap::new();
+    let mut neg_seq: u64 = 0;
+    let (neg_tx, mut neg_rx) = mpsc::channel::<(String, u64, NegOpened)>(32);
     // for stats, keep track of how many events the client published,
     // and how many it received from queries.
     let mut client_published_event_count: usize = 0;
@@ -654,8 +809,8 @@ async fn nostr_server(
 
     let unspec = "<unspecified>".to_string();
     info!("new client connection (cid: {}, ip: {:?})", cid, conn.ip());
-    let origin = client_info.origin.as_ref().unwrap_or_else(|| &unspec);
-    let user_agent = client_info.user_agent.as_ref().unwrap_or_else(|| &unspec);
+    let origin = client_info.origin.as_ref().unwrap_or(&unspec);
+    let user_agent = client_info.user_agent.as_ref().unwrap_or(&unspec);
     info!(
         "cid: {}, origin: {:?}, user-agent: {:?}",
         cid, origin, user_agent
@@ -664,6 +819,18 @@ async fn nostr_server(
     // Measure connections
     metrics.connections.inc();
 
+    // Refuse clients from addresses blocked through the management API
+    if moderation.borrow().is_ip_blocked(conn.ip()) {
+        info!(
+            "closing connection from blocked ip (cid: {}, ip: {:?})",
+            cid,
+            conn.ip()
+        );
+        metrics.disconnects.with_label_values(&["blocked"]).inc();
+        ws_stream.close(None).await.ok();
+        return;
+    }
+
     if settings.authorization.nip42_auth {
         conn.generate_auth_challenge();
         if let Some(challenge) = conn.auth_challenge() {
@@ -695,12 +862,66 @@ async fn nostr_server(
                 // Send a ping
                 ws_stream.send(Message::Ping(Vec::new())).await.ok();
             },
+            Ok(()) = moderation.changed() => {
+                // disconnect clients whose address was just blocked
+                if moderation.borrow().is_ip_blocked(conn.ip()) {
+                    info!("closing connection from blocked ip (cid: {}, ip: {:?})", cid, conn.ip());
+                    metrics.disconnects.with_label_values(&["blocked"]).inc();
+                    break;
+                }
+            },
             Some(notice_msg) = notice_rx.recv() => {
                 ws_stream.send(make_notice_message(&notice_msg)).await.ok();
             },
+            Some((sub_id, count_res)) = count_rx.recv() => {
+                match count_res {
+                    Ok(count) => {
+                        let count_msg = json!(["COUNT", sub_id, {"count": count}]);
+                        ws_stream.send(Message::text(count_msg.to_string())).await.ok();
+                    },
+                    Err(e) => {
+                        info!("Count error: {} (cid: {}, sub: {:?})", e, cid, sub_id);
+                        ws_stream.send(make_notice_message(&Notice::message(format!("Count error: {e}")))).await.ok();
+                    }
+                }
+            },
+            Some((neg_id, seq, opened)) = neg_rx.recv() => {
+                if neg_loading.get(&neg_id).map(|(s, _)| *s) != Some(seq) {
+                    continue;
+                }
+                neg_loading.remove(&neg_id);
+                match opened {
+                    Ok((neg, permit, response)) => {
+                        ws_stream.send(Message::text(json!(["NEG-MSG", neg_id, hex::encode(response)]).to_string())).await.ok();
+                        neg_sessions.insert(neg_id, (neg, permit));
+                    },
+                    Err(msg) => {
+                        info!("could not open negentropy session: {} (cid: {}, sub: {:?})", msg, cid, neg_id);
+                        ws_stream.send(make_neg_err_message(&neg_id, &msg)).await.ok();
+                    }
+                }
+            },
             Some(query_result) = query_rx.recv() => {
                 // database informed us of a query result we asked for
                 let subesc = query_result.sub_id.replace('"', "");
+                // the relay ended the query early, so end the subscription
+                // too, unless the client has since replaced it.
+                if let Some(mut closed) = query_result.closed {
+                    let current = running_queries.get(&query_result.sub_id).is_some_and(|q| closed.ends_query(q));
+                    if !current {
+                        debug!("ignoring end of replaced query (cid: {}, sub: {:?})", cid, query_result.sub_id);
+                        continue;
+                    }
+                    running_queries.remove(&query_result.sub_id);
+                    listener.unsubscribe(&query_result.sub_id);
+                    conn.unsubscribe(&Close { id: query_result.sub_id.clone() });
+                    ws_stream.send(make_notice_message(&Notice::closed(query_result.sub_id, &closed.msg, closed.status))).await.ok();
+                    continue;
+                }
+                // messages of private groups are only sent to members
+                if !groups.borrow().can_read_serialized(&query_result.event, conn.auth_pubkey()) {
+                    continue;
+                }
                 if query_result.event == "EOSE" {
                     let send_str = format!("[\"EOSE\",\"{subesc}\"]");
                     ws_stream.send(Message::Text(send_str)).await.ok();
@@ -712,28 +933,22 @@ async fn nostr_server(
                     ws_stream.send(Message::Text(send_str)).await.ok();
                 }
             },
-            // TODO: consider logging the LaggedRecv error
-            Ok(global_event) = bcast_rx.recv() => {
-                // an event has been broadcast to all clients
-                // first check if there is a subscription for this event.
-                for (s, sub) in conn.subscriptions() {
-                    if !sub.interested_in_event(&global_event) {
-                        continue;
-                    }
-
-                    // TODO: serialize at broadcast time, instead of
-                    // once for each consumer.
-                    if let Ok(event_str) = serde_json::to_string(&global_event) {
-                        trace!("sub match for client: {}, sub: {:?}, event: {:?}",
-                               cid, s,
-                               global_event.get_event_id_prefix());
-                        // create an event response and send it
-                        let subesc = s.replace('"', "");
-                        metrics.sent_events.with_label_values(&["realtime"]).inc();
-                        ws_stream.send(Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str}]"))).await.ok();
-                    } else {
-                        warn!("could not serialize event: {:?}", global_event.get_event_id_prefix());
-                    }
+            Some(delivery) = realtime_rx.recv() => {
+                // a new event matched some of our subscriptions
+                let global_event = &delivery.event.event;
+                if !groups.borrow().can_read(global_event, conn.auth_pubkey()) {
+                    continue;
+                }
+                // the event was serialized once, for all subscribers
+                let event_str = &delivery.event.json;
+                for s in delivery.sub_ids {
+                    trace!("sub match for client: {}, sub: {:?}, event: {:?}",
+                           cid, s,
+                           global_event.get_event_id_prefix());
+                    // create an event response and send it
+                    let subesc = s.replace('"', "");
+                    metrics.sent_events.with_label_values(&["realtime"]).inc();
+                    ws_stream.send(Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str}]"))).await.ok();
                 }
             },
             ws_next = ws_stream.next() => {
@@ -790,7 +1005,7 @@ async fn nostr_server(
                         // An EventCmd needs to be validated to be converted into an Event
                         // handle each type of message
                         let evid = ec.event_id().to_owned();
-                        let parsed : Result<EventWrapper> = Result::<EventWrapper>::from(ec);
+                        let parsed : Result<EventWrapper> = verifier.wrap(ec).await;
 
                         match parsed {
                             Ok(WrappedEvent(e)) => {
@@ -801,10 +1016,21 @@ async fn nostr_server(
                                 if e.is_expired() {
                                     let notice = Notice::invalid(e.id, "The event has already expired");
                                     ws_stream.send(make_notice_message(&notice)).await.ok();
+                                    // check if the event has enough proof-of-work.
+                                } else if let Some(min_pow) = settings.limits.min_pow_difficulty_for(e.kind).filter(|min| e.effective_pow_difficulty() < *min) {
+                                    info!("client: {} sent an event with insufficient proof-of-work", cid);
+                                    let msg = format!("difficulty {} < {}", e.effective_pow_difficulty(), min_pow);
+                                    let notice = Notice::pow(e.id, &msg);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                    // check if the client must authenticate first.
+                                } else if let Some(msg) = conn.publish_auth_error(&e, settings.authorization.nip42_publish) {
+                                    info!("client: {} sent an event without required authentication", cid);
+                                    let notice = Notice::auth_required(e.id, msg);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
                                     // check if the event is too far in the future.
                                 } else if e.is_valid_timestamp(settings.options.reject_future_seconds) {
                                     // Write this to the database.
-                                    let auth_pubkey = conn.auth_pubkey().and_then(|pubkey| hex::decode(&pubkey).ok());
+                                    let auth_pubkey = conn.auth_pubkey().and_then(|pubkey| hex::decode(pubkey).ok());
                                     let submit_event = SubmittedEvent {
                                         event: e.clone(),
                                         notice_tx: notice_tx.clone(),
@@ -831,19 +1057,21 @@ async fn nostr_server(
                                     match &settings.info.relay_url {
                                         None => {
                                             error!("AUTH command received, but relay_url is not set in the config file (cid: {})", cid);
+                                            ws_stream.send(make_notice_message(&Notice::error(event.id, "relay is not configured for authentication"))).await.ok();
                                         },
                                         Some(relay) => {
-                                            match conn.authenticate(&event, &relay) {
+                                            match conn.authenticate(&event, relay) {
                                                 Ok(_) => {
                                                     let pubkey = match conn.auth_pubkey() {
                                                         Some(k) => k.chars().take(8).collect(),
                                                         None => "<unspecified>".to_string(),
                                                     };
                                                     info!("client is authenticated: (cid: {}, pubkey: {:?})", cid, pubkey);
+                                                    ws_stream.send(make_notice_message(&Notice::saved(event.id))).await.ok();
                                                 },
                                                 Err(e) => {
                                                     info!("authentication error: {} (cid: {})", e, cid);
-                                                    ws_stream.send(make_notice_message(&Notice::message(format!("Authentication error: {e}")))).await.ok();
+                                                    ws_stream.send(make_notice_message(&Notice::invalid(event.id, &format!("{e}")))).await.ok();
                                                 },
                                             }
                                         }
@@ -861,8 +1089,24 @@ async fn nostr_server(
                             }
                         }
                     },
-                    Ok(NostrMessage::SubMsg(s)) => {
+                    Ok(NostrMessage::SubMsg(mut s)) => {
                         debug!("subscription requested (cid: {}, sub: {:?})", cid, s.id);
+                        s.restrict_search_kinds(settings.options.searchable_kinds());
+                        s.restrict_tag_names(settings.options.indexed_tag_names());
+                        if settings.authorization.nip42_dms {
+                            if conn.auth_pubkey().is_none() && s.requests_private_kinds() {
+                                info!("unauthenticated client requested private kinds (cid: {}, sub: {:?})", cid, s.id);
+                                ws_stream.send(make_notice_message(&Notice::closed(s.id, "private kinds require authentication", EventResultStatus::AuthRequired))).await.ok();
+                                continue;
+                            }
+                            s.restrict_private_kinds(conn.auth_pubkey());
+                        }
+                        let refusal = groups.borrow().private_group_refusal(&s, conn.auth_pubkey());
+                        if let Some((status, msg)) = refusal {
+                            info!("client requested private group without membership (cid: {}, sub: {:?})", cid, s.id);
+                            ws_stream.send(make_notice_message(&Notice::closed(s.id, msg, status))).await.ok();
+                            continue;
+                        }
                         // subscription handling consists of:
                         // * check for rate limits
                         // * registering the subscription so future events can be matched
@@ -879,6 +1123,7 @@ async fn nostr_server(
                             let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
                             match conn.subscribe(s.clone()) {
                                 Ok(()) => {
+                                    listener.subscribe(&s);
                                     // when we insert, if there was a previous query running with the same name, cancel it.
                                     if let Some(previous_query) = running_queries.insert(s.id.clone(), abandon_query_tx) {
                                         previous_query.send(()).ok();
@@ -890,11 +1135,131 @@ async fn nostr_server(
                                 },
                                 Err(e) => {
                                     info!("Subscription error: {} (cid: {}, sub: {:?})", e, cid, s.id);
-                                    ws_stream.send(make_notice_message(&Notice::message(format!("Subscription error: {e}")))).await.ok();
+                                    let (msg, status) = match e {
+                                        Error::SubMaxExceededError => ("too many concurrent subscriptions", EventResultStatus::RateLimited),
+                                        Error::SubIdMaxLengthError => ("subscription id too long", EventResultStatus::Error),
+                                        _ => ("could not create subscription", EventResultStatus::Error),
+                                    };
+                                    ws_stream.send(make_notice_message(&Notice::closed(s.id, msg, status))).await.ok();
                                 }
                             }
                         }
                     },
+                    Ok(NostrMessage::CountMsg(c)) => {
+                        debug!("count requested (cid: {}, sub: {:?})", cid, c.id);
+                        metrics.cmd_count.inc();
+                        if let Some(ref lim) = sub_lim_opt {
+                            lim.until_ready_with_jitter(jitter).await;
+                        }
+                        let sub_id = c.id.clone();
+                        let mut s = Subscription::from(c);
+                        s.restrict_search_kinds(settings.options.searchable_kinds());
+                        s.restrict_tag_names(settings.options.indexed_tag_names());
+                        if settings.authorization.nip42_dms {
+                            if conn.auth_pubkey().is_none() && s.requests_private_kinds() {
+                                info!("unauthenticated client counted private kinds (cid: {}, sub: {:?})", cid, s.id);
+                                ws_stream.send(make_notice_message(&Notice::closed(s.id, "private kinds require authentication", EventResultStatus::AuthRequired))).await.ok();
+                                continue;
+                            }
+                            s.restrict_private_kinds(conn.auth_pubkey());
+                        }
+                        let refusal = groups.borrow().private_group_refusal(&s, conn.auth_pubkey());
+                        if let Some((status, msg)) = refusal {
+                            info!("client counted private group without membership (cid: {}, sub: {:?})", cid, s.id);
+                            ws_stream.send(make_notice_message(&Notice::closed(s.id, msg, status))).await.ok();
+                            continue;
+                        }
+                        // counts are not filtered like REQ results, so
+                        // leave out unreadable group messages in the query.
+                        s.hide_groups(&groups.borrow().hidden_groups(conn.auth_pubkey()));
+                        // count in a separate task, so that a slow count does
+                        // not hold up other messages for this client.
+                        let count_repo = repo.clone();
+                        let count_cid = cid.clone();
+                        let count_tx = count_tx.clone();
+                        tokio::spawn(async move {
+                            let count_res = count_repo.count_subscription(s, count_cid).await;
+                            count_tx.send((sub_id, count_res)).await.ok();
+                        });
+                    },
+                    Ok(NostrMessage::NegOpenMsg(n)) => {
+                        debug!("negentropy session requested (cid: {}, sub: {:?})", cid, n.id);
+                        metrics.cmd_neg.inc();
+                        if n.id.len() > MAX_SUBSCRIPTION_ID_LEN {
+                            ws_stream.send(make_neg_err_message(&n.id, "blocked: subscription id too long")).await.ok();
+                            continue;
+                        }
+                        let reopened = neg_sessions.contains_key(&n.id) || neg_loading.contains_key(&n.id);
+                        if !reopened && neg_sessions.len() + neg_loading.len() >= MAX_SUBSCRIPTIONS {
+                            ws_stream.send(make_neg_err_message(&n.id, "blocked: too many open sessions")).await.ok();
+                            continue;
+                        }
+                        // opening a session replaces any previous one
+                        neg_sessions.remove(&n.id);
+                        if let Some((_, loading)) = neg_loading.remove(&n.id) {
+                            loading.abort();
+                        }
+                        if let Some(ref lim) = sub_lim_opt {
+                            lim.until_ready_with_jitter(jitter).await;
+                        }
+                        let mut s = Subscription { id: n.id.clone(), filters: vec![n.filter] };
+                        s.restrict_search_kinds(settings.options.searchable_kinds());
+                        s.restrict_tag_names(settings.options.indexed_tag_names());
+                        if settings.authorization.nip42_dms {
+                            if conn.auth_pubkey().is_none() && s.requests_private_kinds() {
+                                info!("unauthenticated client reconciled private kinds (cid: {}, sub: {:?})", cid, s.id);
+                                ws_stream.send(make_neg_err_message(&n.id, "auth-required: private kinds require authentication")).await.ok();
+                                continue;
+                            }
+                            s.restrict_private_kinds(conn.auth_pubkey());
+                        }
+                        let refusal = groups.borrow().private_group_refusal(&s, conn.auth_pubkey());
+                        if let Some((status, msg)) = refusal {
+                            info!("client reconciled private group without membership (cid: {}, sub: {:?})", cid, s.id);
+                            ws_stream.send(make_neg_err_message(&n.id, &format!("{}: {}", status.prefix(), msg))).await.ok();
+                            continue;
+                        }
+                        s.hide_groups(&groups.borrow().hidden_groups(conn.auth_pubkey()));
+                        let Ok(query) = hex::decode(&n.message) else {
+                            ws_stream.send(make_neg_err_message(&n.id, "error: invalid message")).await.ok();
+                            continue;
+                        };
+                        // load the matching events in a separate task, like
+                        // subscription queries, and answer when it completes.
+                        neg_seq += 1;
+                        let seq = neg_seq;
+                        let opening = open_neg_session(repo.clone(), s.filters.remove(0), cid.clone(), query, neg_items.clone());
+                        let neg_tx = neg_tx.clone();
+                        let neg_id = n.id.clone();
+                        let loading = tokio::spawn(async move {
+                            neg_tx.send((neg_id, seq, opening.await)).await.ok();
+                        });
+                        neg_loading.insert(n.id, (seq, loading));
+                    },
+                    Ok(NostrMessage::NegMsg(m)) => {
+                        let Some((neg, _)) = neg_sessions.get(&m.id) else {
+                            ws_stream.send(make_neg_err_message(&m.id, "closed: unknown session")).await.ok();
+                            continue;
+                        };
+                        let response = hex::decode(&m.message)
+                            .map_err(|_| "invalid message".to_owned())
+                            .and_then(|query| neg.reconcile(&query).map_err(|e| e.to_string()));
+                        match response {
+                            Ok(response) => {
+                                ws_stream.send(Message::text(json!(["NEG-MSG", m.id, hex::encode(response)]).to_string())).await.ok();
+                            },
+                            Err(e) => {
+                                neg_sessions.remove(&m.id);
+                                ws_stream.send(make_neg_err_message(&m.id, &format!("error: {e}"))).await.ok();
+                            }
+                        }
+                    },
+                    Ok(NostrMessage::NegCloseMsg(c)) => {
+                        neg_sessions.remove(&c.id);
+                        if let Some((_, loading)) = neg_loading.remove(&c.id) {
+                            loading.abort();
+                        }
+                    },
                     Ok(NostrMessage::CloseMsg(cc)) => {
                         // closing a request simply removes the subscription.
                         let parsed : Result<Close> = Result::<Close>::from(cc);
@@ -908,6 +1273,7 @@ async fn nostr_server(
                             }
                             // stop checking new events against
                             // the subscription
+                            listener.unsubscribe(&c.id);
                             conn.unsubscribe(&c);
                         } else {
                             info!("invalid command ignored");
@@ -937,6 +1303,9 @@ async fn nostr_server(
     for (_, stop_tx) in running_queries {
         stop_tx.send(()).ok();
     }
+    for (_, (_, loading)) in neg_loading {
+        loading.abort();
+    }
     info!(
         "stopping client connection (cid: {}, ip: {:?}, sent: {} events, recv: {} events, connected: {:?})",
         cid,
@@ -952,6 +1321,7 @@ pub struct NostrMetrics {
     pub query_sub: Histogram,        // response time of successful subscriptions
     pub query_db: Histogram,         // individual database query execution time
     pub db_connections: IntGauge,    // database connections in use
+    pub verify_queue: IntGauge,      // events waiting for signature verification
     pub write_events: Histogram,     // response time of event writes
     pub sent_events: IntCounterVec,  // count of events sent to clients
     pub connections: IntCounter,     // count of websocket connections
@@ -961,4 +1331,7 @@ pub struct NostrMetrics {
     pub cmd_event: IntCounter,       // count of EVENT commands received
     pub cmd_close: IntCounter, // count of CLOSE commands receivedpub cmd_auth: IntCounter, // count of AUTH commands received
     pub cmd_auth: IntCounter,
+    pub cmd_count: IntCounter, // count of COUNT commands received
+    pub cmd_neg: IntCounter,   // count of NEG-OPEN commands received
+    pub retention_deletes: IntCounterVec, // count of events removed by retention policy
 }
@@ -1,6 +1,7 @@
 //! Subscription and filter parsing
 use crate::error::Result;
-use crate::event::Event;
+use crate::event::{is_indexed_tagname, Event, PRIVATE_KINDS};
+use crate::nip29::GROUP_TAG;
 use serde::de::Unexpected;
 use serde::ser::SerializeMap;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -35,7 +36,18 @@ pub struct ReqFilter {
     /// Limit number of results
     pub limit: Option<u64>,
     /// Set of tags
-    pub tags: Option<HashMap<char, HashSet<String>>>,
+    pub tags: Option<HashMap<String, HashSet<String>>>,
+    /// Set of tags where every value must be present (`&` filters)
+    pub all_tags: Option<HashMap<String, HashSet<String>>>,
+    /// Full-text search query (NIP-50)
+    pub search: Option<String>,
+    /// Pubkeys allowed to read private kinds, if they are restricted
+    // set by the relay based on client authentication, rather than by
+    // the client.
+    pub private_readers: Option<Vec<String>>,
+    /// Private groups (NIP-29) whose messages may not be read
+    // set by the relay based on client authentication.
+    pub hidden_groups: Option<Vec<String>>,
     /// Force no matches due to malformed data
     // we can't represent it in the req filter, so we don't want to
     // erroneously match.  This basically indicates the req tried to
@@ -67,6 +79,9 @@ impl Serialize for ReqFilter {
         if let Some(authors) = &self.authors {
             map.serialize_entry("authors", &authors)?;
         }
+        if let Some(search) = &self.search {
+            map.serialize_entry("search", search)?;
+        }
         // serialize tags
         if let Some(tags) = &self.tags {
             for (k, v) in tags {
@@ -74,6 +89,12 @@ impl Serialize for ReqFilter {
                 map.serialize_entry(&format!("#{k}"), &vals)?;
             }
         }
+        if let Some(tags) = &self.all_tags {
+            for (k, v) in tags {
+                let vals: Vec<&String> = v.iter().collect();
+                map.serialize_entry(&format!("&{k}"), &vals)?;
+            }
+        }
         map.end()
     }
 }
@@ -98,10 +119,15 @@ impl<'de> Deserialize<'de> for ReqFilter {
             authors: None,
             limit: None,
             tags: None,
+            all_tags: None,
+            search: None,
+            private_readers: None,
+            hidden_groups: None,
             force_no_match: false,
         };
         let empty_string = "".into();
         let mut ts = None;
+        let mut all_ts = None;
         // iterate through each key, and assign values that exist
         for (key, val) in filter {
             // ids
@@ -135,13 +161,24 @@ impl<'de> Deserialize<'de> for ReqFilter {
                     }
                 }
                 rf.authors = raw_authors;
-            } else if key.starts_with('#') && key.len() > 1 && val.is_array() {
-                if let Some(tag_search) = tag_search_char_from_filter(key) {
-                    if ts.is_none() {
+            } else if key == "search" {
+                rf.search = Deserialize::deserialize(val).ok();
+            } else if (key.starts_with('#') || key.starts_with('&'))
+                && key.len() > 1
+                && val.is_array()
+            {
+                if let Some(tag_search) = tag_name_from_filter(key) {
+                    // '&' requires all values to be present, '#' any of them
+                    let tag_map = if key.starts_with('&') {
+                        &mut all_ts
+                    } else {
+                        &mut ts
+                    };
+                    if tag_map.is_none() {
                         // Initialize the tag if necessary
-                        ts = Some(HashMap::new());
+                        *tag_map = Some(HashMap::new());
                     }
-                    if let Some(m) = ts.as_mut() {
+                    if let Some(m) = tag_map.as_mut() {
                         let tag_vals: Option<Vec<String>> = Deserialize::deserialize(val).ok();
                         if let Some(v) = tag_vals {
                             let hs = v.into_iter().collect::<HashSet<_>>();
@@ -149,35 +186,86 @@ impl<'de> Deserialize<'de> for ReqFilter {
                         }
                     };
                 } else {
-                    // tag search that is multi-character, don't add to subscription
+                    // tag search without a name, don't add to subscription
                     rf.force_no_match = true;
                     continue;
                 }
             }
         }
         rf.tags = ts;
+        rf.all_tags = all_ts;
+        // a search without any words we can match would match anything
+        if rf.search.is_some() && rf.search_terms().is_empty() {
+            rf.force_no_match = true;
+        }
         Ok(rf)
     }
 }
 
-/// Attempt to form a single-char identifier from a tag search filter
-fn tag_search_char_from_filter(tagname: &str) -> Option<char> {
-    let tagname_nohash = &tagname[1..];
-    // We return the tag character if and only if the tagname consists
-    // of a single char.
-    let mut tagnamechars = tagname_nohash.chars();
-    let firstchar = tagnamechars.next();
-    match firstchar {
-        Some(_) => {
-            // check second char
-            if tagnamechars.next().is_none() {
-                firstchar
-            } else {
-                None
-            }
-        }
-        None => None,
-    }
+/// Get the tag name from a tag search filter key.  Whether the name
+/// may be searched is decided by [`Subscription::restrict_tag_names`].
+fn tag_name_from_filter(tagname: &str) -> Option<&str> {
+    Some(&tagname[1..]).filter(|n| !n.is_empty())
+}
+
+/// Split text into lowercase words, for full-text search matching.
+#[must_use]
+pub fn search_tokens(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Parse a `[<cmd>, <subscription id>, <filter>...]` array, which is
+/// shared by the `REQ` and `COUNT` commands.
+fn deserialize_filters<'de, D>(
+    deserializer: D,
+    cmd: &str,
+) -> Result<(String, Vec<ReqFilter>), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut v: Value = Deserialize::deserialize(deserializer)?;
+    // this shoud be a 3-or-more element array.
+    // verify the first element is a String, matching the command
+    // get the subscription from the second element.
+    // convert each of the remaining objects into filters
+
+    // check for array
+    let va = v
+        .as_array_mut()
+        .ok_or_else(|| serde::de::Error::custom("not array"))?;
+
+    // check length
+    if va.len() < 3 {
+        return Err(serde::de::Error::custom("not enough fields"));
+    }
+    let mut i = va.iter_mut();
+    // get command ("REQ") and ensure it is a string
+    let req_cmd_str: serde_json::Value = i.next().unwrap().take();
+    let req = req_cmd_str
+        .as_str()
+        .ok_or_else(|| serde::de::Error::custom("first element of request was not a string"))?;
+    if req != cmd {
+        return Err(serde::de::Error::custom(format!("missing {cmd} command")));
+    }
+
+    // ensure sub id is a string
+    let sub_id_str: serde_json::Value = i.next().unwrap().take();
+    let sub_id = sub_id_str
+        .as_str()
+        .ok_or_else(|| serde::de::Error::custom("missing subscription id"))?;
+
+    let mut filters = vec![];
+    for fv in i {
+        let f: ReqFilter = serde_json::from_value(fv.take())
+            .map_err(|_| serde::de::Error::custom("could not parse filter"))?;
+        // create indexes
+        filters.push(f);
+    }
+    filters.dedup();
+    Ok((sub_id.to_owned(), filters))
 }
 
 impl<'de> Deserialize<'de> for Subscription {
@@ -187,49 +275,36 @@ impl<'de> Deserialize<'de> for Subscription {
     where
         D: Deserializer<'de>,
     {
-        let mut v: Value = Deserialize::deserialize(deserializer)?;
-        // this shoud be a 3-or-more element array.
-        // verify the first element is a String, REQ
-        // get the subscription from the second element.
-        // convert each of the remaining objects into filters
-
-        // check for array
-        let va = v
-            .as_array_mut()
-            .ok_or_else(|| serde::de::Error::custom("not array"))?;
-
-        // check length
-        if va.len() < 3 {
-            return Err(serde::de::Error::custom("not enough fields"));
-        }
-        let mut i = va.iter_mut();
-        // get command ("REQ") and ensure it is a string
-        let req_cmd_str: serde_json::Value = i.next().unwrap().take();
-        let req = req_cmd_str
-            .as_str()
-            .ok_or_else(|| serde::de::Error::custom("first element of request was not a string"))?;
-        if req != "REQ" {
-            return Err(serde::de::Error::custom("missing REQ command"));
-        }
+        let (id, filters) = deserialize_filters(deserializer, "REQ")?;
+        Ok(Subscription { id, filters })
+    }
+}
+
+/// Request for the number of events matching a set of filters (NIP-45)
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct Count {
+    pub id: String,
+    pub filters: Vec<ReqFilter>,
+}
+
+impl<'de> Deserialize<'de> for Count {
+    /// Custom deserializer for counts, which share the structure of
+    /// a subscription request.
+    fn deserialize<D>(deserializer: D) -> Result<Count, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (id, filters) = deserialize_filters(deserializer, "COUNT")?;
+        Ok(Count { id, filters })
+    }
+}
 
-        // ensure sub id is a string
-        let sub_id_str: serde_json::Value = i.next().unwrap().take();
-        let sub_id = sub_id_str
-            .as_str()
-            .ok_or_else(|| serde::de::Error::custom("missing subscription id"))?;
-
-        let mut filters = vec![];
-        for fv in i {
-            let f: ReqFilter = serde_json::from_value(fv.take())
-                .map_err(|_| serde::de::Error::custom("could not parse filter"))?;
-            // create indexes
-            filters.push(f);
+impl From<Count> for Subscription {
+    fn from(c: Count) -> Subscription {
+        Subscription {
+            id: c.id,
+            filters: c.filters,
         }
-        filters.dedup();
-        Ok(Subscription {
-            id: sub_id.to_owned(),
-            filters,
-        })
     }
 }
 
@@ -247,6 +322,50 @@ impl Subscription {
         self.filters.iter().any(|f| f.limit != Some(0))
     }
 
+    /// Limit any full-text search filters to the event kinds that
+    /// are indexed for search.
+    pub fn restrict_search_kinds(&mut self, search_kinds: &[u64]) {
+        for f in &mut self.filters {
+            f.restrict_search_kinds(search_kinds);
+        }
+    }
+
+    /// Only allow tag searches on indexed tag names; single-char
+    /// names, and the given extra names.
+    pub fn restrict_tag_names(&mut self, extra: &[String]) {
+        for f in &mut self.filters {
+            f.restrict_tag_names(extra);
+        }
+    }
+
+    /// Determine if any filter explicitly requests private kinds.
+    #[must_use]
+    pub fn requests_private_kinds(&self) -> bool {
+        self.filters.iter().any(|f| {
+            f.kinds
+                .as_ref()
+                .is_some_and(|ks| ks.iter().any(|k| PRIVATE_KINDS.contains(k)))
+        })
+    }
+
+    /// Only match private kinds for which the reader (if any) is a
+    /// participant.
+    pub fn restrict_private_kinds(&mut self, reader: Option<&String>) {
+        for f in &mut self.filters {
+            f.private_readers = Some(reader.into_iter().cloned().collect());
+        }
+    }
+
+    /// Never match messages of the given private groups.
+    pub fn hide_groups(&mut self, groups: &[String]) {
+        if groups.is_empty() {
+            return;
+        }
+        for f in &mut self.filters {
+            f.hidden_groups = Some(groups.to_vec());
+        }
+    }
+
     /// Determine if this subscription matches a given [`Event`].  Any
     /// individual filter match is sufficient.
     #[must_use]
@@ -297,7 +416,7 @@ impl ReqFilter {
         // get the hashset from the filter.
         if let Some(map) = &self.tags {
             for (key, val) in map.iter() {
-                let tag_match = event.generic_tag_val_intersect(*key, val);
+                let tag_match = event.generic_tag_val_intersect(key, val);
                 // if there is no match for this tag, the match fails.
                 if !tag_match {
                     return false;
@@ -305,10 +424,91 @@ impl ReqFilter {
                 // if there was a match, we move on to the next one.
             }
         }
+        // every value of an '&' tag filter must be present.
+        if let Some(map) = &self.all_tags {
+            if !map
+                .iter()
+                .all(|(key, val)| event.generic_tag_val_superset(key, val))
+            {
+                return false;
+            }
+        }
         // if the tag map is empty, the match succeeds (there was no filter)
         true
     }
 
+    /// Words that must all appear in the content of matching events.
+    /// Search extensions (`key:value`) are not supported, and ignored.
+    #[must_use]
+    pub fn search_terms(&self) -> Vec<String> {
+        self.search.as_ref().map_or_else(Vec::new, |s| {
+            s.split_whitespace()
+                .filter(|w| !w.contains(':'))
+                .flat_map(search_tokens)
+                .collect()
+        })
+    }
+
+    fn search_match(&self, event: &Event) -> bool {
+        let terms = self.search_terms();
+        if terms.is_empty() {
+            return self.search.is_none();
+        }
+        let content: HashSet<String> = search_tokens(&event.content).into_iter().collect();
+        terms.iter().all(|t| content.contains(t))
+    }
+
+    fn private_match(&self, event: &Event) -> bool {
+        match &self.private_readers {
+            Some(readers) if PRIVATE_KINDS.contains(&event.kind) => {
+                readers.iter().any(|r| event.is_participant(r))
+            }
+            _ => true,
+        }
+    }
+
+    fn group_match(&self, event: &Event) -> bool {
+        match &self.hidden_groups {
+            Some(groups) => !event
+                .tag_values_by_name(GROUP_TAG)
+                .iter()
+                .any(|g| groups.contains(g)),
+            None => true,
+        }
+    }
+
+    /// Limit a search filter to kinds which are indexed; a filter
+    /// with no remaining kinds can never match.
+    fn restrict_search_kinds(&mut self, search_kinds: &[u64]) {
+        if self.search.is_none() {
+            return;
+        }
+        let kinds: Vec<u64> = match &self.kinds {
+            Some(ks) => ks
+                .iter()
+                .filter(|k| search_kinds.contains(k))
+                .copied()
+                .collect(),
+            None => search_kinds.to_vec(),
+        };
+        if kinds.is_empty() {
+            self.force_no_match = true;
+        }
+        self.kinds = Some(kinds);
+    }
+
+    /// A filter searching tags that are not indexed can never match.
+    fn restrict_tag_names(&mut self, extra: &[String]) {
+        let indexed = |m: &Option<HashMap<String, HashSet<String>>>| {
+            m.iter()
+                .flat_map(HashMap::keys)
+                .all(|k| is_indexed_tagname(k, extra))
+        };
+        if !indexed(&self.tags) || !indexed(&self.all_tags) {
+            self.force_no_match = true;
+        }
+    }
+
     /// Check if this filter either matches, or does not care about the kind.
     fn kind_match(&self, kind: u64) -> bool {
         self.kinds.as_ref().map_or(true, |ks| ks.contains(&kind))
@@ -324,6 +524,9 @@ impl ReqFilter {
             && self.kind_match(event.kind)
             && (self.authors_match(event) || self.delegated_authors_match(event))
             && self.tag_match(event)
+            && self.search_match(event)
+            && self.private_match(event)
+            && self.group_match(event)
             && !self.force_no_match
     }
 }
@@ -647,4 +850,153 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn count_parse() -> Result<()> {
+        let raw_json = r#"["COUNT","some-id",{"kinds": [7]},{"authors": ["abc"]}]"#;
+        let c: Count = serde_json::from_str(raw_json)?;
+        assert_eq!(c.id, "some-id");
+        assert_eq!(c.filters.len(), 2);
+        assert_eq!(c.filters.get(0).unwrap().kinds, Some(vec![7]));
+        Ok(())
+    }
+
+    #[test]
+    fn count_not_subscription() {
+        // a COUNT is not a REQ, and vice versa
+        let count_json = r#"["COUNT","some-id",{}]"#;
+        assert!(serde_json::from_str::<Subscription>(count_json).is_err());
+        let req_json = r#"["REQ","some-id",{}]"#;
+        assert!(serde_json::from_str::<Count>(req_json).is_err());
+    }
+
+    #[test]
+    fn search_match() -> Result<()> {
+        let s: Subscription =
+            serde_json::from_str(r#"["REQ","xyz",{"search":"Nostr relays language:en"}]"#)?;
+        let mut e = Event::simple_event();
+        e.content = "Running relays for nostr, since 2022.".to_owned();
+        assert!(s.interested_in_event(&e));
+        // every search term must be present as a word
+        e.content = "Running relays for nostrich".to_owned();
+        assert!(!s.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn search_without_terms_matches_nothing() -> Result<()> {
+        let s: Subscription = serde_json::from_str(
+            r#"["REQ","xyz",{"search":"?!"},{"search":"language:en"},{"search":"nostr"}]"#,
+        )?;
+        assert!(s.filters[0].force_no_match);
+        assert!(s.filters[1].force_no_match);
+        assert!(!s.filters[2].force_no_match);
+        let mut e = Event::simple_event();
+        e.content = "?! language:en".to_owned();
+        assert!(!s.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn search_restrict_kinds() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(
+            r#"["REQ","xyz",{"search":"nostr"},{"search":"nostr","kinds":[0,1]},{"search":"nostr","kinds":[0]},{"kinds":[0]}]"#,
+        )?;
+        s.restrict_search_kinds(&[1, 30023]);
+        assert_eq!(s.filters[0].kinds, Some(vec![1, 30023]));
+        assert_eq!(s.filters[1].kinds, Some(vec![1]));
+        assert!(s.filters[2].force_no_match);
+        // filters without a search are not modified
+        assert_eq!(s.filters[3].kinds, Some(vec![0]));
+        assert!(!s.filters[3].force_no_match);
+        Ok(())
+    }
+
+    #[test]
+    fn private_kinds_restricted() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"authors":["abc"]}]"#)?;
+        assert!(!s.requests_private_kinds());
+        let mut e = Event::simple_event();
+        e.pubkey = "abc".to_owned();
+        e.kind = 4;
+        e.tags = vec![vec!["p".to_owned(), "def".to_owned()]];
+        // unauthenticated readers see no private events
+        s.restrict_private_kinds(None);
+        assert!(!s.interested_in_event(&e));
+        // a recipient can read the message
+        s.restrict_private_kinds(Some(&"def".to_owned()));
+        assert!(s.interested_in_event(&e));
+        // but others cannot
+        s.restrict_private_kinds(Some(&"123".to_owned()));
+        assert!(!s.interested_in_event(&e));
+        // other kinds are unaffected
+        e.kind = 1;
+        assert!(s.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn private_groups_hidden() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"kinds":[9]}]"#)?;
+        let mut e = Event::simple_event();
+        e.kind = 9;
+        e.tags = vec![vec!["h".to_owned(), "secret".to_owned()]];
+        s.hide_groups(&[]);
+        assert!(s.interested_in_event(&e));
+        s.hide_groups(&["secret".to_owned()]);
+        assert!(!s.interested_in_event(&e));
+        // messages of other groups are unaffected
+        e.tags = vec![vec!["h".to_owned(), "open".to_owned()]];
+        assert!(s.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn private_kinds_requested() -> Result<()> {
+        let s: Subscription =
+            serde_json::from_str(r#"["REQ","xyz",{"kinds":[1]},{"kinds":[1059]}]"#)?;
+        assert!(s.requests_private_kinds());
+        Ok(())
+    }
+
+    #[test]
+    fn all_tags_match() -> Result<()> {
+        let s: Subscription =
+            serde_json::from_str(r##"["REQ","xyz",{"&t":["rust","nostr"],"#p":["abc"]}]"##)?;
+        let f = &s.filters[0];
+        assert_eq!(f.all_tags.as_ref().map(|m| m["t"].len()), Some(2));
+        assert_eq!(f.tags.as_ref().map(|m| m["p"].len()), Some(1));
+        let mut e = Event::simple_event();
+        e.tags = vec![
+            vec!["t".to_owned(), "rust".to_owned()],
+            vec!["p".to_owned(), "abc".to_owned()],
+        ];
+        e.build_index(&[]);
+        // only one of the values is present
+        assert!(!s.interested_in_event(&e));
+        e.tags.push(vec!["t".to_owned(), "nostr".to_owned()]);
+        e.build_index(&[]);
+        assert!(s.interested_in_event(&e));
+        // the '&' filter survives serialization
+        let parsed: ReqFilter = serde_json::from_str(&serde_json::to_string(f)?)?;
+        assert_eq!(&parsed, f);
+        Ok(())
+    }
+
+    #[test]
+    fn multichar_tags_restricted() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(
+            r##"["REQ","xyz",{"#client":["app"]},{"&title":["a"]},{"#t":["nostr"]}]"##,
+        )?;
+        let mut e = Event::simple_event();
+        e.tags = vec![vec!["client".to_owned(), "app".to_owned()]];
+        e.build_index(&["client".to_owned()]);
+        assert!(s.interested_in_event(&e));
+        // names that are not indexed cannot be searched
+        s.restrict_tag_names(&["title".to_owned()]);
+        assert!(s.filters[0].force_no_match);
+        assert!(!s.filters[1].force_no_match);
+        assert!(!s.filters[2].force_no_match);
+        Ok(())
+    }
 }
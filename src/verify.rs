@@ -0,0 +1,212 @@
+//! Event signature verification on a pool of worker threads
+//!
+//! Verifying signatures is CPU intensive, so connections hand events
+//! to a fixed set of worker threads over a bounded queue.  When the
+//! workers fall behind, the queue fills and connections wait, which
+//! stops them reading further events from their clients.
+use crate::error::{Error, Result};
+use crate::event::{Event, EventCmd, EventWrapper};
+use prometheus::IntGauge;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, trace, warn};
+
+/// An event to verify, and where to send it back with the result.
+type Job = (Event, oneshot::Sender<(Event, Result<()>)>);
+
+/// Ids and signatures of recently verified events.
+struct RecentIds {
+    capacity: usize,
+    sigs: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl RecentIds {
+    fn new(capacity: usize) -> Self {
+        RecentIds {
+            capacity,
+            sigs: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn contains(&self, event: &Event) -> bool {
+        self.sigs.get(&event.id) == Some(&event.sig)
+    }
+
+    fn insert(&mut self, event: &Event) {
+        if self.capacity == 0 || self.sigs.contains_key(&event.id) {
+            return;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.sigs.remove(&oldest);
+            }
+        }
+        self.sigs.insert(event.id.clone(), event.sig.clone());
+        self.order.push_back(event.id.clone());
+    }
+}
+
+/// Handle for submitting events to the verification workers.
+#[derive(Clone)]
+pub struct VerifierPool {
+    tx: mpsc::Sender<Job>,
+    queued: IntGauge,
+    recent: Arc<Mutex<RecentIds>>,
+    /// Configured tag names to index, besides single letters
+    indexed_tags: Arc<[String]>,
+}
+
+impl VerifierPool {
+    /// Start `workers` verification threads, with room for `queue`
+    /// waiting events, remembering up to `cache` verified events.
+    /// Verified events have their `indexed_tags` indexed.
+    #[must_use]
+    pub fn new(
+        workers: usize,
+        queue: usize,
+        cache: usize,
+        queued: IntGauge,
+        indexed_tags: &[String],
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>(queue.max(1));
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = workers.max(1);
+        for n in 0..workers {
+            let rx = rx.clone();
+            let queued = queued.clone();
+            thread::Builder::new()
+                .name(format!("verifier-{n}"))
+                .spawn(move || loop {
+                    let job = rx.lock().unwrap().blocking_recv();
+                    let Some((event, result_tx)) = job else {
+                        break;
+                    };
+                    queued.dec();
+                    let res = event.validate();
+                    result_tx.send((event, res)).ok();
+                })
+                .expect("could not start verifier thread");
+        }
+        info!("started {} signature verification workers", workers);
+        VerifierPool {
+            tx,
+            queued,
+            recent: Arc::new(Mutex::new(RecentIds::new(cache))),
+            indexed_tags: indexed_tags.into(),
+        }
+    }
+
+    /// Check an event's id and signature, waiting for room in the
+    /// queue if the workers are busy.
+    pub async fn verify(&self, event: Event) -> Result<Event> {
+        // an event seen recently only needs its id checked against
+        // the contents, since the signature covers the id.
+        if self.recent.lock().unwrap().contains(&event) {
+            trace!(
+                "skipping verification of recent event: {:?}",
+                event.get_event_id_prefix()
+            );
+            return event.validate_id().map(|_| event);
+        }
+        let (result_tx, result_rx) = oneshot::channel();
+        self.queued.inc();
+        if let Err(mpsc::error::SendError((event, _))) = self.tx.send((event, result_tx)).await {
+            // the workers have stopped; verify here instead.
+            self.queued.dec();
+            return event.validate().map(|_| event);
+        }
+        // a worker always replies, unless it panicked.
+        let Ok((event, res)) = result_rx.await else {
+            warn!("verifier worker stopped without a result");
+            return Err(Error::ChannelClosed);
+        };
+        res?;
+        self.recent.lock().unwrap().insert(&event);
+        Ok(event)
+    }
+
+    /// Convert a network event command to a parsed and validated
+    /// event, verifying `EVENT` signatures on the pool.
+    pub async fn wrap(&self, ec: EventCmd) -> Result<EventWrapper> {
+        if ec.cmd() != "EVENT" {
+            return Result::<EventWrapper>::from(ec);
+        }
+        self.verify(ec.into_event())
+            .await
+            .map(|e| EventWrapper::validated(e, &self.indexed_tags))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::{sha256, Hash};
+    use secp256k1::{KeyPair, Secp256k1};
+
+    fn signed_event(content: &str) -> Event {
+        let secp = Secp256k1::new();
+        let kp = KeyPair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let mut e = Event::simple_event();
+        e.pubkey = secp256k1::XOnlyPublicKey::from_keypair(&kp).to_string();
+        e.content = content.to_owned();
+        let digest = sha256::Hash::hash(e.to_canonical().unwrap().as_bytes());
+        e.id = format!("{digest:x}");
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        e.sig = secp.sign_schnorr(&msg, &kp).to_string();
+        e
+    }
+
+    #[tokio::test]
+    async fn verifies_on_workers() {
+        let pool = VerifierPool::new(2, 4, 10, IntGauge::new("q", "q").unwrap(), &[]);
+        assert!(pool.verify(signed_event("hello")).await.is_ok());
+        let mut forged = signed_event("hello");
+        forged.sig = signed_event("other").sig;
+        let res = pool.verify(forged).await;
+        assert!(matches!(res, Err(Error::EventInvalidSignature)));
+        assert_eq!(pool.queued.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn recent_events_still_match_their_id() {
+        let pool = VerifierPool::new(1, 4, 10, IntGauge::new("q", "q").unwrap(), &[]);
+        let event = signed_event("hello");
+        assert!(pool.verify(event.clone()).await.is_ok());
+        assert!(pool.recent.lock().unwrap().contains(&event));
+        assert!(pool.verify(event.clone()).await.is_ok());
+        // a verified id and signature with altered contents is rejected
+        let mut altered = event;
+        altered.content = "goodbye".to_owned();
+        let res = pool.verify(altered).await;
+        assert!(matches!(res, Err(Error::EventInvalidId)));
+    }
+
+    #[tokio::test]
+    async fn malformed_signature_rejected() {
+        let pool = VerifierPool::new(1, 4, 10, IntGauge::new("q", "q").unwrap(), &[]);
+        for sig in ["zz", &"g".repeat(128), ""] {
+            let mut malformed = signed_event("hello");
+            malformed.sig = sig.to_owned();
+            let res = pool.verify(malformed).await;
+            assert!(matches!(res, Err(Error::EventInvalidSignature)));
+        }
+        // the only worker is still running
+        assert!(pool.verify(signed_event("hello")).await.is_ok());
+    }
+
+    #[test]
+    fn recent_ids_are_bounded() {
+        let mut recent = RecentIds::new(2);
+        let events: Vec<Event> = ["a", "b", "c"].iter().map(|c| signed_event(c)).collect();
+        for e in &events {
+            recent.insert(e);
+        }
+        assert!(!recent.contains(&events[0]));
+        assert!(recent.contains(&events[1]) && recent.contains(&events[2]));
+        assert_eq!(recent.sigs.len(), 2);
+    }
+}
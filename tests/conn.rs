@@ -6,6 +6,7 @@ mod tests {
     use secp256k1::rand;
     use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
 
+    use nostr_rs_relay::config::{PublishAuthMode, Settings};
     use nostr_rs_relay::conn::ClientConn;
     use nostr_rs_relay::error::Error;
     use nostr_rs_relay::event::Event;
@@ -15,7 +16,7 @@ mod tests {
 
     #[test]
     fn test_generate_auth_challenge() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -39,7 +40,7 @@ mod tests {
 
     #[test]
     fn test_authenticate_with_valid_event() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -61,7 +62,7 @@ mod tests {
 
     #[test]
     fn test_fail_to_authenticate_in_invalid_state() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -74,7 +75,7 @@ mod tests {
 
     #[test]
     fn test_authenticate_when_already_authenticated() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -104,7 +105,7 @@ mod tests {
 
     #[test]
     fn test_fail_to_authenticate_with_invalid_event() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -125,7 +126,7 @@ mod tests {
 
     #[test]
     fn test_fail_to_authenticate_with_invalid_event_kind() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -140,12 +141,12 @@ mod tests {
 
         let result = client_conn.authenticate(&event, &RELAY.into());
 
-        assert!(matches!(result, Err(Error::AuthFailure)));
+        assert!(matches!(result, Err(Error::AuthWrongKind)));
     }
 
     #[test]
     fn test_fail_to_authenticate_with_expired_timestamp() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -160,12 +161,12 @@ mod tests {
 
         let result = client_conn.authenticate(&event, &RELAY.into());
 
-        assert!(matches!(result, Err(Error::AuthFailure)));
+        assert!(matches!(result, Err(Error::AuthStaleEvent)));
     }
 
     #[test]
     fn test_fail_to_authenticate_with_future_timestamp() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -180,12 +181,12 @@ mod tests {
 
         let result = client_conn.authenticate(&event, &RELAY.into());
 
-        assert!(matches!(result, Err(Error::AuthFailure)));
+        assert!(matches!(result, Err(Error::AuthStaleEvent)));
     }
 
     #[test]
     fn test_fail_to_authenticate_without_tags() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -199,12 +200,12 @@ mod tests {
 
         let result = client_conn.authenticate(&event, &RELAY.into());
 
-        assert!(matches!(result, Err(Error::AuthFailure)));
+        assert!(matches!(result, Err(Error::AuthChallengeMismatch)));
     }
 
     #[test]
     fn test_fail_to_authenticate_without_challenge() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -218,12 +219,12 @@ mod tests {
 
         let result = client_conn.authenticate(&event, &RELAY.into());
 
-        assert!(matches!(result, Err(Error::AuthFailure)));
+        assert!(matches!(result, Err(Error::AuthChallengeMismatch)));
     }
 
     #[test]
     fn test_fail_to_authenticate_without_relay() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -238,12 +239,12 @@ mod tests {
 
         let result = client_conn.authenticate(&event, &RELAY.into());
 
-        assert!(matches!(result, Err(Error::AuthFailure)));
+        assert!(matches!(result, Err(Error::AuthRelayMismatch)));
     }
 
     #[test]
     fn test_fail_to_authenticate_with_invalid_challenge() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -257,12 +258,12 @@ mod tests {
 
         let result = client_conn.authenticate(&event, &RELAY.into());
 
-        assert!(matches!(result, Err(Error::AuthFailure)));
+        assert!(matches!(result, Err(Error::AuthChallengeMismatch)));
     }
 
     #[test]
     fn test_fail_to_authenticate_with_invalid_relay() {
-        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
 
         assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
@@ -277,7 +278,50 @@ mod tests {
 
         let result = client_conn.authenticate(&event, &RELAY.into());
 
-        assert!(matches!(result, Err(Error::AuthFailure)));
+        assert!(matches!(result, Err(Error::AuthRelayMismatch)));
+    }
+
+    #[test]
+    fn test_publish_requires_authentication() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into(), &Settings::default());
+        let event = auth_event_with_kind(&"challenge".into(), 1);
+
+        assert_eq!(
+            client_conn.publish_auth_error(&event, PublishAuthMode::Disabled),
+            None
+        );
+        assert!(client_conn
+            .publish_auth_error(&event, PublishAuthMode::Authenticated)
+            .is_some());
+        assert!(client_conn
+            .publish_auth_error(&event, PublishAuthMode::Author)
+            .is_some());
+
+        client_conn.generate_auth_challenge();
+        let challenge = client_conn.auth_challenge().unwrap();
+        let auth = auth_event(challenge);
+        client_conn.authenticate(&auth, &RELAY.into()).unwrap();
+
+        // any authenticated pubkey may publish, but only its own events
+        assert_eq!(
+            client_conn.publish_auth_error(&event, PublishAuthMode::Authenticated),
+            None
+        );
+        assert!(client_conn
+            .publish_auth_error(&event, PublishAuthMode::Author)
+            .is_some());
+        assert_eq!(
+            client_conn.publish_auth_error(&auth, PublishAuthMode::Author),
+            None
+        );
+
+        // or events delegated by it
+        let mut delegated = event.clone();
+        delegated.delegated_by = Some(auth.pubkey.clone());
+        assert_eq!(
+            client_conn.publish_auth_error(&delegated, PublishAuthMode::Author),
+            None
+        );
     }
 
     fn auth_event(challenge: &String) -> Event {